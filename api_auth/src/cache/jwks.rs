@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use common::error::{AppError, Res};
+use dashmap::DashMap;
+use jsonwebtoken::{
+    DecodingKey, Validation, decode, decode_header,
+    jwk::{Jwk, JwkSet},
+};
+use oauth2::reqwest;
+use serde::de::DeserializeOwned;
+
+/// Minimum time between two fetches of the same key set, so tokens with
+/// unknown key IDs can't make us fetch on every request.
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+struct CachedKeySet {
+    keys: JwkSet,
+    /// `None` for key sets inserted with `insert_static`, which are never fetched.
+    fetched_at: Option<Instant>,
+}
+
+/// Cache of the JSON Web Key Sets identity providers sign their ID tokens with,
+/// keyed by JWKS URL.
+///
+/// A key set is refetched after `ttl`, or earlier when a token is signed with
+/// an unknown key (key rotation). If a fetch fails, the cached keys are used.
+pub struct JwksCache {
+    http_client: reqwest::Client,
+    ttl: Duration,
+    key_sets: DashMap<String, CachedKeySet>,
+}
+
+impl JwksCache {
+    pub fn new(ttl: Duration) -> Self {
+        JwksCache {
+            http_client: reqwest::Client::new(),
+            ttl,
+            key_sets: DashMap::new(),
+        }
+    }
+
+    /// Serves `keys` for `jwks_url` without ever fetching it, e.g. in tests.
+    pub fn insert_static(&self, jwks_url: &str, keys: JwkSet) {
+        self.key_sets.insert(
+            jwks_url.to_string(),
+            CachedKeySet {
+                keys,
+                fetched_at: None,
+            },
+        );
+    }
+
+    /// Verifies the signature and claims of a token signed with a key of `jwks_url`.
+    ///
+    /// # Arguments
+    ///
+    /// * `jwks_url` - The URL of the key set of the issuer.
+    /// * `token` - The token to verify.
+    /// * `validation` - The algorithms, issuer, audience and expiry to check.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the claims of the token or an `Unauthorized` error if it is invalid.
+    pub async fn verify<C: DeserializeOwned>(
+        &self,
+        jwks_url: &str,
+        token: &str,
+        validation: &Validation,
+    ) -> Res<C> {
+        let header = decode_header(token)
+            .map_err(|e| AppError::Unauthorized(format!("Invalid ID token. {}", e)))?;
+        let kid = header
+            .kid
+            .ok_or_else(|| AppError::Unauthorized("ID token has no key ID".to_string()))?;
+        let jwk = self.get(jwks_url, &kid).await?;
+        let key = DecodingKey::from_jwk(&jwk)
+            .map_err(|e| AppError::Internal(format!("Invalid key '{}'. {}", kid, e)))?;
+
+        decode::<C>(token, &key, validation)
+            .map(|data| data.claims)
+            .map_err(|e| AppError::Unauthorized(format!("Invalid ID token. {}", e)))
+    }
+
+    /// Gets a key of the key set at `jwks_url` by its ID.
+    async fn get(&self, jwks_url: &str, kid: &str) -> Res<Jwk> {
+        let refetch = match self.key_sets.get(jwks_url) {
+            Some(cached) => {
+                let key = cached.keys.find(kid).cloned();
+                match (key, cached.fetched_at) {
+                    (Some(key), None) => return Ok(key),
+                    (Some(key), Some(fetched_at)) if fetched_at.elapsed() < self.ttl => {
+                        return Ok(key);
+                    }
+                    (None, None) => false,
+                    (None, Some(fetched_at)) => fetched_at.elapsed() >= MIN_REFETCH_INTERVAL,
+                    (Some(_), Some(_)) => true,
+                }
+            }
+            None => true,
+        };
+
+        if refetch {
+            match self.fetch(jwks_url).await {
+                Ok(keys) => {
+                    self.key_sets.insert(
+                        jwks_url.to_string(),
+                        CachedKeySet {
+                            keys,
+                            fetched_at: Some(Instant::now()),
+                        },
+                    );
+                }
+                Err(e) => log::warn!(
+                    "Failed to fetch keys from {}, using cached ones: {}",
+                    jwks_url,
+                    e
+                ),
+            }
+        }
+
+        self.key_sets
+            .get(jwks_url)
+            .and_then(|cached| cached.keys.find(kid).cloned())
+            .ok_or_else(|| AppError::Unauthorized(format!("Unknown ID token key '{}'", kid)))
+    }
+
+    async fn fetch(&self, jwks_url: &str) -> Res<JwkSet> {
+        let keys = self
+            .http_client
+            .get(jwks_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(keys)
+    }
+}
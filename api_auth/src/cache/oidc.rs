@@ -0,0 +1,311 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use common::{
+    env_config::OidcProviderConfig,
+    error::{AppError, Res},
+};
+use jsonwebtoken::{Algorithm, Validation, decode_header};
+use oauth2::reqwest;
+use serde::Deserialize;
+
+use crate::cache::jwks::JwksCache;
+
+/// Provider metadata of the OpenID Connect discovery document.
+#[derive(Clone, Debug, Deserialize)]
+pub struct OidcMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    pub userinfo_endpoint: Option<String>,
+    /// Algorithms the provider signs ID tokens with, RS256 if not listed.
+    #[serde(default)]
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    /// Ways the client may authenticate at the token endpoint, `client_secret_basic` if not listed.
+    #[serde(default)]
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+/// User of a verified OpenID Connect login.
+#[derive(Debug, Clone)]
+pub struct OidcUserInfo {
+    /// The ID of the user at the provider (`sub`).
+    pub subject: String,
+    pub email: String,
+    /// Whether the provider verified the email of the user.
+    pub email_verified: bool,
+    pub first_name: String,
+    pub last_name: String,
+}
+
+/// Standard claims of an ID token or a userinfo response.
+#[derive(Debug, Deserialize)]
+struct OidcClaims {
+    sub: String,
+    email: Option<String>,
+    /// A boolean, some providers send it as a string.
+    email_verified: Option<serde_json::Value>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+    name: Option<String>,
+    nonce: Option<String>,
+}
+
+struct OidcProvider {
+    config: OidcProviderConfig,
+    metadata: RwLock<Option<(Arc<OidcMetadata>, Instant)>>,
+}
+
+/// Generic OpenID Connect providers configured with `OIDC_PROVIDERS`.
+///
+/// The discovery document of a provider is fetched on first use and cached for
+/// `ttl`. If a refetch fails, the last discovered metadata is kept.
+pub struct OidcRegistry {
+    http_client: reqwest::Client,
+    ttl: Duration,
+    providers: HashMap<String, OidcProvider>,
+}
+
+impl OidcRegistry {
+    pub fn new(providers: Vec<OidcProviderConfig>, ttl: Duration) -> Self {
+        OidcRegistry {
+            http_client: reqwest::Client::new(),
+            ttl,
+            providers: providers
+                .into_iter()
+                .map(|config| {
+                    (
+                        config.name.clone(),
+                        OidcProvider {
+                            config,
+                            metadata: RwLock::new(None),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Gets the configuration of a provider by its name.
+    pub fn get(&self, name: &str) -> Option<&OidcProviderConfig> {
+        self.providers.get(name).map(|provider| &provider.config)
+    }
+
+    /// Gets the discovered metadata of a provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the provider.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `OidcMetadata` or an `AppError` if the provider is unknown or
+    /// could never be discovered.
+    pub async fn metadata(&self, name: &str) -> Res<Arc<OidcMetadata>> {
+        let provider = self
+            .providers
+            .get(name)
+            .ok_or_else(|| AppError::BadRequest(format!("Invalid OAuth provider: {}", name)))?;
+
+        let cached = provider.metadata.read().unwrap().clone();
+        if let Some((metadata, fetched_at)) = &cached
+            && fetched_at.elapsed() < self.ttl
+        {
+            return Ok(metadata.clone());
+        }
+
+        match self.discover(&provider.config).await {
+            Ok(metadata) => {
+                let metadata = Arc::new(metadata);
+                *provider.metadata.write().unwrap() = Some((metadata.clone(), Instant::now()));
+                Ok(metadata)
+            }
+            Err(e) => match cached {
+                Some((metadata, _)) => {
+                    log::warn!(
+                        "Failed to rediscover OpenID Connect provider {}, using cached metadata: {}",
+                        name,
+                        e
+                    );
+                    Ok(metadata)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Gets the user of a login from its ID token, and from the userinfo endpoint
+    /// if the ID token lacks the email or name.
+    ///
+    /// # Arguments
+    ///
+    /// * `jwks` - The cache of the keys providers sign ID tokens with.
+    /// * `name` - The name of the provider.
+    /// * `id_token` - The ID token returned by the token endpoint.
+    /// * `access_token` - The access token returned by the token endpoint.
+    /// * `nonce` - The nonce sent to the provider when the flow started.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `OidcUserInfo` or an `Unauthorized` error if the ID token is invalid.
+    pub async fn fetch_user_info(
+        &self,
+        jwks: &JwksCache,
+        name: &str,
+        id_token: &str,
+        access_token: &str,
+        nonce: Option<&str>,
+    ) -> Res<OidcUserInfo> {
+        let metadata = self.metadata(name).await?;
+        let config = self
+            .get(name)
+            .ok_or_else(|| AppError::BadRequest(format!("Invalid OAuth provider: {}", name)))?;
+
+        let validation = id_token_validation(&metadata, &config.client_id, id_token)?;
+        let claims: OidcClaims = jwks
+            .verify(&metadata.jwks_uri, id_token, &validation)
+            .await?;
+        if claims.nonce.as_deref() != nonce {
+            return Err(AppError::Unauthorized(
+                "Invalid ID token. Nonce mismatch".to_string(),
+            ));
+        }
+        let mut user = user_info(claims);
+
+        let incomplete = user.email.is_empty() || user.first_name.is_empty();
+        if let (true, Some(userinfo_endpoint)) = (incomplete, &metadata.userinfo_endpoint) {
+            let claims = self
+                .fetch_userinfo_claims(userinfo_endpoint, access_token)
+                .await?;
+            // userinfo of another user must not be mixed in (OpenID Connect Core 5.3.2)
+            if claims.sub != user.subject {
+                return Err(AppError::Unauthorized(
+                    "Userinfo subject does not match the ID token".to_string(),
+                ));
+            }
+            let info = user_info(claims);
+            if user.email.is_empty() {
+                user.email = info.email;
+                user.email_verified = info.email_verified;
+            }
+            if user.first_name.is_empty() {
+                user.first_name = info.first_name;
+                user.last_name = info.last_name;
+            }
+        }
+        Ok(user)
+    }
+
+    async fn discover(&self, config: &OidcProviderConfig) -> Res<OidcMetadata> {
+        let url = format!("{}/.well-known/openid-configuration", config.issuer);
+        let metadata: OidcMetadata = self
+            .http_client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                AppError::Internal(format!("Failed to fetch discovery document {}: {}", url, e))
+            })?
+            .json()
+            .await
+            .map_err(|e| {
+                AppError::Internal(format!("Failed to parse discovery document {}: {}", url, e))
+            })?;
+
+        // the issuer must be the configured one, or ID tokens of another issuer would be accepted
+        if metadata.issuer.trim_end_matches('/') != config.issuer {
+            return Err(AppError::Internal(format!(
+                "Discovery document {} is of issuer {}",
+                url, metadata.issuer
+            )));
+        }
+        log::info!("Discovered OpenID Connect provider {}", config.name);
+        Ok(metadata)
+    }
+
+    async fn fetch_userinfo_claims(&self, url: &str, access_token: &str) -> Res<OidcClaims> {
+        let response = self
+            .http_client
+            .get(url)
+            .bearer_auth(access_token)
+            .send()
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to fetch userinfo: {}", e)))?;
+
+        if !response.status().is_success() {
+            return Err(AppError::Internal(format!(
+                "Userinfo endpoint returned error status: {}",
+                response.status()
+            )));
+        }
+        response
+            .json()
+            .await
+            .map_err(|e| AppError::Internal(format!("Failed to parse userinfo: {}", e)))
+    }
+}
+
+/// Validation of an ID token of a provider. Only the asymmetric algorithms the
+/// provider lists are accepted, the client secret is never used as a key.
+fn id_token_validation(
+    metadata: &OidcMetadata,
+    client_id: &str,
+    id_token: &str,
+) -> Res<Validation> {
+    let header = decode_header(id_token)
+        .map_err(|e| AppError::Unauthorized(format!("Invalid ID token. {}", e)))?;
+    let supported: Vec<Algorithm> = if metadata.id_token_signing_alg_values_supported.is_empty() {
+        vec![Algorithm::RS256]
+    } else {
+        metadata
+            .id_token_signing_alg_values_supported
+            .iter()
+            .filter_map(|alg| alg.parse().ok())
+            .collect()
+    };
+    let symmetric = matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    );
+    if symmetric || !supported.contains(&header.alg) {
+        return Err(AppError::Unauthorized(format!(
+            "Invalid ID token. Unsupported algorithm {:?}",
+            header.alg
+        )));
+    }
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[client_id]);
+    Ok(validation)
+}
+
+fn user_info(claims: OidcClaims) -> OidcUserInfo {
+    let email_verified = match claims.email_verified {
+        Some(serde_json::Value::Bool(verified)) => verified,
+        Some(serde_json::Value::String(verified)) => verified == "true",
+        _ => false,
+    };
+    let (first_name, last_name) = match (claims.given_name, claims.family_name) {
+        (Some(first_name), last_name) => (first_name, last_name.unwrap_or_default()),
+        (None, _) => {
+            let name = claims.name.unwrap_or_default();
+            match name.split_once(' ') {
+                Some((first_name, last_name)) => (first_name.to_string(), last_name.to_string()),
+                None => (name, String::new()),
+            }
+        }
+    };
+    OidcUserInfo {
+        subject: claims.sub,
+        email: claims.email.unwrap_or_default(),
+        email_verified,
+        first_name,
+        last_name,
+    }
+}
@@ -16,20 +16,60 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+/// Token of an email verification link, posted by the web app.
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthResponse {
     pub token: String,
     pub user: User,
 }
 
+/// Query, or form for providers that post back (Apple), of the redirect back from an OAuth provider.
+/// Holds either `code` and `state`, or `error` if the user denied access or the provider failed.
 #[derive(Debug, Deserialize)]
 pub struct OAuthCallbackQuery {
-    pub code: String,
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    /// Only logged, never forwarded to the web app.
+    pub error_description: Option<String>,
+    /// The user info JSON Apple posts on the first login only.
+    pub user: Option<String>,
+}
+
+/// OAuth flow in progress, kept in the session between the redirect to the provider and the callback.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OAuthFlow {
+    pub provider: String,
+    /// The `state` sent to the provider, which must come back unchanged.
+    pub state: String,
+    /// The PKCE verifier of the code challenge sent to the provider.
+    pub pkce_verifier: Option<String>,
+    /// The nonce sent to providers that return an ID token, which must be in the ID token.
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+/// Provider account waiting to be linked to the existing account with the same email,
+/// kept in the session until the user confirms by logging in to that account.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingLink {
+    pub provider: String,
+    pub provider_user_id: String,
+    pub email: String,
+    /// Unix timestamp after which the link can no longer be confirmed.
+    pub expires_at: i64,
 }
 
 #[derive(Debug)]
 pub struct OAuthUserData {
     pub email: String,
+    /// Whether the provider verified that the user owns the email.
+    pub email_verified: bool,
     pub first_name: String,
     pub last_name: String,
     pub provider_user_id: String,
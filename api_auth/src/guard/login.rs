@@ -0,0 +1,167 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use common::{
+    env_config::Config,
+    error::{AppError, Res},
+};
+use db::models::user::User;
+use sqlx::PgPool;
+
+/// Consecutive failures older than this are forgotten.
+const RESET_SECS: i64 = 24 * 60 * 60;
+
+/// Gets notified when an account is locked after repeated failed logins.
+#[async_trait]
+pub trait LockoutNotifier: Send + Sync {
+    async fn account_locked(&self, user: &User, locked_until: NaiveDateTime);
+}
+
+/// Notifier that only logs lockouts.
+pub struct LogLockoutNotifier;
+
+#[async_trait]
+impl LockoutNotifier for LogLockoutNotifier {
+    async fn account_locked(&self, user: &User, locked_until: NaiveDateTime) {
+        log::warn!(
+            "Account of user {} is locked until {} after repeated failed logins",
+            user.id,
+            locked_until
+        );
+    }
+}
+
+/// Protects the password login against brute force.
+///
+/// Failed attempts are counted per account email and per client IP in the
+/// database, so they hold across instances. Once a threshold is reached, the
+/// email or IP is locked, starting with the base lockout and doubling with every
+/// further failure up to the maximum. Emails are tracked whether or not an account
+/// exists, so lockouts don't reveal which emails are registered.
+pub struct LoginGuard {
+    account_threshold: i32,
+    ip_threshold: i32,
+    base_lock_secs: i64,
+    max_lock_secs: i64,
+    notifier: Arc<dyn LockoutNotifier>,
+}
+
+impl LoginGuard {
+    pub fn new(config: &Config, notifier: Arc<dyn LockoutNotifier>) -> Self {
+        LoginGuard {
+            account_threshold: config.login_lockout_threshold,
+            ip_threshold: config.login_ip_lockout_threshold,
+            base_lock_secs: config.login_lockout_base_secs,
+            max_lock_secs: config.login_lockout_max_secs,
+            notifier,
+        }
+    }
+
+    /// Rejects the login if the account email or the client IP is locked.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - A reference to the database connection pool.
+    /// * `email` - The email the login is attempted for.
+    /// * `ip` - The client IP, if known.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or a `TooManyRequests` error if locked.
+    pub async fn check(&self, pool: &PgPool, email: &str, ip: Option<IpAddr>) -> Res<()> {
+        let mut subjects = vec![account_subject(email)];
+        subjects.extend(ip.map(ip_subject));
+
+        if db::login::get_active_lock(pool, &subjects).await?.is_some() {
+            return Err(AppError::TooManyRequests(
+                "Too many failed login attempts. Please try again later.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records a failed login and locks the account email or client IP if
+    /// their threshold is reached. The owner of a locked account is notified.
+    pub async fn record_failure(
+        &self,
+        pool: &Arc<PgPool>,
+        email: &str,
+        ip: Option<IpAddr>,
+    ) -> Res<()> {
+        let subject = account_subject(email);
+        let failures = db::login::record_login_failure(&**pool, &subject, RESET_SECS).await?;
+        if let Some(lock_secs) = self.lock_secs(failures, self.account_threshold) {
+            let locked_until = db::login::lock_login_subject(&**pool, &subject, lock_secs).await?;
+            self.notify_locked(pool.clone(), email.to_string(), locked_until);
+        }
+
+        if let Some(ip) = ip {
+            let subject = ip_subject(ip);
+            let failures = db::login::record_login_failure(&**pool, &subject, RESET_SECS).await?;
+            if let Some(lock_secs) = self.lock_secs(failures, self.ip_threshold) {
+                let locked_until =
+                    db::login::lock_login_subject(&**pool, &subject, lock_secs).await?;
+                log::warn!(
+                    "Client IP {} is locked until {} after repeated failed logins",
+                    ip,
+                    locked_until
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the failed logins of an account after a successful login.
+    /// Failures of the client IP are kept, one valid account must not unlock guessing others.
+    pub async fn record_success(&self, pool: &PgPool, email: &str) -> Res<()> {
+        db::login::clear_login_attempts(pool, &account_subject(email)).await
+    }
+
+    /// Deletes expired attempts every `period`.
+    pub async fn clean_periodically(&self, pool: &PgPool, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            match db::login::delete_stale_login_attempts(pool, RESET_SECS).await {
+                Ok(deleted) if deleted > 0 => {
+                    log::info!("Deleted {} stale login attempts", deleted)
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to delete stale login attempts: {}", e),
+            }
+        }
+    }
+
+    /// Seconds to lock a subject for after `failures` consecutive failures, `None` below the threshold.
+    /// A threshold of 0 disables locking.
+    fn lock_secs(&self, failures: i32, threshold: i32) -> Option<i64> {
+        if threshold <= 0 || failures < threshold {
+            return None;
+        }
+        let doublings = (failures - threshold).min(32) as u32;
+        Some(
+            self.base_lock_secs
+                .saturating_mul(1 << doublings)
+                .min(self.max_lock_secs),
+        )
+    }
+
+    fn notify_locked(&self, pool: Arc<PgPool>, email: String, locked_until: NaiveDateTime) {
+        let notifier = self.notifier.clone();
+        // in the background, so the response takes the same time whether the account exists or not
+        actix_web::rt::spawn(async move {
+            if let Ok(user) = db::user::get_user_by_email(&*pool, email).await {
+                notifier.account_locked(&user, locked_until).await;
+            }
+        });
+    }
+}
+
+fn account_subject(email: &str) -> String {
+    format!("email:{}", email.trim().to_lowercase())
+}
+
+fn ip_subject(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
@@ -13,7 +13,19 @@ pub mod routes {
 pub mod middleware {
     pub mod auth;
 }
+pub mod guard {
+    pub mod login;
+}
+pub mod cache {
+    pub mod jwks;
+    pub mod oidc;
+}
+pub mod mail {
+    pub mod mailer;
+    pub mod verification;
+}
 mod services {
+    pub(crate) mod apple;
     pub(crate) mod auth;
     pub(crate) mod user;
 }
@@ -58,10 +70,17 @@ pub fn mount_auth() -> actix_web::Scope {
         .service(routes::session::get_session)
         .service(routes::auth::post_register)
         .service(routes::auth::post_login)
+        .service(routes::auth::post_verify_email)
         .service(routes::auth::get_auth_provider)
         .service(routes::auth::get_auth_provider_callback)
+        .service(routes::auth::post_auth_provider_callback)
 }
 // User endpoints
 pub fn mount_user() -> actix_web::Scope {
-    web::scope("/user").service(routes::user::get_me)
+    web::scope("/user")
+        .service(routes::user::get_me)
+        .service(routes::user::post_resend_verification)
+        .service(routes::user::get_providers)
+        .service(routes::user::post_link_provider)
+        .service(routes::user::delete_provider)
 }
@@ -0,0 +1,154 @@
+use std::{sync::Arc, time::Duration};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use common::error::{AppError, Res};
+use db::models::user::User;
+use oauth2::url::Url;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::mail::mailer::{Email, Mailer};
+
+/// Random bytes of a verification token.
+const TOKEN_BYTES: usize = 32;
+
+/// Verifies that users own the email they registered with.
+///
+/// A random token is emailed to the user as a link to the web app, which posts it
+/// back to `POST /auth/verify-email`. Only the SHA-256 hash of the token is stored.
+/// A user has at most one token at a time, and it can only be used once.
+pub struct EmailVerifier {
+    mailer: Arc<dyn Mailer>,
+    from: String,
+    link_url: String,
+    ttl: Duration,
+    resend_interval: Duration,
+}
+
+impl EmailVerifier {
+    /// Creates a verifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `mailer` - The mailer the verification links are sent with.
+    /// * `from` - The sender of the emails.
+    /// * `link_url` - The web app page the links point to.
+    /// * `ttl` - How long a link is valid.
+    /// * `resend_interval` - Minimum time between two emails to the same user.
+    pub fn new(
+        mailer: Arc<dyn Mailer>,
+        from: &str,
+        link_url: &str,
+        ttl: Duration,
+        resend_interval: Duration,
+    ) -> Self {
+        EmailVerifier {
+            mailer,
+            from: from.to_string(),
+            link_url: link_url.to_string(),
+            ttl,
+            resend_interval,
+        }
+    }
+
+    /// Sends a new verification link to a user. Earlier links of the user stop working.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - A reference to the database connection pool.
+    /// * `user` - The user to send the link to.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success, a `BadRequest` error if the email is already verified,
+    /// or a `TooManyRequests` error if a link was sent less than the resend interval ago.
+    pub async fn send(&self, pool: &PgPool, user: &User) -> Res<()> {
+        if user.verified {
+            return Err(AppError::BadRequest(
+                "Email is already verified".to_string(),
+            ));
+        }
+
+        let token = generate_token();
+        let stored = db::verification::insert_verification_token(
+            pool,
+            user.id,
+            &hash_token(&token),
+            self.ttl.as_secs() as i64,
+            self.resend_interval.as_secs() as i64,
+        )
+        .await?;
+        if !stored {
+            return Err(AppError::TooManyRequests(format!(
+                "A verification email was sent recently. Please wait {} seconds between emails.",
+                self.resend_interval.as_secs()
+            )));
+        }
+
+        let mut link = Url::parse(&self.link_url)
+            .map_err(|e| AppError::Internal(format!("Invalid email verification URL. {}", e)))?;
+        link.query_pairs_mut().append_pair("token", &token);
+        self.mailer
+            .send(Email {
+                from: self.from.clone(),
+                to: user.email.clone(),
+                subject: "Verify your email address".to_string(),
+                body: format!(
+                    "Hi {},\n\nPlease verify your email address by opening this link:\n\n{}\n\n\
+                     The link expires in {} hours. If you did not sign up, you can ignore this email.\n",
+                    user.first_name,
+                    link,
+                    self.ttl.as_secs() / 3600
+                ),
+            })
+            .await
+    }
+
+    /// Verifies the email of the user a token was sent to. The token can't be used again.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - A reference to the database connection pool.
+    /// * `token` - The token of the verification link.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the verified `User`, or a `BadRequest` error if the token is
+    /// unknown, expired or already used.
+    pub async fn verify(&self, pool: &PgPool, token: &str) -> Res<User> {
+        let mut tx = pool.begin().await?;
+        let user_id = db::verification::consume_verification_token(&mut *tx, &hash_token(token))
+            .await?
+            .ok_or_else(|| {
+                AppError::BadRequest("Invalid or expired verification token".to_string())
+            })?;
+        let user = db::user::set_user_verified(&mut *tx, user_id).await?;
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    /// Deletes expired tokens every `period`.
+    pub async fn clean_periodically(&self, pool: &PgPool, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            match db::verification::delete_expired_verification_tokens(pool).await {
+                Ok(deleted) if deleted > 0 => {
+                    log::info!("Deleted {} expired email verification tokens", deleted)
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to delete expired email verification tokens: {}", e),
+            }
+        }
+    }
+}
+
+fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
@@ -6,6 +6,7 @@ use actix_web::{
 use common::jwt;
 use futures::future::{Ready, ok};
 
+#[derive(Default)]
 pub struct AuthMiddleware {}
 
 impl AuthMiddleware {
@@ -58,9 +59,9 @@ where
                     // If claims are valid, insert them into the request extensions
                     req.extensions_mut().insert(claims);
                     // Call the next service in the chain
-                    return srv.call(req).await.map(|res| res.map_into_boxed_body())
+                    srv.call(req).await.map(|res| res.map_into_boxed_body())
                 },
-                Err(response) => return Ok(req.into_response(response)),
+                Err(response) => Ok(req.into_response(response)),
             }
         })
     }
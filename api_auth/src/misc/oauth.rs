@@ -3,6 +3,8 @@ use std::fmt;
 use common::error::{AppError, Res};
 use serde::{Deserialize, Serialize};
 
+use crate::cache::oidc::OidcRegistry;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum OAuthProvider {
     GitHub,
@@ -10,27 +12,32 @@ pub enum OAuthProvider {
     Facebook,
     Apple,
     X,
+    /// A generic OpenID Connect provider, by its configured name.
+    Oidc(String),
 }
 impl OAuthProvider {
     /// Returns the OAuth provider as a string.
-    pub fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &str {
         match self {
             OAuthProvider::GitHub => "github",
             OAuthProvider::Google => "google",
             OAuthProvider::Facebook => "facebook",
             OAuthProvider::Apple => "apple",
             OAuthProvider::X => "x",
+            OAuthProvider::Oidc(name) => name,
         }
     }
 
     /// Creates an OAuth provider from a string.
-    pub fn from_str(s: &str) -> Res<Self> {
+    /// Names other than the built-in providers are looked up in the configured OpenID Connect providers.
+    pub fn from_str(s: &str, oidc: &OidcRegistry) -> Res<Self> {
         match s {
             "github" => Ok(OAuthProvider::GitHub),
             "google" => Ok(OAuthProvider::Google),
             "facebook" => Ok(OAuthProvider::Facebook),
             "apple" => Ok(OAuthProvider::Apple),
             "x" => Ok(OAuthProvider::X),
+            ps if oidc.get(ps).is_some() => Ok(OAuthProvider::Oidc(ps.to_string())),
             ps => Err(AppError::Internal(format!(
                 "Invalid OAuth provider: {}",
                 ps
@@ -46,8 +53,20 @@ impl OAuthProvider {
             OAuthProvider::Facebook => vec!["email"],
             OAuthProvider::Apple => vec!["name email"],
             OAuthProvider::X => vec!["email"],
+            OAuthProvider::Oidc(_) => vec!["openid email profile"],
         }
     }
+
+    /// Returns `true` if the provider supports PKCE (RFC 7636).
+    pub fn supports_pkce(&self) -> bool {
+        // Apple does not document PKCE support for Sign in with Apple
+        !matches!(self, OAuthProvider::Apple)
+    }
+
+    /// Returns `true` if the user is identified by an ID token, which must carry the nonce of the flow.
+    pub fn uses_id_token(&self) -> bool {
+        matches!(self, OAuthProvider::Apple | OAuthProvider::Oidc(_))
+    }
 }
 impl fmt::Display for OAuthProvider {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -1,23 +1,58 @@
 use actix_session::Session;
-use actix_web::{HttpResponse, Responder, get, http::header::LOCATION, post, web};
+use actix_web::{HttpRequest, HttpResponse, Responder, get, http::header::LOCATION, post, web};
+use chrono::Utc;
 use common::env_config::Config;
 use common::error::{AppError, Res};
 use common::http::Success;
+use common::ip;
 use common::jwt::{self, ClaimsSpec};
-use oauth2::{AuthorizationCode, CsrfToken, Scope, TokenResponse, reqwest};
+use oauth2::{
+    AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, Scope, TokenResponse,
+    reqwest, url::Url,
+};
 use sqlx::PgPool;
 use std::sync::Arc;
 
-use crate::dtos::auth::{AuthResponse, LoginRequest, OAuthCallbackQuery, RegisterRequest};
+use crate::cache::jwks::JwksCache;
+use crate::cache::oidc::OidcRegistry;
+use crate::dtos::auth::{
+    AuthResponse, LoginRequest, OAuthCallbackQuery, OAuthFlow, OAuthUserData, PendingLink,
+    RegisterRequest, VerifyEmailRequest,
+};
+use crate::guard::login::LoginGuard;
+use crate::mail::verification::EmailVerifier;
 use crate::misc::oauth::OAuthProvider;
 use crate::services;
 
+/// Session key of the OAuth flow in progress.
+const OAUTH_FLOW_KEY: &str = "oauth_flow";
+/// Session key of the provider account waiting to be linked.
+pub(crate) const PENDING_LINK_KEY: &str = "oauth_pending_link";
+/// Seconds a provider account waits to be linked.
+const PENDING_LINK_TTL_SECS: i64 = 10 * 60;
+
+/// Error codes of OAuth providers forwarded to the web app, any other is reported as `server_error`.
+const OAUTH_ERROR_CODES: [&str; 8] = [
+    "access_denied",
+    "invalid_request",
+    "invalid_scope",
+    "server_error",
+    "temporarily_unavailable",
+    "unauthorized_client",
+    "unsupported_response_type",
+    // Apple, if the user closes the sign in dialog
+    "user_cancelled_authorize",
+];
+
 /// Registers a new user with email and password authentication.
 ///
+/// The user is created unverified and a verification link is emailed to them.
+///
 /// # Input
 /// - `req`: JSON payload containing registration information (email, password, names)
 /// - `pool`: Database connection pool
 /// - `config`: Application configuration
+/// - `verifier`: Sender of the email verification link
 ///
 /// # Output
 /// - Success: Returns the created user object with 201 Created status
@@ -50,27 +85,76 @@ async fn post_register(
     req: web::Json<RegisterRequest>,
     pool: web::Data<Arc<sqlx::PgPool>>,
     config: web::Data<Arc<Config>>,
+    verifier: web::Data<Arc<EmailVerifier>>,
 ) -> impl Responder {
-    let pg_pool: &PgPool = &**pool;
+    let pg_pool: &PgPool = &pool;
     let username_exists = services::user::exists_user_by_email(pg_pool, req.email.clone()).await?;
     if username_exists {
         return Err(AppError::BadRequest("Username already exists".to_string()));
     }
     let user =
         services::user::create_user_with_credentials(pg_pool, req.into_inner(), &config).await?;
+    // the account exists now, the user can ask for another link if this one fails
+    if let Err(e) = verifier.send(pg_pool, &user).await {
+        log::error!(
+            "Failed to send verification email to user {}: {}",
+            user.id,
+            e
+        );
+    }
     Ok(Success::created(user))
 }
 
+/// Verifies the email of a user with the token of a verification link.
+///
+/// # Input
+/// - `req`: JSON payload containing the token of the link
+/// - `pool`: Database connection pool
+/// - `verifier`: Email verification
+///
+/// # Output
+/// - Success: Returns the verified user object
+/// - Error: Returns 400 Bad Request if the token is unknown, expired or already used
+///
+/// # Frontend Example
+/// ```javascript
+/// // on the page the verification link points to, e.g. /auth/verify-email?token=...
+/// const token = new URLSearchParams(window.location.search).get('token');
+/// const response = await fetch('/api/auth/verify-email', {
+///   method: 'POST',
+///   headers: {
+///     'Content-Type': 'application/json'
+///   },
+///   body: JSON.stringify({ token })
+/// });
+///
+/// if (response.ok) {
+///   const user = await response.json();
+///   console.log('Verified email:', user.email);
+/// }
+/// ```
+#[post("/verify-email")]
+async fn post_verify_email(
+    req: web::Json<VerifyEmailRequest>,
+    pool: web::Data<Arc<PgPool>>,
+    verifier: web::Data<Arc<EmailVerifier>>,
+) -> Res<impl Responder> {
+    let user = verifier.verify(&pool, &req.token).await?;
+    Success::ok(user)
+}
+
 /// Authenticates a user with email and password.
 ///
 /// # Input
 /// - `login_data`: JSON payload containing email and password
 /// - `config`: Application configuration for JWT generation
 /// - `pool`: Database connection pool
+/// - `guard`: Failed login tracking
 ///
 /// # Output
 /// - Success: Returns an auth response with JWT token and user details
-/// - Error: Returns 401 Unauthorized for invalid credentials
+/// - Error: Returns 401 Unauthorized for an unknown email or a wrong password
+/// - Error: Returns 429 Too Many Requests while the account or client IP is locked
 ///
 /// # Frontend Example
 /// ```javascript
@@ -95,15 +179,31 @@ async fn post_register(
 /// ```
 #[post("/login")]
 pub async fn post_login(
+    req: HttpRequest,
     login_data: web::Json<LoginRequest>,
     config: web::Data<Arc<Config>>,
     pool: web::Data<Arc<PgPool>>,
+    guard: web::Data<Arc<LoginGuard>>,
 ) -> Res<impl Responder> {
-    let pg_pool: &PgPool = &**pool;
-    let user = services::auth::authenticate_user(pg_pool, &login_data.into_inner()).await?;
+    let pg_pool: &PgPool = &pool;
+    let login_data = login_data.into_inner();
+    let client_ip = ip::client_ip(&req, &config.trusted_proxies);
+
+    guard.check(pg_pool, &login_data.email, client_ip).await?;
+    let user = match services::auth::authenticate_user(pg_pool, &login_data).await {
+        Ok(user) => user,
+        Err(AppError::Unauthorized(message)) => {
+            guard
+                .record_failure(&pool, &login_data.email, client_ip)
+                .await?;
+            return Err(AppError::Unauthorized(message));
+        }
+        Err(e) => return Err(e),
+    };
+    guard.record_success(pg_pool, &login_data.email).await?;
     let token = jwt::generate_jwt(
         ClaimsSpec {
-            user_id: user.id.clone(),
+            user_id: user.id,
             stripe_customer_id: user.stripe_customer_id.clone(),
         },
         &config.jwt_config,
@@ -113,9 +213,14 @@ pub async fn post_login(
 
 /// Initiates OAuth authentication flow with the specified provider.
 ///
+/// The `state` sent to the provider and the PKCE verifier are stored in the session,
+/// the callback only accepts a code that comes back with the same `state`.
+///
 /// # Input
-/// - `path`: OAuth provider name (google, github, facebook, x, apple)
+/// - `path`: OAuth provider name (google, github, facebook, x, apple, or a configured OpenID Connect provider)
 /// - `config`: Application configuration with OAuth settings
+/// - `oidc`: Configured OpenID Connect providers
+/// - `session`: User session for storing the OAuth flow
 ///
 /// # Output
 /// - Success: Redirects user to the OAuth provider's authentication page
@@ -140,19 +245,49 @@ pub async fn post_login(
 pub async fn get_auth_provider(
     path: web::Path<String>,
     config: web::Data<Arc<Config>>,
+    oidc: web::Data<Arc<OidcRegistry>>,
+    session: Session,
 ) -> Res<impl Responder> {
-    let provider = OAuthProvider::from_str(path.as_str())?;
-    let client = services::auth::create_oauth_client(&provider, &config);
-
-    let (auth_url, _csrf_token) = client
-        .authorize_url(CsrfToken::new_random)
-        .add_scopes(
-            provider
-                .get_scopes()
-                .into_iter()
-                .map(|s| Scope::new(s.to_string())),
+    let provider = OAuthProvider::from_str(path.as_str(), &oidc)?;
+    let client = services::auth::create_oauth_client(&provider, &config, &oidc).await?;
+
+    let mut auth_request = client.authorize_url(CsrfToken::new_random).add_scopes(
+        provider
+            .get_scopes()
+            .into_iter()
+            .map(|s| Scope::new(s.to_string())),
+    );
+    let pkce_verifier = if provider.supports_pkce() {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        auth_request = auth_request.set_pkce_challenge(pkce_challenge);
+        Some(pkce_verifier.secret().to_string())
+    } else {
+        None
+    };
+    let nonce = if provider.uses_id_token() {
+        let nonce = CsrfToken::new_random().secret().to_string();
+        auth_request = auth_request.add_extra_param("nonce", nonce.clone());
+        Some(nonce)
+    } else {
+        None
+    };
+    if matches!(provider, OAuthProvider::Apple) {
+        // Apple posts the callback when name or email are requested
+        auth_request = auth_request.add_extra_param("response_mode", "form_post");
+    }
+    let (auth_url, csrf_token) = auth_request.url();
+
+    session
+        .insert(
+            OAUTH_FLOW_KEY,
+            OAuthFlow {
+                provider: provider.as_str().to_string(),
+                state: csrf_token.secret().to_string(),
+                pkce_verifier,
+                nonce,
+            },
         )
-        .url();
+        .map_err(|_| AppError::Internal("Failed to store OAuth state".to_string()))?;
 
     Ok(HttpResponse::Found()
         .append_header(("Location", auth_url.to_string()))
@@ -162,14 +297,21 @@ pub async fn get_auth_provider(
 /// Handles OAuth callback after user authenticates with the provider.
 ///
 /// # Input
-/// - `path`: OAuth provider name (google, github, facebook, x, apple)
-/// - `query`: Query parameters containing the authorization code from the OAuth provider
+/// - `path`: OAuth provider name (google, github, facebook, x, apple, or a configured OpenID Connect provider)
+/// - `query`: Query parameters containing the authorization code and state, or an error, from the OAuth provider
 /// - `config`: Application configuration
 /// - `pool`: Database connection pool
-/// - `session`: User session for storing authentication data
+/// - `jwks`: Cache of the keys providers sign ID tokens with
+/// - `oidc`: Configured OpenID Connect providers
+/// - `session`: User session holding the OAuth flow, and for storing authentication data
 ///
 /// # Output
 /// - Success: Redirects to the application callback URL with session data set
+/// - Provider error: Redirects to the application callback URL with the `error` code of the provider,
+///   if it is a known one, `server_error` otherwise. The description of the provider is not forwarded.
+/// - Error: Returns 400 Bad Request if the state does not match the flow started in this session,
+///   even if the provider returned an error
+/// - Error: Returns 401 Unauthorized if the ID token of the provider is invalid (Apple, OpenID Connect)
 /// - Error: Returns appropriate error responses for various failure scenarios
 ///
 /// # Note
@@ -186,56 +328,219 @@ async fn get_auth_provider_callback(
     query: web::Query<OAuthCallbackQuery>,
     config: web::Data<Arc<Config>>,
     pool: web::Data<Arc<PgPool>>,
+    jwks: web::Data<Arc<JwksCache>>,
+    oidc: web::Data<Arc<OidcRegistry>>,
     session: Session,
 ) -> Res<impl Responder> {
-    let provider = OAuthProvider::from_str(path.as_str())
+    let query = query.into_inner();
+    complete_oauth_flow(&path, query, &config, &pool, &jwks, &oidc, session).await
+}
+
+/// Handles OAuth callback posted by providers using `response_mode=form_post` (Apple).
+///
+/// # Input
+/// - `path`: OAuth provider name (apple)
+/// - `form`: Form containing the authorization code, state and, on the first login, the user info, or an error
+/// - `config`: Application configuration
+/// - `pool`: Database connection pool
+/// - `jwks`: Cache of the keys providers sign ID tokens with
+/// - `oidc`: Configured OpenID Connect providers
+/// - `session`: User session holding the OAuth flow, and for storing authentication data
+///
+/// # Output
+/// - Same as `GET oauth/{provider}/callback`
+///
+/// # Note
+/// The provider posts from its own site, so the session cookie is only sent if it is `SameSite=None`,
+/// which requires a secure cookie (HTTPS).
+#[post("oauth/{provider}/callback")]
+async fn post_auth_provider_callback(
+    path: web::Path<String>,
+    form: web::Form<OAuthCallbackQuery>,
+    config: web::Data<Arc<Config>>,
+    pool: web::Data<Arc<PgPool>>,
+    jwks: web::Data<Arc<JwksCache>>,
+    oidc: web::Data<Arc<OidcRegistry>>,
+    session: Session,
+) -> Res<impl Responder> {
+    let form = form.into_inner();
+    complete_oauth_flow(&path, form, &config, &pool, &jwks, &oidc, session).await
+}
+
+/// Completes the OAuth flow started in the session: exchanges the code, gets the
+/// user data of the provider, signs the user up or in and stores the token in the session.
+async fn complete_oauth_flow(
+    provider: &str,
+    query: OAuthCallbackQuery,
+    config: &Config,
+    pg_pool: &PgPool,
+    jwks: &JwksCache,
+    oidc: &OidcRegistry,
+    session: Session,
+) -> Res<HttpResponse> {
+    let provider = OAuthProvider::from_str(provider, oidc)
         .map_err(|_| AppError::BadRequest("Invalid provider".to_string()))?;
-    let client = services::auth::create_oauth_client(&provider, &config);
-    let pg_pool: &PgPool = &**pool;
+
+    // a flow can only be completed once
+    let flow = session
+        .remove_as::<OAuthFlow>(OAUTH_FLOW_KEY)
+        .and_then(Result::ok);
+
+    // the state is checked before anything else of the callback is trusted,
+    // errors included, so only the flow started in this session is redirected
+    let Some(state) = query.state else {
+        return Err(AppError::BadRequest("Missing OAuth state".to_string()));
+    };
+    let flow = flow
+        .filter(|flow| flow.provider == provider.as_str() && flow.state == state)
+        .ok_or_else(|| AppError::BadRequest("Invalid OAuth state".to_string()))?;
+
+    // the description is free text of whoever made the request, it is only logged
+    if let Some(error) = query.error {
+        log::info!(
+            "OAuth provider {} returned error '{}': {}",
+            provider,
+            error,
+            query.error_description.as_deref().unwrap_or_default()
+        );
+        let error = OAUTH_ERROR_CODES
+            .into_iter()
+            .find(|code| *code == error)
+            .unwrap_or("server_error");
+        return redirect_with_error(config, error, None);
+    }
+
+    let Some(code) = query.code else {
+        return Err(AppError::BadRequest(
+            "Missing authorization code".to_string(),
+        ));
+    };
 
     let http_client = reqwest::ClientBuilder::new()
         .redirect(reqwest::redirect::Policy::none())
         .build()
         .expect("Client should build");
 
-    let token = client
-        .exchange_code(AuthorizationCode::new(query.code.clone()))
+    let client = services::auth::create_oauth_client(&provider, config, oidc).await?;
+    let mut token_request = client.exchange_code(AuthorizationCode::new(code));
+    if let Some(pkce_verifier) = flow.pkce_verifier {
+        token_request = token_request.set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier));
+    }
+    let token = token_request
         .request_async(&http_client)
         .await
         .map_err(|e| AppError::Internal(format!("Failed to exchange code. {}", e)))?;
 
-    let access_token = token.access_token().secret();
-    let user_data = services::auth::fetch_provider_user_data(&provider, access_token).await?;
-
-    let existing_user =
-        services::user::exists_user_by_email(pg_pool, user_data.email.clone()).await?;
+    let id_token = token.extra_fields().id_token.as_deref();
+    let user_data = match &provider {
+        // Apple has no user info endpoint, the user is identified by the ID token
+        OAuthProvider::Apple => {
+            let id_token = id_token.ok_or_else(|| {
+                AppError::Unauthorized("Missing ID token in the token response".to_string())
+            })?;
+            services::apple::fetch_apple_user_data(
+                jwks,
+                &config.apple_sign_in,
+                &config.apple_client.client_id,
+                id_token,
+                flow.nonce.as_deref(),
+                query.user.as_deref(),
+            )
+            .await?
+        }
+        OAuthProvider::Oidc(name) => {
+            let id_token = id_token.ok_or_else(|| {
+                AppError::Unauthorized("Missing ID token in the token response".to_string())
+            })?;
+            let user_info = oidc
+                .fetch_user_info(
+                    jwks,
+                    name,
+                    id_token,
+                    token.access_token().secret(),
+                    flow.nonce.as_deref(),
+                )
+                .await?;
+            OAuthUserData {
+                email: user_info.email,
+                email_verified: user_info.email_verified,
+                first_name: user_info.first_name,
+                last_name: user_info.last_name,
+                provider_user_id: user_info.subject,
+            }
+        }
+        _ => {
+            let access_token = token.access_token().secret();
+            services::auth::fetch_provider_user_data(&provider, access_token).await?
+        }
+    };
 
-    let auth_response = if existing_user {
-        let user = services::user::get_user_by_email(pg_pool, user_data.email).await?;
-        let token = jwt::generate_jwt(
-            ClaimsSpec {
-                user_id: user.id.clone(),
-                stripe_customer_id: user.stripe_customer_id.clone(),
-            },
-            &config.jwt_config,
-        )?;
-        AuthResponse { token, user }
-    } else {
-        let user =
-            services::user::create_user_with_oauth(pg_pool, user_data, &provider, &config).await?;
-        let token = jwt::generate_jwt(
-            ClaimsSpec {
-                user_id: user.id.clone(),
-                stripe_customer_id: user.stripe_customer_id.clone(),
-            },
-            &config.jwt_config,
-        )?;
-        AuthResponse { token, user }
+    let user = match services::user::get_user_by_provider(
+        pg_pool,
+        &provider,
+        &user_data.provider_user_id,
+    )
+    .await?
+    {
+        Some(user) => user,
+        None if user_data.email.is_empty() => {
+            return Err(AppError::BadRequest(
+                "The provider did not share an email address".to_string(),
+            ));
+        }
+        None => {
+            let existing_user =
+                services::user::exists_user_by_email(pg_pool, user_data.email.clone()).await?;
+            if !existing_user {
+                services::user::create_user_with_oauth(pg_pool, user_data, &provider, config)
+                    .await?
+            } else if user_data.email_verified {
+                // the provider vouches for the email, so its account belongs to the same person
+                let user =
+                    services::user::get_user_by_email(pg_pool, user_data.email.clone()).await?;
+                services::user::link_verified_provider(
+                    pg_pool,
+                    user,
+                    &provider,
+                    &user_data.provider_user_id,
+                )
+                .await?
+            } else {
+                // anyone can claim an unverified email, the owner of the account must confirm
+                session
+                    .insert(
+                        PENDING_LINK_KEY,
+                        PendingLink {
+                            provider: provider.as_str().to_string(),
+                            provider_user_id: user_data.provider_user_id,
+                            email: user_data.email,
+                            expires_at: Utc::now().timestamp() + PENDING_LINK_TTL_SECS,
+                        },
+                    )
+                    .map_err(|_| AppError::Internal("Failed to store pending link".to_string()))?;
+                return redirect_with_error(
+                    config,
+                    "link_required",
+                    Some("An account with this email exists. Log in to it to link this provider."),
+                );
+            }
+        }
     };
 
+    let token = jwt::generate_jwt(
+        ClaimsSpec {
+            user_id: user.id,
+            stripe_customer_id: user.stripe_customer_id.clone(),
+        },
+        &config.jwt_config,
+    )?;
+    let auth_response = AuthResponse { token, user };
+
     let user_string = serde_json::to_string(&auth_response.user).unwrap();
     let redirect_uri = config.web_app_auth_callback_url.as_str();
 
+    // a new session on login, so a session planted before it can't be taken over
+    session.renew();
     session
         .insert("token", &auth_response.token)
         .map_err(|_| AppError::Internal("Failed to insert token cookie".to_string()))?;
@@ -247,3 +552,22 @@ async fn get_auth_provider_callback(
         .append_header((LOCATION, redirect_uri))
         .finish())
 }
+
+/// Redirects to the web app callback URL with an OAuth error.
+fn redirect_with_error(
+    config: &Config,
+    error: &str,
+    description: Option<&str>,
+) -> Res<HttpResponse> {
+    let mut redirect_uri = Url::parse(&config.web_app_auth_callback_url)
+        .map_err(|e| AppError::Internal(format!("Invalid web app callback URL. {}", e)))?;
+    redirect_uri.query_pairs_mut().append_pair("error", error);
+    if let Some(description) = description {
+        redirect_uri
+            .query_pairs_mut()
+            .append_pair("error_description", description);
+    }
+    Ok(HttpResponse::Found()
+        .append_header((LOCATION, redirect_uri.as_str()))
+        .finish())
+}
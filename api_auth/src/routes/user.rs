@@ -1,10 +1,20 @@
 use std::sync::Arc;
 
-use actix_web::{Responder, get, web};
-use common::{http::Success, jwt::JwtClaims};
+use actix_session::Session;
+use actix_web::{HttpResponse, Responder, delete, get, post, web};
+use chrono::Utc;
+use common::{
+    error::{AppError, Res},
+    http::Success,
+    jwt::JwtClaims,
+};
 use sqlx::PgPool;
+use uuid::Uuid;
 
-use crate::services;
+use crate::{
+    dtos::auth::PendingLink, mail::verification::EmailVerifier, routes::auth::PENDING_LINK_KEY,
+    services,
+};
 
 /// Endpoint to retrieve the current authenticated user's information.
 ///
@@ -50,7 +60,156 @@ async fn get_me(
     pool: web::Data<Arc<sqlx::PgPool>>,
 ) -> impl Responder {
     let user_id = claims.user_id;
-    let pg_pool: &PgPool = &**pool;
+    let pg_pool: &PgPool = &pool;
     let user = services::user::get_user_by_id(pg_pool, user_id).await?;
     Success::ok(user)
 }
+
+/// Sends another email verification link to the authenticated user.
+///
+/// # Input
+/// - `claims`: The JWT claims of the authenticated user
+/// - `pool`: Database connection pool
+/// - `verifier`: Sender of the email verification link
+///
+/// # Output
+/// - Success: Returns 204 No Content once the link is sent, earlier links stop working
+/// - Error: Returns 400 Bad Request if the email is already verified
+/// - Error: Returns 429 Too Many Requests if a link was sent to the user less than a minute ago (`EMAIL_VERIFICATION_RESEND_SECS`)
+///
+/// # Frontend Example
+/// ```javascript
+/// const response = await fetch('/api/dashboard/user/verify-email/resend', {
+///   method: 'POST',
+///   headers: {
+///     'Authorization': `Bearer ${localStorage.getItem('authToken')}`
+///   }
+/// });
+/// ```
+#[post("/verify-email/resend")]
+async fn post_resend_verification(
+    claims: web::ReqData<JwtClaims>,
+    pool: web::Data<Arc<PgPool>>,
+    verifier: web::Data<Arc<EmailVerifier>>,
+) -> Res<impl Responder> {
+    let user = services::user::get_user_by_id(&pool, claims.user_id).await?;
+    verifier.send(&pool, &user).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Lists the provider accounts (GitHub, Google, ...) linked to the authenticated user.
+///
+/// # Input
+/// - `claims`: The JWT claims of the authenticated user
+/// - `pool`: Database connection pool
+///
+/// # Output
+/// - Success: Returns the linked provider accounts, oldest first
+///
+/// # Frontend Example
+/// ```javascript
+/// const response = await fetch('/api/dashboard/user/providers', {
+///   headers: {
+///     'Authorization': `Bearer ${localStorage.getItem('authToken')}`
+///   }
+/// });
+///
+/// if (response.ok) {
+///   const providers = await response.json();
+///   // [{ id: "5f0c...", provider: "github", provider_user_id: "583231", created_at: "..." }]
+/// }
+/// ```
+#[get("/providers")]
+async fn get_providers(
+    claims: web::ReqData<JwtClaims>,
+    pool: web::Data<Arc<PgPool>>,
+) -> Res<impl Responder> {
+    let providers = services::user::get_linked_providers(&pool, claims.user_id).await?;
+    Success::ok(providers)
+}
+
+/// Unlinks a provider account from the authenticated user.
+///
+/// # Input
+/// - `claims`: The JWT claims of the authenticated user
+/// - `pool`: Database connection pool
+/// - `path`: The ID of the linked provider account
+///
+/// # Output
+/// - Success: Returns the unlinked provider account
+/// - Error: Returns 404 Not Found if the user has no such provider account
+/// - Error: Returns 400 Bad Request if it is the last way the user can log in
+///
+/// # Frontend Example
+/// ```javascript
+/// const response = await fetch(`/api/dashboard/user/providers/${providerId}`, {
+///   method: 'DELETE',
+///   headers: {
+///     'Authorization': `Bearer ${localStorage.getItem('authToken')}`
+///   }
+/// });
+/// ```
+#[delete("/providers/{provider_id}")]
+async fn delete_provider(
+    claims: web::ReqData<JwtClaims>,
+    pool: web::Data<Arc<PgPool>>,
+    path: web::Path<Uuid>,
+) -> Res<impl Responder> {
+    let provider =
+        services::user::unlink_provider(&pool, claims.user_id, path.into_inner()).await?;
+    Success::ok(provider)
+}
+
+/// Links the provider account waiting in the session to the authenticated user.
+///
+/// An OAuth login whose email matches an existing account, but is not verified by
+/// the provider, is not logged in. The provider account waits in the session until
+/// the owner of the account logs in and confirms the link with this endpoint.
+///
+/// # Input
+/// - `claims`: The JWT claims of the authenticated user
+/// - `pool`: Database connection pool
+/// - `session`: User session holding the provider account waiting to be linked
+///
+/// # Output
+/// - Success: Returns the linked provider account with 201 Created status
+/// - Error: Returns 400 Bad Request if no provider account is waiting, it expired, or its email
+///   is not the email of the authenticated user
+///
+/// # Frontend Example
+/// ```javascript
+/// // after the OAuth callback redirected with `error=link_required` and the user logged in
+/// const response = await fetch('/api/dashboard/user/providers/link', {
+///   method: 'POST',
+///   credentials: 'include', // sends the session cookie
+///   headers: {
+///     'Authorization': `Bearer ${localStorage.getItem('authToken')}`
+///   }
+/// });
+/// ```
+#[post("/providers/link")]
+async fn post_link_provider(
+    claims: web::ReqData<JwtClaims>,
+    pool: web::Data<Arc<PgPool>>,
+    session: Session,
+) -> Res<impl Responder> {
+    let pending = session
+        .remove_as::<PendingLink>(PENDING_LINK_KEY)
+        .and_then(Result::ok)
+        .filter(|pending| pending.expires_at > Utc::now().timestamp())
+        .ok_or_else(|| {
+            AppError::BadRequest("No provider account is waiting to be linked".to_string())
+        })?;
+
+    let user = services::user::get_user_by_id(&pool, claims.user_id).await?;
+    if !user.email.eq_ignore_ascii_case(&pending.email) {
+        return Err(AppError::BadRequest(
+            "The provider account has another email than this account".to_string(),
+        ));
+    }
+
+    let provider =
+        services::user::link_provider(&pool, user.id, &pending.provider, &pending.provider_user_id)
+            .await?;
+    Success::created(provider)
+}
@@ -0,0 +1,138 @@
+use chrono::Utc;
+use common::{
+    env_config::AppleSignInConfig,
+    error::{AppError, Res},
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, Validation, encode};
+use serde::{Deserialize, Serialize};
+
+use crate::{cache::jwks::JwksCache, dtos::auth::OAuthUserData};
+
+/// Issuer of Apple ID tokens, and audience of the client secret.
+const APPLE_ISSUER: &str = "https://appleid.apple.com";
+
+/// Seconds a generated client secret is valid. Apple accepts up to 6 months,
+/// a secret is generated for each token request.
+const CLIENT_SECRET_TTL_SECS: i64 = 60 * 60;
+
+#[derive(Debug, Serialize)]
+struct ClientSecretClaims<'a> {
+    iss: &'a str,
+    iat: i64,
+    exp: i64,
+    aud: &'a str,
+    sub: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct AppleIdTokenClaims {
+    sub: String,
+    email: Option<String>,
+    /// A boolean, or `"true"` / `"false"` in older tokens.
+    email_verified: Option<serde_json::Value>,
+    nonce: Option<String>,
+}
+
+/// User info Apple posts to the callback on the first login only.
+#[derive(Debug, Deserialize)]
+struct AppleUser {
+    name: Option<AppleUserName>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AppleUserName {
+    first_name: Option<String>,
+    last_name: Option<String>,
+}
+
+/// Generates the client secret Apple requires instead of a static one.
+///
+/// # Arguments
+///
+/// * `config` - The Sign in with Apple configuration with the team, key ID and private key.
+/// * `client_id` - The Services ID of the app.
+///
+/// # Returns
+///
+/// A `Result` containing the client secret, a JWT signed with ES256.
+pub(crate) fn generate_client_secret(config: &AppleSignInConfig, client_id: &str) -> Res<String> {
+    let key = EncodingKey::from_ec_pem(config.private_key.as_bytes())
+        .map_err(|e| AppError::Internal(format!("Invalid Apple private key. {}", e)))?;
+    let mut header = Header::new(Algorithm::ES256);
+    header.kid = Some(config.key_id.clone());
+
+    let now = Utc::now().timestamp();
+    let claims = ClientSecretClaims {
+        iss: &config.team_id,
+        iat: now,
+        exp: now + CLIENT_SECRET_TTL_SECS,
+        aud: APPLE_ISSUER,
+        sub: client_id,
+    };
+    encode(&header, &claims, &key)
+        .map_err(|e| AppError::Internal(format!("Failed to generate Apple client secret. {}", e)))
+}
+
+/// Gets the user data of a Sign in with Apple login from its ID token.
+///
+/// Apple has no user info endpoint. The ID token holds the user ID and email, the
+/// name is only posted to the callback on the first login.
+///
+/// # Arguments
+///
+/// * `jwks` - The cache of the keys Apple signs ID tokens with.
+/// * `config` - The Sign in with Apple configuration.
+/// * `client_id` - The Services ID of the app, the expected audience.
+/// * `id_token` - The ID token returned by the token endpoint.
+/// * `nonce` - The nonce sent to Apple when the flow started.
+/// * `user` - The `user` JSON posted to the callback, if any.
+///
+/// # Returns
+///
+/// A `Result` containing the `OAuthUserData` or an `Unauthorized` error if the ID token is invalid.
+pub(crate) async fn fetch_apple_user_data(
+    jwks: &JwksCache,
+    config: &AppleSignInConfig,
+    client_id: &str,
+    id_token: &str,
+    nonce: Option<&str>,
+    user: Option<&str>,
+) -> Res<OAuthUserData> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[APPLE_ISSUER]);
+    validation.set_audience(&[client_id]);
+    let claims: AppleIdTokenClaims = jwks.verify(&config.jwks_url, id_token, &validation).await?;
+
+    if claims.nonce.as_deref() != nonce {
+        return Err(AppError::Unauthorized(
+            "Invalid ID token. Nonce mismatch".to_string(),
+        ));
+    }
+
+    // a malformed name must not fail the login, it is only used to fill the profile
+    let name = user
+        .and_then(|user| serde_json::from_str::<AppleUser>(user).ok())
+        .and_then(|user| user.name);
+    let (first_name, last_name) = match name {
+        Some(name) => (
+            name.first_name.unwrap_or_default(),
+            name.last_name.unwrap_or_default(),
+        ),
+        None => (String::new(), String::new()),
+    };
+
+    let email_verified = match claims.email_verified {
+        Some(serde_json::Value::Bool(verified)) => verified,
+        Some(serde_json::Value::String(verified)) => verified == "true",
+        _ => false,
+    };
+
+    Ok(OAuthUserData {
+        email: claims.email.unwrap_or_default(),
+        email_verified,
+        first_name,
+        last_name,
+        provider_user_id: claims.sub,
+    })
+}
@@ -1,77 +1,140 @@
+use std::sync::LazyLock;
+
 use argon2::{
     Argon2,
-    password_hash::{PasswordHash, PasswordVerifier},
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
 };
 use common::{
-    env_config::Config,
+    env_config::{Config, OAuthProviderClient},
     error::{AppError, Res},
 };
 use db::models::user::User;
 use oauth2::basic::*;
 use oauth2::*;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 
 use crate::{
+    cache::oidc::OidcRegistry,
     dtos::auth::{LoginRequest, OAuthUserData},
     misc::oauth::OAuthProvider,
+    services::apple,
 };
 
+/// Extra fields of a token response, the ID token of OpenID Connect providers.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IdTokenFields {
+    pub id_token: Option<String>,
+}
+impl ExtraTokenFields for IdTokenFields {}
+
+/// Token response of a provider, with the ID token if one is returned.
+pub type OAuthTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+
+/// OAuth client with authorization and token endpoints set.
+pub type OAuthClient = Client<
+    BasicErrorResponse,
+    OAuthTokenResponse,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointSet,
+>;
+
 /// Create OAuth client object.
 ///
+/// For Apple, the client secret is generated from the Sign in with Apple key if one is configured.
+/// For OpenID Connect providers, the endpoints are read from the discovery document.
+///
 /// # Arguments
 ///
 /// * `provider` - The OAuth provider.
 /// * `config` - The application configuration.
+/// * `oidc` - The configured OpenID Connect providers.
 ///
 /// # Returns
 ///
-/// A `Client` object for the specified OAuth provider.
-pub fn create_oauth_client(
+/// A `Result` containing the `Client` object for the specified OAuth provider.
+pub async fn create_oauth_client(
     provider: &OAuthProvider,
     config: &Config,
-) -> Client<
-    StandardErrorResponse<BasicErrorResponseType>,
-    StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>,
-    StandardTokenIntrospectionResponse<EmptyExtraTokenFields, BasicTokenType>,
-    StandardRevocableToken,
-    StandardErrorResponse<RevocationErrorResponseType>,
-    EndpointSet,
-    EndpointNotSet,
-    EndpointNotSet,
-    EndpointNotSet,
-    EndpointSet,
-> {
+    oidc: &OidcRegistry,
+) -> Res<OAuthClient> {
+    // whether the client credentials are sent in the request body instead of a basic auth header
+    let mut credentials_in_body = false;
     let provider_client = match provider {
-        OAuthProvider::GitHub => &config.github_client,
-        OAuthProvider::Google => &config.google_client,
-        OAuthProvider::Facebook => &config.facebook_client,
-        OAuthProvider::Apple => &config.apple_client,
-        OAuthProvider::X => &config.x_client,
-        // _ => panic!("Unsupported OAuth provider"),
+        OAuthProvider::GitHub => config.github_client.clone(),
+        OAuthProvider::Google => config.google_client.clone(),
+        OAuthProvider::Facebook => config.facebook_client.clone(),
+        OAuthProvider::Apple => {
+            // Apple only accepts the client credentials in the request body
+            credentials_in_body = true;
+            config.apple_client.clone()
+        }
+        OAuthProvider::X => config.x_client.clone(),
+        OAuthProvider::Oidc(name) => {
+            let metadata = oidc.metadata(name).await?;
+            let provider_config = oidc
+                .get(name)
+                .ok_or_else(|| AppError::BadRequest(format!("Invalid OAuth provider: {}", name)))?;
+            let auth_methods = &metadata.token_endpoint_auth_methods_supported;
+            credentials_in_body = !auth_methods.is_empty()
+                && !auth_methods.iter().any(|m| m == "client_secret_basic")
+                && auth_methods.iter().any(|m| m == "client_secret_post");
+            OAuthProviderClient {
+                client_id: provider_config.client_id.clone(),
+                client_secret: provider_config.client_secret.clone(),
+                auth_url: metadata.authorization_endpoint.clone(),
+                token_url: metadata.token_endpoint.clone(),
+                redirect_uri: provider_config.redirect_uri.clone(),
+            }
+        }
     };
 
-    let client_id = ClientId::new(provider_client.client_id.clone());
-    let client_secret = ClientSecret::new(provider_client.client_secret.clone());
-    let auth_url =
-        AuthUrl::new(provider_client.auth_url.clone()).expect("Invalid authorization endpoint URL");
-    let token_url =
-        TokenUrl::new(provider_client.token_url.clone()).expect("Invalid token endpoint URL");
+    let client_secret = match provider {
+        OAuthProvider::Apple if !config.apple_sign_in.private_key.is_empty() => {
+            apple::generate_client_secret(&config.apple_sign_in, &provider_client.client_id)?
+        }
+        _ => provider_client.client_secret,
+    };
+    let client_id = ClientId::new(provider_client.client_id);
+    let client_secret = ClientSecret::new(client_secret);
+    let auth_url = AuthUrl::new(provider_client.auth_url)
+        .map_err(|e| AppError::Internal(format!("Invalid authorization endpoint URL. {}", e)))?;
+    let token_url = TokenUrl::new(provider_client.token_url)
+        .map_err(|e| AppError::Internal(format!("Invalid token endpoint URL. {}", e)))?;
 
-    let client = BasicClient::new(client_id)
+    let client = Client::new(client_id)
         .set_client_secret(client_secret)
         .set_auth_uri(auth_url)
         .set_token_uri(token_url)
         .set_redirect_uri(
-            RedirectUrl::new(provider_client.redirect_uri.to_string())
-                .expect("Invalid redirect URL"),
+            RedirectUrl::new(provider_client.redirect_uri).expect("Invalid redirect URL"),
         );
-
-    client
+    Ok(if credentials_in_body {
+        client.set_auth_type(AuthType::RequestBody)
+    } else {
+        client
+    })
 }
 
+/// Hash verified when no account matches the email, so that the response takes
+/// as long as for a wrong password.
+static DUMMY_PASSWORD_HASH: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(b"dummy password", &salt)
+        .expect("Failed to hash dummy password")
+        .to_string()
+});
+
 /// Authenticates existing user.
-/// If user does not exists, returns 400
-/// If password hash does not match stored password hash, returns 401
+/// If user does not exist or the password does not match, returns 401 with the same message,
+/// so callers can't tell whether an email is registered
 ///
 /// # Arguments
 ///
@@ -82,19 +145,22 @@ pub fn create_oauth_client(
 ///
 /// A `Result` containing the `User` object or an `AppError` if an error occurs.
 pub async fn authenticate_user(pool: &PgPool, login_data: &LoginRequest) -> Res<User> {
-    let (user, credentials) = db::user::get_user_with_password_hash(pool, login_data.email.clone())
-        .await
-        .map_err(|_| AppError::BadRequest("User with this email does not exist".to_string()))?;
+    let found = db::user::get_user_with_password_hash(pool, login_data.email.clone()).await?;
+    let password_hash = match &found {
+        Some((_, credentials)) => credentials.password_hash.as_str(),
+        None => DUMMY_PASSWORD_HASH.as_str(),
+    };
 
-    let parsed_hash = PasswordHash::new(&credentials.password_hash).unwrap();
+    let parsed_hash = PasswordHash::new(password_hash).unwrap();
     let is_valid = Argon2::default()
         .verify_password(login_data.password.as_bytes(), &parsed_hash)
         .is_ok();
 
-    if is_valid {
-        Ok(user)
-    } else {
-        Err(AppError::Unauthorized("Invalid credentials".to_string()))
+    match found {
+        Some((user, _)) if is_valid => Ok(user),
+        _ => Err(AppError::Unauthorized(
+            "Invalid email or password".to_string(),
+        )),
     }
 }
 
@@ -142,43 +208,20 @@ async fn fetch_github_user_data(access_token: &str) -> Res<OAuthUserData> {
             .await
             .map_err(|e| AppError::Internal(format!("Failed to parse GitHub user data: {}", e)))?;
 
-        let email = match github_user["email"].as_str() {
-            Some(email) => email.to_string(),
-            None => {
-                // If the email is not public, fetch it from the emails API
-                let emails_url = "https://api.github.com/user/emails";
-                let emails_response = client
-                    .get(emails_url)
-                    .header("Authorization", format!("Bearer {}", access_token))
-                    .header("User-Agent", "WebServer")
-                    .send()
-                    .await
-                    .map_err(|e| {
-                        AppError::Internal(format!("Failed to fetch GitHub emails: {}", e))
-                    })?;
-
-                if emails_response.status().is_success() {
-                    let emails: Vec<serde_json::Value> =
-                        emails_response.json().await.map_err(|e| {
-                            AppError::Internal(format!("Failed to parse GitHub emails: {}", e))
-                        })?;
-
-                    // Find the primary email
-                    emails
-                        .iter()
-                        .find(|email| email["primary"].as_bool().unwrap_or(false))
-                        .and_then(|email| email["email"].as_str())
-                        .unwrap_or("")
-                        .to_string()
-                } else {
-                    log::warn!(
-                        "Failed to fetch GitHub emails: {:?}",
-                        emails_response.status()
-                    );
-                    "".to_string()
-                }
-            }
-        };
+        // the public email of the profile is not necessarily verified, the emails API tells
+        let emails = fetch_github_emails(&client, access_token).await;
+        let public_email = github_user["email"].as_str();
+        let email_entry = emails.iter().find(|entry| match public_email {
+            Some(public_email) => entry["email"].as_str() == Some(public_email),
+            None => entry["primary"].as_bool().unwrap_or(false),
+        });
+        let email = public_email
+            .or_else(|| email_entry.and_then(|entry| entry["email"].as_str()))
+            .unwrap_or("")
+            .to_string();
+        let email_verified = email_entry
+            .and_then(|entry| entry["verified"].as_bool())
+            .unwrap_or(false);
         let name = github_user["name"].as_str().unwrap_or("").to_string();
         let names: Vec<&str> = name.split(' ').collect();
         let first_name = names.first().unwrap_or(&"").to_string();
@@ -187,6 +230,7 @@ async fn fetch_github_user_data(access_token: &str) -> Res<OAuthUserData> {
 
         Ok(OAuthUserData {
             email,
+            email_verified,
             first_name,
             last_name,
             provider_user_id,
@@ -199,6 +243,36 @@ async fn fetch_github_user_data(access_token: &str) -> Res<OAuthUserData> {
     }
 }
 
+/// Fetches the email addresses of a GitHub user, empty if they can't be fetched.
+async fn fetch_github_emails(
+    client: &reqwest::Client,
+    access_token: &str,
+) -> Vec<serde_json::Value> {
+    let response = client
+        .get("https://api.github.com/user/emails")
+        .header("Authorization", format!("Bearer {}", access_token))
+        .header("User-Agent", "WebServer")
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            response.json().await.unwrap_or_else(|e| {
+                log::warn!("Failed to parse GitHub emails: {}", e);
+                Vec::new()
+            })
+        }
+        Ok(response) => {
+            log::warn!("Failed to fetch GitHub emails: {:?}", response.status());
+            Vec::new()
+        }
+        Err(e) => {
+            log::warn!("Failed to fetch GitHub emails: {}", e);
+            Vec::new()
+        }
+    }
+}
+
 async fn fetch_google_user_data(access_token: &str) -> Res<OAuthUserData> {
     let client = reqwest::Client::new();
     let request = client
@@ -222,10 +296,12 @@ async fn fetch_google_user_data(access_token: &str) -> Res<OAuthUserData> {
             .as_str()
             .unwrap_or("")
             .to_string();
+        let email_verified = google_user["email_verified"].as_bool().unwrap_or(false);
         let provider_user_id = google_user["sub"].to_string();
 
         Ok(OAuthUserData {
             email,
+            email_verified,
             first_name,
             last_name,
             provider_user_id,
@@ -266,8 +342,10 @@ async fn fetch_facebook_user_data(access_token: &str) -> Res<OAuthUserData> {
             .to_string();
         let provider_user_id = facebook_user["id"].to_string();
 
+        // Facebook does not tell whether the email was verified
         Ok(OAuthUserData {
             email,
+            email_verified: false,
             first_name,
             last_name,
             provider_user_id,
@@ -301,10 +379,12 @@ async fn fetch_x_user_data(access_token: &str) -> Res<OAuthUserData> {
         let email = x_user["email"].as_str().unwrap_or("").to_string();
         let name = x_user["name"].as_str().unwrap_or("").to_string();
         let parts: Vec<&str> = name.split(' ').collect();
-        let first_name = parts.get(0).unwrap_or(&"").to_string();
+        let first_name = parts.first().unwrap_or(&"").to_string();
         let last_name = parts.get(1..).unwrap_or(&[""]).join(" ");
+        // X does not tell whether the email was verified
         Ok(OAuthUserData {
             email,
+            email_verified: false,
             first_name,
             last_name,
             provider_user_id,
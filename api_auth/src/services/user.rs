@@ -4,11 +4,11 @@ use argon2::password_hash::SaltString;
 use argon2::password_hash::rand_core::OsRng;
 use argon2::{Argon2, password_hash::PasswordHasher};
 use common::env_config::Config;
-use common::error::Res;
+use common::error::{AppError, Res};
 use common::misc::UserVerificationOrigin;
 use common::stripe;
 use db::dtos::user::{AuthProviderCreateRequest, UserCreateRequest};
-use db::models::user::{AuthCredentials, User};
+use db::models::user::{AuthCredentials, AuthProvider, User};
 
 use sqlx::PgPool;
 use uuid::Uuid;
@@ -55,8 +55,171 @@ pub async fn get_user_by_id(pool: &PgPool, user_id: Uuid) -> Res<User> {
     db::user::get_user_by_id(pool, user_id).await
 }
 
+/// Retrieves the user a provider account is linked to.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `provider` - The OAuth provider.
+/// * `provider_user_id` - The ID of the user at the provider.
+///
+/// # Returns
+///
+/// A `Result` containing the linked `User`, or `None` if the provider account is not linked.
+pub async fn get_user_by_provider(
+    pool: &PgPool,
+    provider: &OAuthProvider,
+    provider_user_id: &str,
+) -> Res<Option<User>> {
+    db::user::get_user_by_provider(pool, provider.as_str(), provider_user_id).await
+}
+
+/// Links a provider account to an existing user.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `user_id` - The ID of the user.
+/// * `provider` - The name of the OAuth provider.
+/// * `provider_user_id` - The ID of the user at the provider.
+///
+/// # Returns
+///
+/// A `Result` containing the linked `AuthProvider`, or a `BadRequest` error if the
+/// provider account is already linked to a user.
+pub async fn link_provider(
+    pool: &PgPool,
+    user_id: Uuid,
+    provider: &str,
+    provider_user_id: &str,
+) -> Res<AuthProvider> {
+    db::user::insert_user_with_provider(
+        pool,
+        AuthProviderCreateRequest {
+            user_id,
+            provider: provider.to_string(),
+            provider_user_id: provider_user_id.to_string(),
+        },
+    )
+    .await
+    .map_err(already_linked_error)
+}
+
+/// Links a provider account that verified the email of an existing user to that user.
+///
+/// If the user never verified their email, the provider proves it belongs to them. Their
+/// password is removed, as whoever registered the email first may have set it, and the
+/// user is marked as verified.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `user` - The user with the email.
+/// * `provider` - The OAuth provider.
+/// * `provider_user_id` - The ID of the user at the provider.
+///
+/// # Returns
+///
+/// A `Result` containing the `User`, or a `BadRequest` error if the provider account is
+/// already linked to a user.
+pub async fn link_verified_provider(
+    pool: &PgPool,
+    user: User,
+    provider: &OAuthProvider,
+    provider_user_id: &str,
+) -> Res<User> {
+    let mut tx = pool.begin().await?;
+
+    db::user::insert_user_with_provider(
+        &mut *tx,
+        AuthProviderCreateRequest {
+            user_id: user.id,
+            provider: provider.as_str().to_string(),
+            provider_user_id: provider_user_id.to_string(),
+        },
+    )
+    .await
+    .map_err(already_linked_error)?;
+
+    let user = if user.verified {
+        user
+    } else {
+        log::info!(
+            "Email of user {} verified by {}, removing the password set before",
+            user.id,
+            provider
+        );
+        db::user::delete_credentials(&mut *tx, user.id).await?;
+        db::user::set_user_verified(&mut *tx, user.id).await?
+    };
+
+    tx.commit().await?;
+    Ok(user)
+}
+
+fn already_linked_error(e: AppError) -> AppError {
+    match e {
+        AppError::Database(sqlx::Error::Database(db_error)) if db_error.is_unique_violation() => {
+            AppError::BadRequest("This provider account is already linked".to_string())
+        }
+        e => e,
+    }
+}
+
+/// Retrieves the provider accounts linked to a user.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `user_id` - The ID of the user.
+///
+/// # Returns
+///
+/// A `Result` containing the linked `AuthProvider`s, oldest first.
+pub async fn get_linked_providers(pool: &PgPool, user_id: Uuid) -> Res<Vec<AuthProvider>> {
+    db::user::get_auth_providers(pool, user_id).await
+}
+
+/// Unlinks a provider account from a user.
+/// The last login method of a user, a password or a provider account, can't be unlinked.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `user_id` - The ID of the user.
+/// * `provider_id` - The ID of the linked provider account.
+///
+/// # Returns
+///
+/// A `Result` containing the unlinked `AuthProvider`, a `NotFound` error if the user has no such
+/// provider account, or a `BadRequest` error if it is their last login method.
+pub async fn unlink_provider(pool: &PgPool, user_id: Uuid, provider_id: Uuid) -> Res<AuthProvider> {
+    let mut tx = pool.begin().await?;
+
+    // serialize concurrent unlinks, so that they can't remove the last two methods at once
+    db::user::lock_user(&mut *tx, user_id).await?;
+    let providers = db::user::get_auth_providers(&mut *tx, user_id).await?;
+    let provider = providers
+        .iter()
+        .find(|provider| provider.id == provider_id)
+        .cloned()
+        .ok_or_else(|| AppError::NotFound("Linked provider not found".to_string()))?;
+
+    let has_password = db::user::has_credentials(&mut *tx, user_id).await?;
+    if !has_password && providers.len() <= 1 {
+        return Err(AppError::BadRequest(
+            "Cannot unlink the last login method. Set a password or link another provider first."
+                .to_string(),
+        ));
+    }
+
+    db::user::delete_auth_provider(&mut *tx, user_id, provider_id).await?;
+    tx.commit().await?;
+    Ok(provider)
+}
+
 /// Inserts user record and OAuth data to the database.
-/// Used when signing in using OAuth provider.
+/// Used when signing in using OAuth provider. The user is verified if the provider verified the email.
 ///
 /// # Arguments
 ///
@@ -96,6 +259,7 @@ pub async fn create_user_with_oauth(
             last_name: user_data.last_name,
             company_name: None,
             verification_origin: UserVerificationOrigin::OAuth,
+            verified: user_data.email_verified,
             stripe_customer_id,
         },
     )
@@ -117,7 +281,7 @@ pub async fn create_user_with_oauth(
 }
 
 /// Inserts user record and credentials to the database.
-/// User when signing in using credentials.
+/// User when signing in using credentials. The user is unverified until they open the emailed link.
 ///
 /// # Arguments
 ///
@@ -155,6 +319,7 @@ pub async fn create_user_with_credentials(
             last_name: req.last_name,
             company_name: req.company_name,
             verification_origin: UserVerificationOrigin::Email,
+            verified: false,
             stripe_customer_id,
         },
     )
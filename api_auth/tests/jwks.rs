@@ -0,0 +1,103 @@
+//! Tests of ID token verification against a JSON Web Key Set.
+
+mod keys;
+
+use std::time::Duration;
+
+use api_auth::cache::jwks::JwksCache;
+use chrono::Utc;
+use common::error::AppError;
+use jsonwebtoken::{Algorithm, Validation};
+use serde::{Deserialize, Serialize};
+
+const JWKS_URL: &str = "https://issuer.test/keys";
+const ISSUER: &str = "https://issuer.test";
+const AUDIENCE: &str = "com.example.app";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    iss: String,
+    aud: String,
+    sub: String,
+    exp: i64,
+}
+
+fn cache() -> JwksCache {
+    let cache = JwksCache::new(Duration::from_secs(3600));
+    cache.insert_static(JWKS_URL, keys::jwks());
+    cache
+}
+
+fn validation() -> Validation {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[ISSUER]);
+    validation.set_audience(&[AUDIENCE]);
+    validation
+}
+
+fn sign(kid: &str, aud: &str, exp: i64) -> String {
+    let claims = Claims {
+        iss: ISSUER.to_string(),
+        aud: aud.to_string(),
+        sub: "user-1".to_string(),
+        exp,
+    };
+    keys::sign(kid, &claims)
+}
+
+fn in_one_hour() -> i64 {
+    Utc::now().timestamp() + 3600
+}
+
+#[actix_web::test]
+async fn accepts_valid_token() {
+    let token = sign(keys::KID, AUDIENCE, in_one_hour());
+
+    let claims: Claims = cache()
+        .verify(JWKS_URL, &token, &validation())
+        .await
+        .unwrap();
+    assert_eq!(claims.sub, "user-1");
+}
+
+#[actix_web::test]
+async fn rejects_other_audience() {
+    let token = sign(keys::KID, "com.example.other", in_one_hour());
+
+    let result = cache()
+        .verify::<Claims>(JWKS_URL, &token, &validation())
+        .await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
+
+#[actix_web::test]
+async fn rejects_unknown_key() {
+    let token = sign("rotated-key", AUDIENCE, in_one_hour());
+
+    let result = cache()
+        .verify::<Claims>(JWKS_URL, &token, &validation())
+        .await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
+
+#[actix_web::test]
+async fn rejects_expired_token() {
+    let token = sign(keys::KID, AUDIENCE, Utc::now().timestamp() - 3600);
+
+    let result = cache()
+        .verify::<Claims>(JWKS_URL, &token, &validation())
+        .await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
+
+#[actix_web::test]
+async fn rejects_tampered_token() {
+    let token = sign(keys::KID, AUDIENCE, in_one_hour());
+    let (signed, _) = token.rsplit_once('.').unwrap();
+    let forged = format!("{}.{}", signed, "c2lnbmF0dXJl");
+
+    let result = cache()
+        .verify::<Claims>(JWKS_URL, &forged, &validation())
+        .await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
@@ -0,0 +1,236 @@
+//! Tests of generic OpenID Connect providers against a local mock identity provider.
+
+mod keys;
+
+use std::{net::SocketAddr, time::Duration};
+
+use actix_web::{App, HttpResponse, HttpServer, web};
+use api_auth::cache::{
+    jwks::JwksCache,
+    oidc::{OidcRegistry, OidcUserInfo},
+};
+use chrono::Utc;
+use common::{
+    env_config::OidcProviderConfig,
+    error::{AppError, Res},
+};
+use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use serde_json::{Value, json};
+
+const CLIENT_ID: &str = "tokencheck";
+const NONCE: &str = "nonce-1";
+
+/// Starts a mock identity provider serving discovery, keys and userinfo.
+///
+/// `/tenant` serves a discovery document of another issuer.
+async fn start_idp() -> String {
+    let server = HttpServer::new(|| {
+        App::new()
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(|req: actix_web::HttpRequest| async move {
+                    let issuer = format!("http://{}", req.connection_info().host());
+                    HttpResponse::Ok().json(json!({
+                        "issuer": issuer,
+                        "authorization_endpoint": format!("{}/authorize", issuer),
+                        "token_endpoint": format!("{}/token", issuer),
+                        "jwks_uri": format!("{}/keys", issuer),
+                        "userinfo_endpoint": format!("{}/userinfo", issuer),
+                        "id_token_signing_alg_values_supported": ["RS256"],
+                    }))
+                }),
+            )
+            .route(
+                "/tenant/.well-known/openid-configuration",
+                web::get().to(|req: actix_web::HttpRequest| async move {
+                    let issuer = format!("http://{}", req.connection_info().host());
+                    HttpResponse::Ok().json(json!({
+                        "issuer": issuer,
+                        "authorization_endpoint": format!("{}/authorize", issuer),
+                        "token_endpoint": format!("{}/token", issuer),
+                        "jwks_uri": format!("{}/keys", issuer),
+                    }))
+                }),
+            )
+            .route(
+                "/keys",
+                web::get().to(|| async { HttpResponse::Ok().json(keys::jwks()) }),
+            )
+            .route(
+                "/userinfo",
+                web::get().to(|req: actix_web::HttpRequest| async move {
+                    match req.headers().get("Authorization") {
+                        Some(auth) if auth == "Bearer access-token" => {
+                            HttpResponse::Ok().json(json!({
+                                "sub": "user-1",
+                                "email": "jane@example.com",
+                                "email_verified": true,
+                                "name": "Jane Doe",
+                            }))
+                        }
+                        _ => HttpResponse::Unauthorized().finish(),
+                    }
+                }),
+            )
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let addr: SocketAddr = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    format!("http://{}", addr)
+}
+
+fn registry(issuer: &str) -> OidcRegistry {
+    OidcRegistry::new(
+        vec![OidcProviderConfig {
+            name: "mock".to_string(),
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: "http://localhost:8080/api/auth/oauth/mock/callback".to_string(),
+        }],
+        Duration::from_secs(3600),
+    )
+}
+
+fn id_token(issuer: &str, extra: Value) -> String {
+    let mut claims = json!({
+        "iss": issuer,
+        "aud": CLIENT_ID,
+        "sub": "user-1",
+        "exp": Utc::now().timestamp() + 3600,
+        "nonce": NONCE,
+    });
+    claims
+        .as_object_mut()
+        .unwrap()
+        .extend(extra.as_object().unwrap().clone());
+    keys::sign(keys::KID, &claims)
+}
+
+async fn user_info(issuer: &str, id_token: &str, nonce: &str) -> Res<OidcUserInfo> {
+    let jwks = JwksCache::new(Duration::from_secs(3600));
+    registry(issuer)
+        .fetch_user_info(&jwks, "mock", id_token, "access-token", Some(nonce))
+        .await
+}
+
+#[actix_web::test]
+async fn discovers_endpoints() {
+    let issuer = start_idp().await;
+
+    let metadata = registry(&issuer).metadata("mock").await.unwrap();
+    assert_eq!(metadata.issuer, issuer);
+    assert_eq!(metadata.token_endpoint, format!("{}/token", issuer));
+    assert_eq!(metadata.jwks_uri, format!("{}/keys", issuer));
+}
+
+#[actix_web::test]
+async fn rejects_discovery_of_other_issuer() {
+    let issuer = start_idp().await;
+
+    let result = registry(&format!("{}/tenant", issuer))
+        .metadata("mock")
+        .await;
+    assert!(matches!(result, Err(AppError::Internal(_))));
+}
+
+#[actix_web::test]
+async fn rejects_unknown_provider() {
+    let issuer = start_idp().await;
+
+    let result = registry(&issuer).metadata("other").await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+}
+
+#[actix_web::test]
+async fn accepts_valid_id_token() {
+    let issuer = start_idp().await;
+    let token = id_token(
+        &issuer,
+        json!({
+            "email": "john@example.com",
+            "email_verified": "true",
+            "given_name": "John",
+            "family_name": "Smith",
+        }),
+    );
+
+    let user = user_info(&issuer, &token, NONCE).await.unwrap();
+    assert_eq!(user.subject, "user-1");
+    assert_eq!(user.email, "john@example.com");
+    assert!(user.email_verified);
+    assert_eq!(user.first_name, "John");
+    assert_eq!(user.last_name, "Smith");
+}
+
+#[actix_web::test]
+async fn fills_missing_claims_from_userinfo() {
+    let issuer = start_idp().await;
+    let token = id_token(&issuer, json!({}));
+
+    let user = user_info(&issuer, &token, NONCE).await.unwrap();
+    assert_eq!(user.email, "jane@example.com");
+    assert!(user.email_verified);
+    assert_eq!(user.first_name, "Jane");
+    assert_eq!(user.last_name, "Doe");
+}
+
+#[actix_web::test]
+async fn rejects_userinfo_of_other_subject() {
+    let issuer = start_idp().await;
+    let token = id_token(&issuer, json!({ "sub": "user-2" }));
+
+    let result = user_info(&issuer, &token, NONCE).await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
+
+#[actix_web::test]
+async fn rejects_nonce_mismatch() {
+    let issuer = start_idp().await;
+    let token = id_token(&issuer, json!({ "email": "john@example.com" }));
+
+    let result = user_info(&issuer, &token, "nonce-2").await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
+
+#[actix_web::test]
+async fn rejects_token_of_other_issuer() {
+    let issuer = start_idp().await;
+    let token = id_token("https://other.test", json!({ "email": "john@example.com" }));
+
+    let result = user_info(&issuer, &token, NONCE).await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
+
+#[actix_web::test]
+async fn rejects_token_of_other_client() {
+    let issuer = start_idp().await;
+    let token = id_token(
+        &issuer,
+        json!({ "aud": "other-client", "email": "john@example.com" }),
+    );
+
+    let result = user_info(&issuer, &token, NONCE).await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
+
+#[actix_web::test]
+async fn rejects_token_signed_with_client_secret() {
+    let issuer = start_idp().await;
+    let mut header = Header::new(Algorithm::HS256);
+    header.kid = Some(keys::KID.to_string());
+    let claims = json!({
+        "iss": issuer,
+        "aud": CLIENT_ID,
+        "sub": "user-1",
+        "exp": Utc::now().timestamp() + 3600,
+        "nonce": NONCE,
+        "email": "john@example.com",
+    });
+    let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+
+    let result = user_info(&issuer, &token, NONCE).await;
+    assert!(matches!(result, Err(AppError::Unauthorized(_))));
+}
@@ -0,0 +1,206 @@
+//! Tests of the email verification flow. They need a PostgreSQL server at
+//! `TEST_DATABASE_URL` (default `postgres://postgres@127.0.0.1/tokencheck_test`),
+//! whose database is created and migrated on first use, and are skipped if none is reachable.
+
+use std::{
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use api_auth::mail::{mailer::MemoryMailer, verification::EmailVerifier};
+use common::{error::AppError, misc::UserVerificationOrigin};
+use db::{dtos::user::UserCreateRequest, models::user::User};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn database_url() -> String {
+    std::env::var("TEST_DATABASE_URL")
+        .unwrap_or_else(|_| "postgres://postgres@127.0.0.1/tokencheck_test".to_string())
+}
+
+/// Creates and migrates the test database, once for all tests.
+fn migrated() -> bool {
+    static MIGRATED: OnceLock<bool> = OnceLock::new();
+    *MIGRATED.get_or_init(|| {
+        // on a runtime of its own, as each test has one that ends with the test
+        std::thread::spawn(|| {
+            actix_web::rt::System::new().block_on(async {
+                match db::setup(&database_url(), false).await {
+                    Ok(pool) => {
+                        pool.close().await;
+                        true
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Skipping verification test, PostgreSQL is not reachable: {}",
+                            e
+                        );
+                        false
+                    }
+                }
+            })
+        })
+        .join()
+        .unwrap()
+    })
+}
+
+async fn connect() -> Option<PgPool> {
+    if !migrated() {
+        return None;
+    }
+    PgPool::connect(&database_url()).await.ok()
+}
+
+async fn create_user(pool: &PgPool) -> User {
+    db::user::insert_user(
+        pool,
+        UserCreateRequest {
+            email: format!("{}@example.com", Uuid::new_v4()),
+            first_name: "Jane".to_string(),
+            last_name: "Doe".to_string(),
+            company_name: None,
+            verification_origin: UserVerificationOrigin::Email,
+            verified: false,
+            stripe_customer_id: "cus_test".to_string(),
+        },
+    )
+    .await
+    .unwrap()
+}
+
+fn verifier(mailer: &MemoryMailer, ttl: Duration, resend_interval: Duration) -> EmailVerifier {
+    EmailVerifier::new(
+        Arc::new(mailer.clone()),
+        "TokenCheck <no-reply@localhost>",
+        "http://localhost:3000/auth/verify-email",
+        ttl,
+        resend_interval,
+    )
+}
+
+/// The token of the link in the last sent email.
+fn sent_token(mailer: &MemoryMailer) -> String {
+    let email = mailer.sent().pop().unwrap();
+    let (_, rest) = email.body.split_once("?token=").unwrap();
+    rest.split_whitespace().next().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn sends_link_to_user() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let mailer = MemoryMailer::new();
+    let user = create_user(&pool).await;
+
+    verifier(&mailer, HOUR, HOUR)
+        .send(&pool, &user)
+        .await
+        .unwrap();
+
+    let sent = mailer.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].to, user.email);
+    assert!(
+        sent[0]
+            .body
+            .contains("http://localhost:3000/auth/verify-email?token=")
+    );
+    assert_eq!(sent_token(&mailer).len(), 64);
+}
+
+#[actix_web::test]
+async fn verifies_email_once() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let mailer = MemoryMailer::new();
+    let verifier = verifier(&mailer, HOUR, HOUR);
+    let user = create_user(&pool).await;
+    verifier.send(&pool, &user).await.unwrap();
+    let token = sent_token(&mailer);
+
+    let verified = verifier.verify(&pool, &token).await.unwrap();
+    assert_eq!(verified.id, user.id);
+    assert!(verified.verified);
+    assert!(db::user::is_user_verified(&pool, user.id).await.unwrap());
+
+    let result = verifier.verify(&pool, &token).await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+}
+
+#[actix_web::test]
+async fn rejects_unknown_token() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let mailer = MemoryMailer::new();
+
+    let result = verifier(&mailer, HOUR, HOUR).verify(&pool, "unknown").await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+}
+
+#[actix_web::test]
+async fn rejects_expired_token() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let mailer = MemoryMailer::new();
+    let verifier = verifier(&mailer, Duration::ZERO, HOUR);
+    let user = create_user(&pool).await;
+    verifier.send(&pool, &user).await.unwrap();
+
+    let result = verifier.verify(&pool, &sent_token(&mailer)).await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+    assert!(!db::user::is_user_verified(&pool, user.id).await.unwrap());
+}
+
+#[actix_web::test]
+async fn throttles_resends() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let mailer = MemoryMailer::new();
+    let verifier = verifier(&mailer, HOUR, HOUR);
+    let user = create_user(&pool).await;
+    verifier.send(&pool, &user).await.unwrap();
+
+    let result = verifier.send(&pool, &user).await;
+    assert!(matches!(result, Err(AppError::TooManyRequests(_))));
+    assert_eq!(mailer.sent().len(), 1);
+}
+
+#[actix_web::test]
+async fn resend_replaces_previous_link() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let mailer = MemoryMailer::new();
+    let verifier = verifier(&mailer, HOUR, Duration::ZERO);
+    let user = create_user(&pool).await;
+    verifier.send(&pool, &user).await.unwrap();
+    let first = sent_token(&mailer);
+    verifier.send(&pool, &user).await.unwrap();
+    let second = sent_token(&mailer);
+
+    let result = verifier.verify(&pool, &first).await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+    assert!(verifier.verify(&pool, &second).await.is_ok());
+}
+
+#[actix_web::test]
+async fn rejects_verified_user() {
+    let Some(pool) = connect().await else {
+        return;
+    };
+    let mailer = MemoryMailer::new();
+    let user = create_user(&pool).await;
+    let user = db::user::set_user_verified(&pool, user.id).await.unwrap();
+
+    let result = verifier(&mailer, HOUR, HOUR).send(&pool, &user).await;
+    assert!(matches!(result, Err(AppError::BadRequest(_))));
+    assert!(mailer.sent().is_empty());
+}
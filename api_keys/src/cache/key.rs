@@ -0,0 +1,474 @@
+use std::{
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::NaiveDateTime;
+use common::{
+    connection::RedisConnection,
+    key::{KeyMode, Scope},
+};
+use dashmap::DashMap;
+use db::models::key::{KeyOwner, VerifiedKey};
+use futures::StreamExt;
+use redis::{AsyncCommands, Script};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::types::ipnetwork::IpNetwork;
+use uuid::Uuid;
+
+/// Redis channel used to tell other instances to drop their local entries of a key.
+const INVALIDATION_CHANNEL: &str = "keycache:invalidate";
+
+/// Redis counter of invalidations, see `Generation`.
+const GENERATION_KEY: &str = "keycache:generation";
+
+/// How long an invalidation blocks inserts of verifications that started before it.
+/// Must be longer than any verification takes.
+const TOMBSTONE_TTL: Duration = Duration::from_secs(60);
+
+/// Stores an entry unless its key was invalidated after the verification started.
+///
+/// KEYS: entry, index of the key's digests, tombstone of the key.
+/// ARGV: entry, digest, TTL (seconds), generation the verification started at.
+///
+/// Returns 1 if the entry was stored, 0 otherwise.
+static INSERT_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+        local invalidated = tonumber(redis.call('GET', KEYS[3]) or '0')
+        if invalidated > tonumber(ARGV[4]) then
+            return 0
+        end
+        redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[3])
+        redis.call('SADD', KEYS[2], ARGV[2])
+        redis.call('EXPIRE', KEYS[2], ARGV[3])
+        return 1
+        "#,
+    )
+});
+
+/// Bumps the invalidation counter and records it as the tombstone of a key.
+///
+/// KEYS: invalidation counter, tombstone of the key.
+/// ARGV: tombstone TTL (seconds).
+static TOMBSTONE_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+        local generation = redis.call('INCR', KEYS[1])
+        redis.call('SET', KEYS[2], generation, 'EX', ARGV[1])
+        return generation
+        "#,
+    )
+});
+
+struct CachedKey {
+    verified_key: VerifiedKey,
+    inserted_at: Instant,
+}
+
+/// Entry of the Redis tier. Holds only what is needed to authorize requests,
+/// the owner's personal details are never stored.
+#[derive(Serialize, Deserialize)]
+struct SharedKey {
+    id: Uuid,
+    owner_id: Uuid,
+    stripe_customer_id: String,
+    plan_id: String,
+    scopes: Vec<Scope>,
+    mode: KeyMode,
+    status: String,
+    expires_at: Option<NaiveDateTime>,
+    allowed_ips: Option<Vec<IpNetwork>>,
+}
+
+/// Invalidations seen when a verification started, taken with `KeyCache::generation`.
+///
+/// A verification that started before its key was invalidated may have read the old
+/// key record, so its result is not cached. `redis` is `None` if the Redis tier is disabled
+/// or Redis was unavailable, the result is then only cached in process.
+#[derive(Debug, Clone, Copy)]
+pub struct Generation {
+    local: u64,
+    redis: Option<u64>,
+}
+
+/// Generation at which a key was last invalidated in process.
+struct Tombstone {
+    generation: u64,
+    created_at: Instant,
+}
+
+impl From<&VerifiedKey> for SharedKey {
+    fn from(verified_key: &VerifiedKey) -> Self {
+        SharedKey {
+            id: verified_key.id,
+            owner_id: verified_key.owner.id,
+            stripe_customer_id: verified_key.owner.stripe_customer_id.clone(),
+            plan_id: verified_key.plan_id.clone(),
+            scopes: verified_key.scopes.clone(),
+            mode: verified_key.mode,
+            status: verified_key.status.clone(),
+            expires_at: verified_key.expires_at,
+            allowed_ips: verified_key.allowed_ips.clone(),
+        }
+    }
+}
+
+/// Snapshot of the cache counters.
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyCacheStats {
+    pub local_hits: u64,
+    pub redis_hits: u64,
+    pub misses: u64,
+    pub local_entries: usize,
+}
+
+/// Cache of verified API keys, so `/v1` requests skip the database lookup and
+/// the argon2 verification for keys that were recently verified.
+///
+/// Entries are keyed by the SHA-256 digest of the presented key, the key itself
+/// is never stored. Neither is the hash of its secret. There are two tiers:
+/// - an in-process map shared by all workers, bounded to `capacity` entries,
+/// - an optional Redis tier shared by all instances, enabled with `redis_tier`.
+///   It only holds the fields of `SharedKey`, so a hit needs no database lookup either.
+///
+/// Both tiers expire entries after `ttl`. `invalidate` drops all entries of a key
+/// immediately and notifies other instances through Redis pub/sub, whether or not
+/// the Redis tier is enabled, through the shared `RedisConnection`. It also leaves
+/// a tombstone in both tiers, so verifications that were in flight can't cache the
+/// old key record afterwards.
+pub struct KeyCache {
+    local: DashMap<String, CachedKey>,
+    tombstones: DashMap<Uuid, Tombstone>,
+    generation: AtomicU64,
+    capacity: usize,
+    ttl: Duration,
+    redis: Arc<RedisConnection>,
+    redis_tier: bool,
+    local_hits: AtomicU64,
+    redis_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl KeyCache {
+    pub fn new(
+        capacity: usize,
+        ttl: Duration,
+        redis: Arc<RedisConnection>,
+        redis_tier: bool,
+    ) -> Self {
+        KeyCache {
+            local: DashMap::new(),
+            tombstones: DashMap::new(),
+            generation: AtomicU64::new(0),
+            capacity,
+            ttl,
+            redis,
+            redis_tier,
+            local_hits: AtomicU64::new(0),
+            redis_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the hex encoded SHA-256 digest of a presented key.
+    pub fn digest(key: &str) -> String {
+        format!("{:x}", Sha256::digest(key.as_bytes()))
+    }
+
+    /// Looks up a verified key by the digest of the presented key.
+    pub async fn get(&self, digest: &str) -> Option<VerifiedKey> {
+        if let Some(verified_key) = self.get_local(digest) {
+            self.local_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(verified_key);
+        }
+
+        let generation = self.generation.load(Ordering::SeqCst);
+        if let Some(verified_key) = self.get_redis(digest).await {
+            self.redis_hits.fetch_add(1, Ordering::Relaxed);
+            self.insert_local(digest, verified_key.clone(), generation);
+            return Some(verified_key);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// Returns the current generation. Must be taken before the key record is read
+    /// from the database and passed to `insert` with the verified key.
+    pub async fn generation(&self) -> Generation {
+        let local = self.generation.load(Ordering::SeqCst);
+        if !self.redis_tier {
+            return Generation { local, redis: None };
+        }
+        let result: redis::RedisResult<Option<u64>> = async {
+            let mut conn = self.redis.get().await?;
+            conn.get(GENERATION_KEY).await
+        }
+        .await;
+
+        match result {
+            Ok(generation) => Generation {
+                local,
+                redis: Some(generation.unwrap_or(0)),
+            },
+            Err(e) => {
+                log::warn!("Failed to read key cache generation from Redis: {}", e);
+                Generation { local, redis: None }
+            }
+        }
+    }
+
+    /// Stores a verified key under the digest of the presented key, unless the key
+    /// was invalidated since `generation` was taken.
+    pub async fn insert(&self, digest: &str, verified_key: &VerifiedKey, generation: Generation) {
+        self.insert_local(digest, verified_key.clone(), generation.local);
+        if let Some(redis_generation) = generation.redis {
+            self.insert_redis(digest, verified_key, redis_generation)
+                .await;
+        }
+    }
+
+    /// Drops all cached entries of a key, on this and on all other instances.
+    /// Must be called whenever the key record changes (e.g. it is revoked).
+    pub async fn invalidate(&self, key_id: Uuid) {
+        self.invalidate_local(key_id);
+
+        let result: redis::RedisResult<()> = async {
+            let mut conn = self.redis.get().await?;
+            if self.redis_tier {
+                // the tombstone goes first, so no entry can be stored once the old ones are deleted
+                let _: u64 = TOMBSTONE_SCRIPT
+                    .key(GENERATION_KEY)
+                    .key(redis_tombstone_key(key_id))
+                    .arg(TOMBSTONE_TTL.as_secs())
+                    .invoke_async(&mut conn)
+                    .await?;
+                let index_key = redis_index_key(key_id);
+                let digests: Vec<String> = conn.smembers(&index_key).await?;
+                let mut keys: Vec<String> = digests.iter().map(|d| redis_entry_key(d)).collect();
+                keys.push(index_key);
+                let _: () = conn.del(keys).await?;
+            }
+            let _: () = conn
+                .publish(INVALIDATION_CHANNEL, key_id.to_string())
+                .await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            log::error!("Failed to invalidate cached key {} in Redis: {}", key_id, e);
+        }
+    }
+
+    /// Listens for invalidations published by other instances and drops the
+    /// matching local entries.
+    pub async fn listen_for_invalidations(&self) {
+        loop {
+            // pub/sub needs a dedicated connection
+            match self.redis.client().get_async_pubsub().await {
+                Ok(mut pubsub) => {
+                    if let Err(e) = pubsub.subscribe(INVALIDATION_CHANNEL).await {
+                        log::error!("Failed to subscribe to key cache invalidations: {}", e);
+                    } else {
+                        let mut messages = pubsub.on_message();
+                        while let Some(msg) = messages.next().await {
+                            match msg.get_payload::<String>().map(|p| p.parse::<Uuid>()) {
+                                Ok(Ok(key_id)) => self.invalidate_local(key_id),
+                                _ => log::warn!("Ignoring malformed key cache invalidation"),
+                            }
+                        }
+                    }
+                }
+                Err(e) => log::error!("Failed to connect to key cache invalidations: {}", e),
+            }
+
+            // entries may be stale while disconnected, the TTL bounds how long
+            log::warn!("Key cache invalidation listener disconnected, reconnecting");
+            self.local.clear();
+            actix_web::rt::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    pub fn stats(&self) -> KeyCacheStats {
+        KeyCacheStats {
+            local_hits: self.local_hits.load(Ordering::Relaxed),
+            redis_hits: self.redis_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            local_entries: self.local.len(),
+        }
+    }
+
+    /// Logs the cache counters every `period`.
+    pub async fn log_stats(&self, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            let stats = self.stats();
+            log::info!(
+                "Key cache: {} local hits, {} redis hits, {} misses, {} local entries",
+                stats.local_hits,
+                stats.redis_hits,
+                stats.misses,
+                stats.local_entries
+            );
+        }
+    }
+
+    fn get_local(&self, digest: &str) -> Option<VerifiedKey> {
+        let entry = self.local.get(digest)?;
+        if entry.inserted_at.elapsed() < self.ttl {
+            return Some(entry.verified_key.clone());
+        }
+        // release the read lock before removing
+        drop(entry);
+        self.local.remove(digest);
+        None
+    }
+
+    fn insert_local(&self, digest: &str, verified_key: VerifiedKey, generation: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key_id = verified_key.id;
+
+        if self.local.len() >= self.capacity && !self.local.contains_key(digest) {
+            // drop expired entries first, then an arbitrary one if still full
+            self.local
+                .retain(|_, cached| cached.inserted_at.elapsed() < self.ttl);
+            if self.local.len() >= self.capacity {
+                let victim = self.local.iter().next().map(|entry| entry.key().clone());
+                if let Some(victim) = victim {
+                    self.local.remove(&victim);
+                }
+            }
+        }
+
+        self.local.insert(
+            digest.to_string(),
+            CachedKey {
+                verified_key,
+                inserted_at: Instant::now(),
+            },
+        );
+
+        // checked after inserting, an invalidation racing with the insert either
+        // left its tombstone already or drops the entry itself
+        if self
+            .tombstones
+            .get(&key_id)
+            .is_some_and(|tombstone| tombstone.generation > generation)
+        {
+            self.local.remove(digest);
+        }
+    }
+
+    fn invalidate_local(&self, key_id: Uuid) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        self.tombstones
+            .retain(|_, tombstone| tombstone.created_at.elapsed() < TOMBSTONE_TTL);
+        self.tombstones.insert(
+            key_id,
+            Tombstone {
+                generation,
+                created_at: Instant::now(),
+            },
+        );
+        self.local
+            .retain(|_, cached| cached.verified_key.id != key_id);
+    }
+
+    async fn get_redis(&self, digest: &str) -> Option<VerifiedKey> {
+        if !self.redis_tier {
+            return None;
+        }
+        let result: redis::RedisResult<Option<String>> = async {
+            let mut conn = self.redis.get().await?;
+            conn.get(redis_entry_key(digest)).await
+        }
+        .await;
+
+        let shared: SharedKey = match result {
+            Ok(Some(json)) => serde_json::from_str(&json)
+                .inspect_err(|e| log::warn!("Dropping malformed cached key: {}", e))
+                .ok()?,
+            Ok(None) => return None,
+            Err(e) => {
+                log::warn!("Failed to read cached key from Redis: {}", e);
+                return None;
+            }
+        };
+        if shared.status != "active" {
+            return None;
+        }
+
+        Some(VerifiedKey {
+            id: shared.id,
+            owner: KeyOwner {
+                id: shared.owner_id,
+                stripe_customer_id: shared.stripe_customer_id,
+            },
+            plan_id: shared.plan_id,
+            scopes: shared.scopes,
+            mode: shared.mode,
+            status: shared.status,
+            expires_at: shared.expires_at,
+            allowed_ips: shared.allowed_ips,
+        })
+    }
+
+    async fn insert_redis(&self, digest: &str, verified_key: &VerifiedKey, generation: u64) {
+        let json = match serde_json::to_string(&SharedKey::from(verified_key)) {
+            Ok(json) => json,
+            Err(e) => {
+                log::error!("Failed to serialize verified key: {}", e);
+                return;
+            }
+        };
+
+        let ttl = self.ttl.as_secs().max(1);
+        let index_key = redis_index_key(verified_key.id);
+        let result: redis::RedisResult<u8> = async {
+            let mut conn = self.redis.get().await?;
+            INSERT_SCRIPT
+                .key(redis_entry_key(digest))
+                .key(&index_key)
+                .key(redis_tombstone_key(verified_key.id))
+                .arg(json)
+                .arg(digest)
+                .arg(ttl)
+                .arg(generation)
+                .invoke_async(&mut conn)
+                .await
+        }
+        .await;
+
+        match result {
+            Ok(0) => log::debug!(
+                "Not caching key {} in Redis, it was invalidated during verification",
+                verified_key.id
+            ),
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to cache key in Redis: {}", e),
+        }
+    }
+}
+
+fn redis_entry_key(digest: &str) -> String {
+    format!("keycache:entry:{}", digest)
+}
+
+/// Set of digests cached for a key, used to find its entries on invalidation.
+fn redis_index_key(key_id: Uuid) -> String {
+    format!("keycache:key:{}", key_id)
+}
+
+/// Generation at which a key was last invalidated.
+fn redis_tombstone_key(key_id: Uuid) -> String {
+    format!("keycache:tombstone:{}", key_id)
+}
@@ -1,11 +1,26 @@
 use serde::{Deserialize, Serialize};
-use sqlx::types::{chrono::NaiveDateTime, JsonValue};
+use common::key::{KeyMode, Scope};
+use sqlx::types::{chrono::NaiveDateTime, ipnetwork::IpNetwork};
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateKeyRequest {
     pub name: String,
-    pub permissions: JsonValue,
+    /// Scopes granted to the key. Defaults to `DEFAULT_SCOPES` if omitted.
+    #[serde(default)]
+    pub permissions: Option<Vec<Scope>>,
+    #[serde(default)]
+    pub expires_at: Option<NaiveDateTime>,
+    /// `live` (default) or `test`.
+    #[serde(default)]
+    pub mode: KeyMode,
+}
+
+/// Fields left out are not changed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateKeyRequest {
+    pub name: Option<String>,
+    pub permissions: Option<Vec<Scope>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,15 +28,34 @@ pub struct RevokeKeyRequest {
     pub key_id: Uuid,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateAllowedIpsRequest {
+    /// IPs or CIDR ranges. `None` or an empty list allows any address.
+    pub allowed_ips: Option<Vec<IpNetwork>>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiKeyListItem {
     pub id: Uuid,
     pub user_id: Uuid,
     pub key_hashed: String,
+    pub public_id: Option<String>,
     pub name: String,
     pub status: String,
+    pub mode: String,
     pub created_at: NaiveDateTime,
-    pub permissions: JsonValue,
+    pub permissions: Vec<Scope>,
+    pub expires_at: Option<NaiveDateTime>,
+    /// Key this key replaced on rotation.
+    pub rotated_from: Option<Uuid>,
+    /// Key that replaced this key on rotation.
+    pub rotated_to: Option<Uuid>,
+    pub allowed_ips: Option<Vec<IpNetwork>>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub last_used_ip: Option<IpNetwork>,
+    /// Number of requests made with the key in the last `REQUEST_COUNT_WINDOW_DAYS` days (today included),
+    /// lags behind by up to `KEY_USAGE_FLUSH_SECS`.
+    pub request_count: i64,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateKeyResponse {
@@ -30,6 +64,11 @@ pub struct CreateKeyResponse {
     pub user_id: Uuid,
     pub name: String,
     pub status: String,
+    pub mode: String,
     pub created_at: NaiveDateTime,
-    pub permissions: JsonValue,
+    pub permissions: Vec<Scope>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub rotated_from: Option<Uuid>,
+    /// CIDR ranges the key may be used from, carried over from the old key on rotation.
+    pub allowed_ips: Option<Vec<IpNetwork>>,
 }
\ No newline at end of file
@@ -1,11 +1,14 @@
+use common::key::KeyMode;
 use serde::{Deserialize, Serialize};
 use sqlx::types::chrono::NaiveDateTime;
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KeyUsageRequest {
-    pub user_id: Option<Uuid>,
     pub key_id: Option<Uuid>,
+    /// Mode of the keys to report, defaults to live so test traffic stays out of reports.
+    #[serde(default)]
+    pub mode: KeyMode,
     pub limit: i32,
     pub ending_before: Option<String>,
     pub starting_after: Option<String>,
@@ -16,4 +19,7 @@ pub struct UsageResponse {
     pub name: String,
     pub date: NaiveDateTime,
     pub path: String,
+    pub mode: Option<String>,
+    /// Quota units consumed by the request.
+    pub units: Option<i64>,
 }
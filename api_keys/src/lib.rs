@@ -1,5 +1,6 @@
 use actix_web::web;
-use middleware::key::KeyMiddleware;
+use common::key::Scope;
+use middleware::{key::KeyMiddleware, scope::ScopeMiddleware};
 
 pub mod routes {
     pub mod key;
@@ -7,6 +8,13 @@ pub mod routes {
 }
 pub mod middleware {
     pub mod key;
+    pub mod scope;
+}
+pub mod cache {
+    pub mod key;
+}
+pub mod tracker {
+    pub mod key;
 }
 
 mod service {
@@ -23,8 +31,16 @@ pub fn mount_keys() -> actix_web::Scope {
         .service(routes::key::get_keys)
         .service(routes::key::post_generate_key)
         .service(routes::key::post_revoke)
+        .service(routes::key::post_rotate)
+        .service(routes::key::put_allowed_ips)
+        .service(routes::key::patch_key)
         .service(routes::usage::get_usage)
 }
 pub fn middleware() -> KeyMiddleware {
     KeyMiddleware::new()
 }
+/// Rejects requests whose API key lacks `scope`.
+/// Must run after the key middleware, e.g. on a route under `/v1`.
+pub fn require_scope(scope: Scope) -> ScopeMiddleware {
+    ScopeMiddleware::new(scope)
+}
@@ -1,18 +1,26 @@
 use actix_web::{
-    Error,
+    Error, HttpMessage,
     dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    http::StatusCode,
     web,
 };
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use chrono::Utc;
 use common::{
-    error::AppError,
-    key::{self},
+    env_config::Config,
+    error::{AppError, Res},
+    ip,
+    key::{self, KeyClaims, KeyMode, Scope},
 };
+use db::models::key::{KeyOwner, VerifiedKey};
 use futures::future::{Ready, ok};
 use sqlx::PgPool;
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{future::Future, net::IpAddr, pin::Pin, sync::Arc};
+
+use crate::{cache::key::KeyCache, tracker::key::KeyUsageTracker};
 
 // KeyMiddleware struct (as a Transform)
+#[derive(Default)]
 pub struct KeyMiddleware {}
 
 impl KeyMiddleware {
@@ -62,36 +70,233 @@ where
 
         Box::pin(async move {
             let pool = &***req.app_data::<web::Data<Arc<PgPool>>>().unwrap().clone();
+            let config = &***req.app_data::<web::Data<Arc<Config>>>().unwrap().clone();
+            let cache = &***req.app_data::<web::Data<Arc<KeyCache>>>().unwrap().clone();
+            let tracker = &***req
+                .app_data::<web::Data<Arc<KeyUsageTracker>>>()
+                .unwrap()
+                .clone();
             // Extract key claims from the request
-            match key::get_key_claims_or_error(&req) {
-                Err(response) => {
-                    return Ok(req.into_response(response));
-                }
-                Ok(key_claims) => {
-                    // fetch record from database
-                    match db::key::get_key_by_id(pool, &key_claims.key_id).await {
-                        Ok(key_record) => {
-                            // check if secret matches hashed value from database
-                            let parsed_hash = PasswordHash::new(&key_record.key_encrypted).unwrap();
-                            if Argon2::default()
-                                .verify_password(key_claims.secret.as_bytes(), &parsed_hash)
-                                .is_err()
-                            {
-                                return Ok(req.error_response(AppError::BadRequest(
-                                    "Invalid key".to_string(),
-                                )));
-                            }
-
-                            // ... optional permissions check here ...
-
-                            srv.call(req).await.map(|res| res.map_into_boxed_body())
-                        }
-                        Err(_) => {
-                            Ok(req.error_response(AppError::BadRequest("Invalid key".to_string())))
-                        }
+            let key_claims = match key::get_key_claims_or_error(&req) {
+                Ok(key_claims) => key_claims,
+                Err(response) => return Ok(req.into_response(response)),
+            };
+            let client_ip = ip::client_ip(req.request(), &config.trusted_proxies);
+            let digest = req
+                .headers()
+                .get("X-API-KEY")
+                .and_then(|v| v.to_str().ok())
+                .map(KeyCache::digest)
+                .unwrap_or_default();
+
+            // Verify claims against the cache, falling back to the database record
+            match verify_key_cached(pool, config, cache, &digest, &key_claims, client_ip).await {
+                Ok(verified_key) => {
+                    let key_id = verified_key.id;
+
+                    // Insert verified key for handlers and middlewares down the chain
+                    req.extensions_mut().insert(verified_key);
+                    let res = srv.call(req).await?;
+
+                    // Record usage, written to the database in batches
+                    if !is_rejected(res.status()) {
+                        tracker.record(key_id, client_ip);
                     }
+                    Ok(res.map_into_boxed_body())
                 }
+                Err(err) => Ok(req.error_response(err)),
             }
         })
     }
 }
+
+/// Verifies key claims using the verified key cache, falling back to `verify_key`
+/// on a miss. Only successful verifications are cached. Expiration and the IP
+/// allowlist are checked on every request, since they do not depend on the key alone.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `config` - The application configuration.
+/// * `cache` - The verified key cache.
+/// * `digest` - The digest of the presented key.
+/// * `key_claims` - The claims parsed from the presented API key.
+/// * `client_ip` - The resolved IP address of the client.
+///
+/// # Returns
+///
+/// A `Result` containing the `VerifiedKey` or an `AppError` (see `verify_key`):
+/// * `Unauthorized` - if the key has expired.
+/// * `Forbidden` - if the key is restricted to IP ranges the client is not in.
+async fn verify_key_cached(
+    pool: &PgPool,
+    config: &Config,
+    cache: &KeyCache,
+    digest: &str,
+    key_claims: &KeyClaims,
+    client_ip: Option<IpAddr>,
+) -> Res<VerifiedKey> {
+    // the sunset only depends on the claims, so check it even for cached keys
+    check_legacy_sunset(config, key_claims)?;
+
+    let verified_key = match cache.get(digest).await {
+        Some(verified_key) => verified_key,
+        None => {
+            let generation = cache.generation().await;
+            let verified_key = verify_key(pool, config, key_claims).await?;
+            cache.insert(digest, &verified_key, generation).await;
+            verified_key
+        }
+    };
+
+    // check if key has expired
+    if let Some(expires_at) = verified_key.expires_at
+        && expires_at <= Utc::now().naive_utc()
+    {
+        return Err(AppError::Unauthorized(format!(
+            "Key expired at {} UTC",
+            expires_at.format("%Y-%m-%d %H:%M:%S")
+        )));
+    }
+
+    // check if client is within the allowed IP ranges
+    if let Some(allowed_ips) = &verified_key.allowed_ips {
+        match client_ip {
+            Some(ip) if ip::is_in_networks(ip, allowed_ips) => {}
+            Some(ip) => {
+                return Err(AppError::Forbidden(format!(
+                    "IP address {} is not allowed to use this key",
+                    ip
+                )));
+            }
+            None => {
+                return Err(AppError::Forbidden(
+                    "Could not determine IP address for a key restricted to IP ranges"
+                        .to_string(),
+                ));
+            }
+        }
+    }
+
+    Ok(verified_key)
+}
+
+/// Verifies key claims against the stored key record.
+///
+/// Opaque keys are looked up by their public identifier. Legacy keys are looked up
+/// by the key ID they carry and are only accepted until the configured sunset date.
+/// Then checks, in order, that the secret matches the stored hash, that the key prefix
+/// matches the stored mode, that a legacy key belongs to the user named in its claims
+/// and that the key is active.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `config` - The application configuration.
+/// * `key_claims` - The claims parsed from the presented API key.
+///
+/// # Returns
+///
+/// A `Result` containing the `VerifiedKey` (key and its owner) or an `AppError`:
+/// * `BadRequest` - if the key does not exist, the secret does not match or the prefix
+///   does not match the mode the key was issued for.
+/// * `Unauthorized` - if the key belongs to a different user than the claims state,
+///   or a legacy key is presented after the sunset date.
+/// * `Forbidden` - if the key is not active (e.g. revoked).
+async fn verify_key(pool: &PgPool, config: &Config, key_claims: &KeyClaims) -> Res<VerifiedKey> {
+    let invalid_key = || AppError::BadRequest("Invalid key".to_string());
+
+    // fetch record from database
+    let key_record = match key_claims {
+        KeyClaims::Opaque(token) => db::key::get_key_by_public_id(pool, &token.public_id).await,
+        KeyClaims::Legacy(claims) => {
+            check_legacy_sunset(config, key_claims)?;
+            db::key::get_key_by_id(pool, &claims.key_id).await
+        }
+    }
+    .map_err(|_| invalid_key())?;
+
+    // check if secret matches hashed value from database
+    let parsed_hash = PasswordHash::new(&key_record.key_encrypted).map_err(|_| invalid_key())?;
+    Argon2::default()
+        .verify_password(key_claims.secret().as_bytes(), &parsed_hash)
+        .map_err(|_| invalid_key())?;
+
+    // check if the key prefix matches the mode it was issued for, the checksum
+    // is public so a live key could otherwise be presented as a test key
+    let mode: KeyMode = key_record.mode.parse()?;
+    if let KeyClaims::Opaque(token) = key_claims
+        && token.mode != mode
+    {
+        return Err(invalid_key());
+    }
+
+    // check if legacy key belongs to the user stated in the claims
+    if let KeyClaims::Legacy(claims) = key_claims
+        && key_record.user_id != claims.user_id
+    {
+        return Err(AppError::Unauthorized(
+            "Key does not belong to this user".to_string(),
+        ));
+    }
+
+    // check if key is still active
+    if key_record.status != "active" {
+        return Err(AppError::Forbidden(format!(
+            "Key is not active (status: {})",
+            key_record.status
+        )));
+    }
+
+    // resolve plan, legacy keys carry it in their claims
+    let plan_id = match (&key_record.plan_id, key_claims) {
+        (Some(plan_id), _) => plan_id.clone(),
+        (None, KeyClaims::Legacy(claims)) => claims.plan_id.clone(),
+        (None, KeyClaims::Opaque(_)) => {
+            return Err(AppError::Internal(format!(
+                "Key '{}' has no plan assigned",
+                key_record.id
+            )));
+        }
+    };
+
+    let user = db::user::get_user_by_id(pool, key_record.user_id).await?;
+    let scopes = Scope::from_permissions(&key_record.permissions);
+
+    Ok(VerifiedKey {
+        id: key_record.id,
+        owner: KeyOwner {
+            id: user.id,
+            stripe_customer_id: user.stripe_customer_id,
+        },
+        plan_id,
+        scopes,
+        mode,
+        status: key_record.status,
+        expires_at: key_record.expires_at,
+        allowed_ips: key_record.allowed_ips,
+    })
+}
+
+/// Whether a request was rejected down the chain instead of being served, by the scope
+/// check (403), the rate and quota limiters (429) or an unavailable quota storage (503).
+/// Rejected requests are not recorded as usage of the key.
+fn is_rejected(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    )
+}
+
+/// Rejects legacy keys once the configured sunset date has passed.
+fn check_legacy_sunset(config: &Config, key_claims: &KeyClaims) -> Res<()> {
+    if let KeyClaims::Legacy(_) = key_claims
+        && let Some(sunset) = config.legacy_api_keys_sunset
+        && Utc::now().date_naive() > sunset
+    {
+        return Err(AppError::Unauthorized(
+            "Legacy key format is no longer supported. Please generate a new key".to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use actix_web::{
+    Error, HttpMessage,
+    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+};
+use common::{error::AppError, key::Scope};
+use db::models::key::VerifiedKey;
+use futures::future::{Ready, ok};
+use std::{future::Future, pin::Pin, sync::Arc};
+
+// ScopeMiddleware struct (as a Transform)
+pub struct ScopeMiddleware {
+    scope: Scope,
+}
+
+impl ScopeMiddleware {
+    pub fn new(scope: Scope) -> Self {
+        ScopeMiddleware { scope }
+    }
+}
+
+// Implement the Transform trait for ScopeMiddleware
+impl<S, B> Transform<S, ServiceRequest> for ScopeMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: actix_web::body::MessageBody + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = Error;
+    type Transform = ScopeMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ScopeMiddlewareService {
+            service: Arc::new(service),
+            scope: self.scope,
+        })
+    }
+}
+
+// Service struct for the middleware
+pub struct ScopeMiddlewareService<S> {
+    service: Arc<S>,
+    scope: Scope,
+}
+
+// Implement the Service trait for ScopeMiddlewareService
+impl<S, B> Service<ServiceRequest> for ScopeMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: actix_web::body::MessageBody + 'static,
+{
+    type Response = ServiceResponse<actix_web::body::BoxBody>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let srv = Arc::clone(&self.service);
+        let scope = self.scope;
+
+        Box::pin(async move {
+            // Key middleware must have verified the key before
+            let has_scope = req
+                .extensions()
+                .get::<VerifiedKey>()
+                .map(|verified_key| verified_key.scopes.contains(&scope));
+            let has_scope = match has_scope {
+                Some(has_scope) => has_scope,
+                None => {
+                    return Ok(req.error_response(AppError::Unauthorized(
+                        "No API key provided".to_string(),
+                    )));
+                }
+            };
+
+            if !has_scope {
+                return Ok(req.error_response(AppError::Forbidden(format!(
+                    "Key is missing required scope '{}'",
+                    scope
+                ))));
+            }
+
+            srv.call(req).await.map(|res| res.map_into_boxed_body())
+        })
+    }
+}
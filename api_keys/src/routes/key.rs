@@ -1,14 +1,22 @@
 use std::sync::Arc;
 
 use actix_web::{
-    Responder, get, post,
+    Responder, get, patch, post, put,
     web::{self},
 };
-use common::{env_config::Config, error::Res, http::Success, jwt::JwtClaims};
+use chrono::Duration;
+use common::{
+    env_config::{Config, RestrictedAction},
+    error::Res,
+    http::Success,
+    jwt::JwtClaims,
+};
 use sqlx::PgPool;
+use uuid::Uuid;
 
 use crate::{
-    dtos::key::{CreateKeyRequest, RevokeKeyRequest},
+    cache::key::KeyCache,
+    dtos::key::{CreateKeyRequest, RevokeKeyRequest, UpdateAllowedIpsRequest, UpdateKeyRequest},
     service,
 };
 
@@ -44,6 +52,8 @@ pub async fn get_keys(
 /// # Returns
 ///
 /// A `Result` containing a `Success` response with the created API key or an `AppError` if an error occurs.
+/// Returns `403 Forbidden` if the user has not verified their email and `UNVERIFIED_USER_RESTRICTIONS`
+/// includes `generate_keys`.
 #[post("/generate")]
 pub async fn post_generate_key(
     config: web::Data<Arc<Config>>,
@@ -51,6 +61,13 @@ pub async fn post_generate_key(
     pool: web::Data<Arc<PgPool>>,
     req: web::Json<CreateKeyRequest>,
 ) -> Res<impl Responder> {
+    config
+        .unverified_user_restrictions
+        .require_verified_email(
+            RestrictedAction::GenerateKeys,
+            db::user::is_user_verified(&***pool, claims.user_id),
+        )
+        .await?;
     let key = service::key::create_key(
         &pool,
         claims.into_inner(),
@@ -61,22 +78,132 @@ pub async fn post_generate_key(
     Success::created(key)
 }
 
-/// Revokes an API key.
+/// Revokes an API key owned by the authenticated user.
 ///
 /// # Arguments
 ///
+/// * `claims` - The JWT claims of the authenticated user.
 /// * `pool` - The database connection pool.
+/// * `cache` - The verified key cache.
 /// * `req` - The request containing the ID of the key to revoke.
 ///
 /// # Returns
 ///
 /// A `Result` containing a `Success` response with the revoked API key or an `AppError` if an error occurs.
+/// Returns `404 Not Found` if the key does not belong to the authenticated user.
 #[post("/revoke")]
 pub async fn post_revoke(
+    claims: web::ReqData<JwtClaims>,
     pool: web::Data<Arc<PgPool>>,
+    cache: web::Data<Arc<KeyCache>>,
     req: web::Json<RevokeKeyRequest>,
 ) -> Res<impl Responder> {
-    let key_id = req.key_id;
-    let key = service::key::update_key_status(&pool, key_id, "revoked").await?;
+    let key =
+        service::key::update_key_status(&pool, &cache, claims.user_id, req.key_id, "revoked")
+            .await?;
+    Success::ok(key)
+}
+
+/// Rotates an API key owned by the authenticated user.
+///
+/// Issues a replacement key. The old key stays valid for the configured grace period.
+///
+/// # Arguments
+///
+/// * `config` - The application configuration.
+/// * `claims` - The JWT claims of the authenticated user.
+/// * `pool` - The database connection pool.
+/// * `cache` - The verified key cache.
+/// * `path` - The ID of the key to rotate.
+///
+/// # Returns
+///
+/// A `Result` containing a `Success` response with the replacement API key or an `AppError` if an error occurs.
+/// Returns `404 Not Found` if the key does not belong to the authenticated user.
+/// Not restricted to verified users, rotating replaces a key without adding one.
+#[post("/{key_id}/rotate")]
+pub async fn post_rotate(
+    config: web::Data<Arc<Config>>,
+    claims: web::ReqData<JwtClaims>,
+    pool: web::Data<Arc<PgPool>>,
+    cache: web::Data<Arc<KeyCache>>,
+    path: web::Path<Uuid>,
+) -> Res<impl Responder> {
+    let key = service::key::rotate_key(
+        &pool,
+        &cache,
+        claims.into_inner(),
+        &config.stripe_secret_key,
+        path.into_inner(),
+        Duration::hours(config.key_rotation_grace_hours),
+    )
+    .await?;
+    Success::created(key)
+}
+
+/// Restricts an API key owned by the authenticated user to a list of IPs or CIDR ranges.
+///
+/// # Arguments
+///
+/// * `claims` - The JWT claims of the authenticated user.
+/// * `pool` - The database connection pool.
+/// * `cache` - The verified key cache.
+/// * `path` - The ID of the key to update.
+/// * `req` - The request containing the allowed IPs, `null` or empty to allow any address.
+///
+/// # Returns
+///
+/// A `Result` containing a `Success` response with the updated API key or an `AppError` if an error occurs.
+/// Returns `404 Not Found` if the key does not belong to the authenticated user.
+#[put("/{key_id}/allowed-ips")]
+pub async fn put_allowed_ips(
+    claims: web::ReqData<JwtClaims>,
+    pool: web::Data<Arc<PgPool>>,
+    cache: web::Data<Arc<KeyCache>>,
+    path: web::Path<Uuid>,
+    req: web::Json<UpdateAllowedIpsRequest>,
+) -> Res<impl Responder> {
+    let key = service::key::update_allowed_ips(
+        &pool,
+        &cache,
+        claims.user_id,
+        path.into_inner(),
+        req.into_inner().allowed_ips,
+    )
+    .await?;
+    Success::ok(key)
+}
+
+/// Updates the name and/or scopes of an API key owned by the authenticated user.
+/// The change is recorded in the key's audit trail.
+///
+/// # Arguments
+///
+/// * `claims` - The JWT claims of the authenticated user.
+/// * `pool` - The database connection pool.
+/// * `cache` - The verified key cache.
+/// * `path` - The ID of the key to update.
+/// * `req` - The fields to update.
+///
+/// # Returns
+///
+/// A `Result` containing a `Success` response with the updated API key or an `AppError` if an error occurs.
+/// Returns `404 Not Found` if the key does not belong to the authenticated user.
+#[patch("/{key_id}")]
+pub async fn patch_key(
+    claims: web::ReqData<JwtClaims>,
+    pool: web::Data<Arc<PgPool>>,
+    cache: web::Data<Arc<KeyCache>>,
+    path: web::Path<Uuid>,
+    req: web::Json<UpdateKeyRequest>,
+) -> Res<impl Responder> {
+    let key = service::key::update_key(
+        &pool,
+        &cache,
+        claims.user_id,
+        path.into_inner(),
+        req.into_inner(),
+    )
+    .await?;
     Success::ok(key)
 }
@@ -3,26 +3,30 @@ use std::sync::Arc;
 use actix_web::{
     get, web::{self}, Responder
 };
-use common::{error::Res, http::Success};
+use common::{error::Res, http::Success, jwt::JwtClaims};
 use sqlx::PgPool;
 
 use crate::{dtos::usage::KeyUsageRequest, service};
 
-/// Retrieves usage logs for a given API key.
+/// Retrieves usage logs of the authenticated user, optionally narrowed down to a single API key.
 ///
 /// # Arguments
 ///
+/// * `claims` - The JWT claims of the authenticated user.
 /// * `pool` - The database connection pool.
 /// * `req` - The request containing the query parameters for filtering usage logs.
 ///
 /// # Returns
 ///
 /// A `Result` containing a `Success` response with the usage logs or an `AppError` if an error occurs.
+/// Returns `404 Not Found` if the requested key does not belong to the authenticated user.
 #[get("/usage")]
 pub async fn get_usage(
+    claims: web::ReqData<JwtClaims>,
     pool: web::Data<Arc<PgPool>>,
     req: web::Query<KeyUsageRequest>,
 ) -> Res<impl Responder> {
-    let usage_log = service::usage::get_usage_logs(&pool, req.into_inner()).await?;
+    let usage_log =
+        service::usage::get_usage_logs(&pool, claims.user_id, req.into_inner()).await?;
     Success::ok(usage_log)
 }
@@ -1,14 +1,24 @@
 use common::{
     error::{AppError, Res},
     jwt::JwtClaims,
-    key::KeyClaims,
+    key::{ApiKeyToken, DEFAULT_SCOPES, KeyMode, Scope},
     misc::hash_str,
 };
-use db::{dtos::key::KeyCreateRequest, models::key::ApiKey};
-use sqlx::PgPool;
+use db::{
+    dtos::key::{KeyAuditLogCreateRequest, KeyCreateRequest, KeyUpdateRequest},
+    models::key::ApiKey,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use sqlx::{
+    Executor, PgPool, Postgres,
+    types::{JsonValue, ipnetwork::IpNetwork},
+};
+use std::collections::HashMap;
 use uuid::Uuid;
 
-use crate::dtos::key::{ApiKeyListItem, CreateKeyRequest, CreateKeyResponse};
+use crate::cache::key::KeyCache;
+use crate::dtos::key::{ApiKeyListItem, CreateKeyRequest, CreateKeyResponse, UpdateKeyRequest};
+use crate::tracker;
 
 /// Retrieves a list of API keys for a given user ID.
 ///
@@ -22,17 +32,38 @@ use crate::dtos::key::{ApiKeyListItem, CreateKeyRequest, CreateKeyResponse};
 /// A `Result` containing a vector of `ApiKeyListItem` objects or an `AppError` if an error occurs.
 pub async fn get_keys(pool: &PgPool, user_id: Uuid) -> Res<Vec<ApiKeyListItem>> {
     let api_keys = db::key::get_keys_by_user_id(pool, &user_id).await?;
+    let request_counts: HashMap<Uuid, i64> =
+        db::key::get_request_counts_by_user_id(pool, &user_id, tracker::key::window_start())
+            .await?
+            .into_iter()
+            .map(|count| (count.key_id, count.request_count))
+            .collect();
+
+    // map rotated keys to their replacements
+    let rotated_to: HashMap<Uuid, Uuid> = api_keys
+        .iter()
+        .filter_map(|key| key.rotated_from.map(|from| (from, key.id)))
+        .collect();
 
     let api_key_list_items = api_keys
         .into_iter()
         .map(|key| ApiKeyListItem {
+            rotated_to: rotated_to.get(&key.id).copied(),
             id: key.id,
             user_id: key.user_id,
             key_hashed: key.key_encrypted,
+            public_id: key.public_id,
             name: key.name,
             status: key.status,
+            mode: key.mode,
             created_at: key.created_at,
-            permissions: key.permissions,
+            permissions: Scope::from_permissions(&key.permissions),
+            expires_at: key.expires_at,
+            rotated_from: key.rotated_from,
+            allowed_ips: key.allowed_ips,
+            last_used_at: key.last_used_at,
+            last_used_ip: key.last_used_ip,
+            request_count: request_counts.get(&key.id).copied().unwrap_or(0),
         })
         .collect();
 
@@ -41,6 +72,8 @@ pub async fn get_keys(pool: &PgPool, user_id: Uuid) -> Res<Vec<ApiKeyListItem>>
 
 /// Creates a new API key for a user.
 ///
+/// Test keys (`sk_test_`) get canned results from the sandbox checker and do not consume quota.
+///
 /// # Arguments
 ///
 /// * `pool` - A reference to the database connection pool.
@@ -57,77 +90,483 @@ pub async fn create_key(
     stripe_secret: &str,
     req: CreateKeyRequest,
 ) -> Res<CreateKeyResponse> {
-    let user_id = claims.user_id;
-    let customer_id = &claims.stripe_customer_id;
-
-    // get plan id
-    let client = common::stripe::create_client(stripe_secret);
-    let plan = api_subs::services::sub::get_user_subscription(&client, customer_id).await?;
-    let plan_id = if let Some(plan) = plan {
-        plan.id
-    } else {
+    if let Some(expires_at) = req.expires_at
+        && expires_at <= Utc::now().naive_utc()
+    {
         return Err(AppError::BadRequest(
-            "Tried to create an API key for user with no active subscription plan".to_string(),
+            "Expiration date must be in the future".to_string(),
         ));
-    };
+    }
 
-    // generate a secret token
-    let secret = generate_secret();
+    let scopes = validate_scopes(req.permissions.unwrap_or_else(|| DEFAULT_SCOPES.to_vec()))?;
+    let plan_id = get_plan_id(&claims, stripe_secret).await?;
 
-    // insert hashed secret
-    let db_key = db::key::insert_key(
+    issue_key(
         pool,
-        KeyCreateRequest {
-            user_id,
-            key_encrypted: hash_str(secret.as_str()),
-            name: req.name,
-            permissions: req.permissions,
+        KeyIssueSpec {
+            user_id: claims.user_id,
+            name: validate_name(req.name)?,
+            permissions: scopes,
+            plan_id,
+            expires_at: req.expires_at,
+            rotated_from: None,
+            mode: req.mode,
+            allowed_ips: None,
         },
     )
-    .await?;
+    .await
+}
 
-    // construct claims
-    let key_claims = KeyClaims {
-        user_id,
-        plan_id,
-        secret,
-        key_id: db_key.id,
+/// Rotates an API key owned by the given user.
+///
+/// Issues a replacement key with the same name, scopes, mode and allowed IPs. If the old key had an
+/// expiration date, the replacement gets the same lifetime starting now. The old key stays
+/// valid for the grace period (or until its own expiration date, if sooner) and then expires.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `cache` - The verified key cache.
+/// * `claims` - The JWT claims of the authenticated user.
+/// * `stripe_secret` - The Stripe secret key.
+/// * `key_id` - The ID of the key to rotate.
+/// * `grace_period` - How long the old key stays valid.
+///
+/// # Returns
+///
+/// A `Result` containing the replacement key as a `CreateKeyResponse` or an `AppError`:
+/// * `NotFound` - if the key does not exist or belongs to another user.
+/// * `BadRequest` - if the key is not active, has expired or was already rotated.
+pub async fn rotate_key(
+    pool: &PgPool,
+    cache: &KeyCache,
+    claims: JwtClaims,
+    stripe_secret: &str,
+    key_id: Uuid,
+    grace_period: Duration,
+) -> Res<CreateKeyResponse> {
+    let user_id = claims.user_id;
+    // resolved before the transaction, so a slow Stripe call does not hold the key locked
+    let plan_id = get_plan_id(&claims, stripe_secret).await?;
+    let mut tx = pool.begin().await?;
+
+    // locked until the rotation commits, so concurrent rotations of the key see each other
+    let old_key = db::key::lock_user_key_by_id(&mut *tx, &key_id, &user_id)
+        .await?
+        .ok_or_else(key_not_found)?;
+    let now = Utc::now().naive_utc();
+
+    if old_key.status != "active" {
+        return Err(AppError::BadRequest(
+            "Only active keys can be rotated".to_string(),
+        ));
+    }
+    if old_key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(AppError::BadRequest(
+            "Expired keys cannot be rotated".to_string(),
+        ));
+    }
+    if db::key::get_key_by_rotated_from(&mut *tx, &old_key.id)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::BadRequest(
+            "Key has already been rotated".to_string(),
+        ));
+    }
+
+    // old key expires at the end of the grace period, unless it expires sooner
+    let grace_expires_at = now + grace_period;
+    let old_expires_at = match old_key.expires_at {
+        Some(expires_at) if expires_at < grace_expires_at => expires_at,
+        _ => grace_expires_at,
     };
 
-    // serialize claims into key
-    let key = key_claims.to_key();
+    let new_key = issue_key(&mut *tx, replacement_spec(&old_key, plan_id, now)?).await?;
 
-    Ok(CreateKeyResponse {
-        id: db_key.id,
-        key,
-        user_id,
-        name: db_key.name,
-        status: db_key.status,
-        created_at: db_key.created_at,
-        permissions: db_key.permissions,
-    })
+    db::key::update_key_expiry(&mut *tx, &old_key.id, &user_id, old_expires_at)
+        .await?
+        .ok_or_else(key_not_found)?;
+
+    tx.commit().await?;
+    cache.invalidate(old_key.id).await;
+
+    Ok(new_key)
 }
 
-/// Updates the status of an API key.
+/// Retrieves an API key owned by the given user.
 ///
 /// # Arguments
 ///
 /// * `pool` - A reference to the database connection pool.
+/// * `user_id` - The ID of the authenticated user.
+/// * `key_id` - The ID of the key to retrieve.
+///
+/// # Returns
+///
+/// A `Result` containing the `ApiKey` object or an `AppError::NotFound` if the key
+/// does not exist or belongs to another user.
+pub async fn get_user_key(pool: &PgPool, user_id: Uuid, key_id: Uuid) -> Res<ApiKey> {
+    db::key::get_user_key_by_id(pool, &key_id, &user_id)
+        .await?
+        .ok_or_else(key_not_found)
+}
+
+/// Updates the status of an API key owned by the given user and drops it from the
+/// verified key cache, so the new status applies to the next request.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `cache` - The verified key cache.
+/// * `user_id` - The ID of the authenticated user.
 /// * `key_id` - The ID of the key to update.
 /// * `status` - The new status of the key.
 ///
 /// # Returns
 ///
-/// A `Result` containing the updated `ApiKey` object or an `AppError` if an error occurs.
-pub async fn update_key_status(pool: &PgPool, key_id: Uuid, status: &str) -> Res<ApiKey> {
-    db::key::update_key_status(pool, key_id, status).await
+/// A `Result` containing the updated `ApiKey` object or an `AppError::NotFound` if the key
+/// does not exist or belongs to another user.
+pub async fn update_key_status(
+    pool: &PgPool,
+    cache: &KeyCache,
+    user_id: Uuid,
+    key_id: Uuid,
+    status: &str,
+) -> Res<ApiKey> {
+    let key = db::key::update_key_status(pool, &key_id, &user_id, status)
+        .await?
+        .ok_or_else(key_not_found)?;
+    cache.invalidate(key.id).await;
+    Ok(key)
 }
 
-/// Generates a secret key.
+/// Updates the name and/or scopes of an API key owned by the given user, records
+/// the change in the key's audit trail and drops the key from the verified key cache.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `cache` - The verified key cache.
+/// * `user_id` - The ID of the authenticated user.
+/// * `key_id` - The ID of the key to update.
+/// * `req` - The fields to update. Fields left out are not changed.
 ///
 /// # Returns
 ///
-/// A randomly generated UUID as a string.
-fn generate_secret() -> String {
-    Uuid::new_v4().to_string()
+/// A `Result` containing the updated `ApiKey` object or an `AppError`:
+/// * `BadRequest` - if no field is provided, the name is empty or too long, or the scope list is empty.
+/// * `NotFound` - if the key does not exist or belongs to another user.
+pub async fn update_key(
+    pool: &PgPool,
+    cache: &KeyCache,
+    user_id: Uuid,
+    key_id: Uuid,
+    req: UpdateKeyRequest,
+) -> Res<ApiKey> {
+    if req.name.is_none() && req.permissions.is_none() {
+        return Err(AppError::BadRequest("Nothing to update".to_string()));
+    }
+    let name = req.name.map(validate_name).transpose()?;
+    let scopes = req.permissions.map(validate_scopes).transpose()?;
+
+    let mut tx = pool.begin().await?;
+
+    // locked until the update commits, so concurrent updates record the values they replaced
+    let old_key = db::key::lock_user_key_by_id(&mut *tx, &key_id, &user_id)
+        .await?
+        .ok_or_else(key_not_found)?;
+
+    // collect changed fields for the audit trail
+    let mut changes = serde_json::Map::new();
+    if let Some(name) = &name
+        && *name != old_key.name
+    {
+        changes.insert(
+            "name".to_string(),
+            serde_json::json!({ "from": old_key.name, "to": name }),
+        );
+    }
+    if let Some(scopes) = &scopes {
+        let old_scopes = Scope::from_permissions(&old_key.permissions);
+        if *scopes != old_scopes {
+            changes.insert(
+                "permissions".to_string(),
+                serde_json::json!({ "from": old_scopes, "to": scopes }),
+            );
+        }
+    }
+
+    let key = db::key::update_key(
+        &mut *tx,
+        &key_id,
+        &user_id,
+        KeyUpdateRequest {
+            name,
+            permissions: scopes.as_deref().map(Scope::to_permissions),
+        },
+    )
+    .await?
+    .ok_or_else(key_not_found)?;
+
+    if !changes.is_empty() {
+        db::key::insert_key_audit_log(
+            &mut *tx,
+            KeyAuditLogCreateRequest {
+                key_id,
+                user_id,
+                action: "update".to_string(),
+                changes: JsonValue::Object(changes),
+            },
+        )
+        .await?;
+    }
+
+    tx.commit().await?;
+    cache.invalidate(key.id).await;
+
+    Ok(key)
+}
+
+/// Maximum number of CIDR ranges a key can be restricted to.
+const MAX_ALLOWED_IPS: usize = 100;
+
+/// Restricts an API key owned by the given user to a list of CIDR ranges
+/// and drops it from the verified key cache.
+///
+/// Ranges are normalized to their network address (e.g. `10.0.0.1/8` becomes `10.0.0.0/8`).
+/// `None` or an empty list removes the restriction.
+///
+/// # Arguments
+///
+/// * `pool` - A reference to the database connection pool.
+/// * `cache` - The verified key cache.
+/// * `user_id` - The ID of the authenticated user.
+/// * `key_id` - The ID of the key to update.
+/// * `allowed_ips` - The CIDR ranges the key may be used from.
+///
+/// # Returns
+///
+/// A `Result` containing the updated `ApiKey` object or an `AppError`:
+/// * `BadRequest` - if more than `MAX_ALLOWED_IPS` ranges are given.
+/// * `NotFound` - if the key does not exist or belongs to another user.
+pub async fn update_allowed_ips(
+    pool: &PgPool,
+    cache: &KeyCache,
+    user_id: Uuid,
+    key_id: Uuid,
+    allowed_ips: Option<Vec<IpNetwork>>,
+) -> Res<ApiKey> {
+    let allowed_ips = allowed_ips.filter(|networks| !networks.is_empty());
+    if let Some(networks) = &allowed_ips
+        && networks.len() > MAX_ALLOWED_IPS
+    {
+        return Err(AppError::BadRequest(format!(
+            "A key can be restricted to at most {} IP ranges",
+            MAX_ALLOWED_IPS
+        )));
+    }
+
+    // CIDR columns reject addresses with host bits set
+    let allowed_ips = allowed_ips.map(|networks| {
+        networks
+            .into_iter()
+            .map(|network| IpNetwork::new(network.network(), network.prefix()).unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let key = db::key::update_key_allowed_ips(pool, &key_id, &user_id, allowed_ips.as_deref())
+        .await?
+        .ok_or_else(key_not_found)?;
+    cache.invalidate(key.id).await;
+    Ok(key)
+}
+
+/// Error returned for keys that do not exist or belong to another user.
+/// Both cases are reported the same way so key IDs of other users are not disclosed.
+fn key_not_found() -> AppError {
+    AppError::NotFound("API key not found".to_string())
+}
+
+/// Maximum length of a key name, matches the `name` column.
+const MAX_NAME_LEN: usize = 100;
+
+/// Trims a key name and checks that it fits the `name` column.
+fn validate_name(name: String) -> Res<String> {
+    let name = name.trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(AppError::BadRequest(format!(
+            "Key name must be between 1 and {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    Ok(name)
+}
+
+/// Sorts and deduplicates scopes. Keys without scopes are useless, so at least one is required.
+fn validate_scopes(mut scopes: Vec<Scope>) -> Res<Vec<Scope>> {
+    scopes.sort_by_key(|scope| scope.as_str());
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(AppError::BadRequest(
+            "At least one scope is required".to_string(),
+        ));
+    }
+    Ok(scopes)
+}
+
+/// Returns the ID of the user's current subscription plan.
+/// Keys can only be issued to users with an active subscription.
+async fn get_plan_id(claims: &JwtClaims, stripe_secret: &str) -> Res<String> {
+    let client = common::stripe::create_client(stripe_secret);
+    let plan =
+        api_subs::services::sub::get_user_subscription(&client, &claims.stripe_customer_id)
+            .await?;
+    match plan {
+        Some(plan) => Ok(plan.id),
+        None => Err(AppError::BadRequest(
+            "Tried to create an API key for user with no active subscription plan".to_string(),
+        )),
+    }
+}
+
+/// Spec of the key replacing `old_key` on rotation, issued at `now` for `plan_id`.
+///
+/// The replacement keeps the name, scopes, mode and allowed IPs of the old key, so rotating
+/// never lifts a restriction. Keys with an expiration date keep their lifetime.
+fn replacement_spec(old_key: &ApiKey, plan_id: String, now: NaiveDateTime) -> Res<KeyIssueSpec> {
+    Ok(KeyIssueSpec {
+        user_id: old_key.user_id,
+        name: old_key.name.clone(),
+        permissions: Scope::from_permissions(&old_key.permissions),
+        plan_id,
+        expires_at: old_key
+            .expires_at
+            .map(|expires_at| now + (expires_at - old_key.created_at)),
+        rotated_from: Some(old_key.id),
+        mode: old_key.mode.parse()?,
+        allowed_ips: old_key.allowed_ips.clone(),
+    })
+}
+
+struct KeyIssueSpec {
+    user_id: Uuid,
+    name: String,
+    permissions: Vec<Scope>,
+    plan_id: String,
+    expires_at: Option<NaiveDateTime>,
+    rotated_from: Option<Uuid>,
+    mode: KeyMode,
+    allowed_ips: Option<Vec<IpNetwork>>,
+}
+
+/// Generates an opaque key and stores its hashed secret.
+/// The returned response is the only place the key is ever shown.
+async fn issue_key<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    spec: KeyIssueSpec,
+) -> Res<CreateKeyResponse> {
+    // generate an opaque key
+    let token = ApiKeyToken::generate(spec.mode);
+
+    // insert hashed secret
+    let db_key = db::key::insert_key(
+        executor,
+        KeyCreateRequest {
+            user_id: spec.user_id,
+            key_encrypted: hash_str(token.secret.as_str()),
+            name: spec.name,
+            permissions: Scope::to_permissions(&spec.permissions),
+            public_id: token.public_id.clone(),
+            plan_id: spec.plan_id,
+            expires_at: spec.expires_at,
+            rotated_from: spec.rotated_from,
+            mode: spec.mode.to_string(),
+            allowed_ips: spec.allowed_ips,
+        },
+    )
+    .await?;
+
+    // serialize token into key
+    let key = token.to_key();
+
+    Ok(CreateKeyResponse {
+        id: db_key.id,
+        key,
+        user_id: db_key.user_id,
+        name: db_key.name,
+        status: db_key.status,
+        mode: db_key.mode,
+        created_at: db_key.created_at,
+        permissions: Scope::from_permissions(&db_key.permissions),
+        expires_at: db_key.expires_at,
+        rotated_from: db_key.rotated_from,
+        allowed_ips: db_key.allowed_ips,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn old_key(allowed_ips: Option<Vec<IpNetwork>>) -> ApiKey {
+        let created_at = Utc::now().naive_utc() - Duration::days(10);
+        ApiKey {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            key_encrypted: "unused".to_string(),
+            name: "production".to_string(),
+            status: "active".to_string(),
+            created_at,
+            permissions: serde_json::json!(["checker:batch", "checker:read"]),
+            public_id: Some("3kTMd9aQxR2b".to_string()),
+            plan_id: Some("price_old".to_string()),
+            expires_at: Some(created_at + Duration::days(30)),
+            rotated_from: None,
+            allowed_ips,
+            last_used_at: None,
+            last_used_ip: None,
+            mode: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn replacement_keeps_allowed_ips() {
+        let allowed_ips: Vec<IpNetwork> = vec![
+            "203.0.113.0/24".parse().unwrap(),
+            "2001:db8::/32".parse().unwrap(),
+        ];
+        let old_key = old_key(Some(allowed_ips.clone()));
+
+        let spec =
+            replacement_spec(&old_key, "price_new".to_string(), Utc::now().naive_utc()).unwrap();
+
+        assert_eq!(spec.allowed_ips, Some(allowed_ips));
+    }
+
+    #[test]
+    fn replacement_keeps_unrestricted_keys_unrestricted() {
+        let old_key = old_key(None);
+
+        let spec =
+            replacement_spec(&old_key, "price_new".to_string(), Utc::now().naive_utc()).unwrap();
+
+        assert_eq!(spec.allowed_ips, None);
+    }
+
+    #[test]
+    fn replacement_keeps_key_settings() {
+        let old_key = old_key(None);
+        let now = Utc::now().naive_utc();
+
+        let spec = replacement_spec(&old_key, "price_new".to_string(), now).unwrap();
+
+        assert_eq!(spec.user_id, old_key.user_id);
+        assert_eq!(spec.name, old_key.name);
+        assert_eq!(spec.permissions, [Scope::CheckerBatch, Scope::CheckerRead]);
+        assert_eq!(spec.plan_id, "price_new");
+        assert_eq!(spec.expires_at, Some(now + Duration::days(30)));
+        assert_eq!(spec.rotated_from, Some(old_key.id));
+        assert_eq!(spec.mode, KeyMode::Test);
+    }
 }
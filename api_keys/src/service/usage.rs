@@ -1,36 +1,45 @@
-use common::error::{AppError, Res};
+use common::error::Res;
 use db::dtos::log::ReportFilter;
 use sqlx::PgPool;
+use uuid::Uuid;
 
-use crate::dtos::usage::{KeyUsageRequest, UsageResponse};
+use crate::{
+    dtos::usage::{KeyUsageRequest, UsageResponse},
+    service,
+};
 
-/// Retrieves usage logs based on the provided request.
+/// Retrieves usage logs of a user based on the provided request.
 ///
 /// # Arguments
 ///
 /// * `pool` - A reference to the database connection pool.
+/// * `user_id` - The ID of the authenticated user.
 /// * `req` - The request containing the filters for retrieving usage logs.
 ///
 /// # Returns
 ///
 /// A `Result` containing a vector of `UsageResponse` objects or an `AppError` if an error occurs.
-pub async fn get_usage_logs(pool: &PgPool, req: KeyUsageRequest) -> Res<Vec<UsageResponse>> {
-    // Check if user_id or key_id is set
-    if req.user_id.is_none() && req.key_id.is_none() {
-        return Err(AppError::BadRequest(
-            "Both user id and key id were not set. At least one value must be set.".to_string(),
-        ));
+/// Returns `AppError::NotFound` if the requested key does not belong to the user.
+pub async fn get_usage_logs(
+    pool: &PgPool,
+    user_id: Uuid,
+    req: KeyUsageRequest,
+) -> Res<Vec<UsageResponse>> {
+    // Check if requested key belongs to the user
+    if let Some(key_id) = req.key_id {
+        service::key::get_user_key(pool, user_id, key_id).await?;
     }
 
     // Get logs from database
     let logs = db::log::get_report(
         pool,
         ReportFilter {
-            user_id: req.user_id,
+            user_id: Some(user_id),
             key_id: req.key_id,
+            mode: Some(req.mode.to_string()),
             method: None,
             code: None,
-            path: Some(format!("/v1")),
+            path: Some("/v1".to_string()),
             limit: Some(req.limit),
             starting_after: req.starting_after,
             ending_before: req.ending_before,
@@ -45,6 +54,8 @@ pub async fn get_usage_logs(pool: &PgPool, req: KeyUsageRequest) -> Res<Vec<Usag
             name: log.key_id.unwrap_or_default().to_string(),
             date: log.timestamp,
             path: log.path.clone(),
+            mode: log.mode.clone(),
+            units: log.units,
         })
         .collect())
 }
@@ -0,0 +1,126 @@
+use std::{net::IpAddr, time::Duration};
+
+use chrono::{Days, NaiveDate, NaiveDateTime, Utc};
+use dashmap::DashMap;
+use db::dtos::key::KeyUsageUpdate;
+use sqlx::{PgPool, types::ipnetwork::IpNetwork};
+use uuid::Uuid;
+
+/// Days, including today, over which the requests of a key are counted when keys are listed.
+pub const REQUEST_COUNT_WINDOW_DAYS: u64 = 30;
+
+struct PendingUsage {
+    last_used_at: NaiveDateTime,
+    last_used_ip: Option<IpNetwork>,
+    request_count: i64,
+}
+
+/// Tracks when, from where and how often API keys are used.
+///
+/// Usage is accumulated in memory and written to the database in batches by
+/// `flush_periodically`, so authenticated requests never wait on a write.
+/// Usage not yet flushed is lost if the process is killed.
+///
+/// Requests are counted per key and day, a batch counts towards the day of its
+/// last request. Days that left the `REQUEST_COUNT_WINDOW_DAYS` window are
+/// deleted by `clean_periodically`.
+#[derive(Default)]
+pub struct KeyUsageTracker {
+    pending: DashMap<Uuid, PendingUsage>,
+}
+
+impl KeyUsageTracker {
+    pub fn new() -> Self {
+        KeyUsageTracker {
+            pending: DashMap::new(),
+        }
+    }
+
+    /// Records a request made with a key.
+    pub fn record(&self, key_id: Uuid, ip: Option<IpAddr>) {
+        self.merge(
+            key_id,
+            PendingUsage {
+                last_used_at: Utc::now().naive_utc(),
+                last_used_ip: ip.map(IpNetwork::from),
+                request_count: 1,
+            },
+        );
+    }
+
+    /// Writes all pending usage to the database in a single query.
+    /// On failure, the usage is kept and retried with the next flush.
+    pub async fn flush(&self, pool: &PgPool) {
+        let key_ids: Vec<Uuid> = self.pending.iter().map(|entry| *entry.key()).collect();
+        if key_ids.is_empty() {
+            return;
+        }
+
+        let updates: Vec<KeyUsageUpdate> = key_ids
+            .into_iter()
+            .filter_map(|key_id| self.pending.remove(&key_id))
+            .map(|(key_id, usage)| KeyUsageUpdate {
+                key_id,
+                last_used_at: usage.last_used_at,
+                last_used_ip: usage.last_used_ip,
+                request_count: usage.request_count,
+            })
+            .collect();
+
+        if let Err(e) = db::key::update_keys_usage(pool, &updates).await {
+            log::error!("Failed to write usage of {} keys: {}", updates.len(), e);
+            for update in updates {
+                self.merge(
+                    update.key_id,
+                    PendingUsage {
+                        last_used_at: update.last_used_at,
+                        last_used_ip: update.last_used_ip,
+                        request_count: update.request_count,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Flushes pending usage every `period`.
+    pub async fn flush_periodically(&self, pool: &PgPool, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.flush(pool).await;
+        }
+    }
+
+    /// Deletes the daily usage that left the request count window every `period`.
+    pub async fn clean_periodically(&self, pool: &PgPool, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            match db::key::delete_key_usage_before(pool, window_start()).await {
+                Ok(deleted) if deleted > 0 => {
+                    log::info!("Deleted {} days of key usage outside the window", deleted)
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Failed to delete old key usage: {}", e),
+            }
+        }
+    }
+
+    fn merge(&self, key_id: Uuid, usage: PendingUsage) {
+        self.pending
+            .entry(key_id)
+            .and_modify(|pending| {
+                if usage.last_used_at >= pending.last_used_at {
+                    pending.last_used_at = usage.last_used_at;
+                    pending.last_used_ip = usage.last_used_ip.or(pending.last_used_ip);
+                }
+                pending.request_count += usage.request_count;
+            })
+            .or_insert(usage);
+    }
+}
+
+/// First day of the request count window.
+pub fn window_start() -> NaiveDate {
+    Utc::now().date_naive() - Days::new(REQUEST_COUNT_WINDOW_DAYS - 1)
+}
@@ -0,0 +1,234 @@
+//! Tests of the verified key cache. The tests of the Redis tier and of invalidations between
+//! instances need a Redis server at `REDIS_URL` (default `redis://127.0.0.1/`). They are ignored
+//! by default, run them with `cargo test -- --ignored`.
+
+use std::{sync::Arc, time::Duration};
+
+use api_keys::cache::key::KeyCache;
+use common::{
+    connection::RedisConnection,
+    key::{KeyMode, Scope},
+};
+use db::models::key::{KeyOwner, VerifiedKey};
+use redis::AsyncCommands;
+use uuid::Uuid;
+
+const TTL: Duration = Duration::from_secs(60);
+
+fn verified_key() -> VerifiedKey {
+    VerifiedKey {
+        id: Uuid::new_v4(),
+        owner: KeyOwner {
+            id: Uuid::new_v4(),
+            stripe_customer_id: "cus_test".to_string(),
+        },
+        plan_id: "price_test".to_string(),
+        scopes: vec![Scope::CheckerRead],
+        mode: KeyMode::Live,
+        status: "active".to_string(),
+        expires_at: None,
+        allowed_ips: None,
+    }
+}
+
+/// Redis connection that is never reachable, the in-process tier works without Redis.
+fn no_redis() -> Arc<RedisConnection> {
+    Arc::new(RedisConnection::new(
+        redis::Client::open("redis://127.0.0.1:1/").unwrap(),
+    ))
+}
+
+/// Redis connection for tests that need Redis, panics if it is not reachable.
+async fn redis() -> Arc<RedisConnection> {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let connection = RedisConnection::new(redis::Client::open(url).unwrap());
+    if let Err(e) = connection.get().await {
+        panic!("Redis is not reachable: {}", e);
+    }
+    Arc::new(connection)
+}
+
+async fn cached_in_redis(redis: &RedisConnection, digest: &str) -> Option<String> {
+    let mut conn = redis.get().await.unwrap();
+    conn.get(format!("keycache:entry:{}", digest))
+        .await
+        .unwrap()
+}
+
+#[actix_web::test]
+async fn caches_verified_key() {
+    let cache = KeyCache::new(10, TTL, no_redis(), false);
+    let key = verified_key();
+    let digest = KeyCache::digest("sk_live_cached");
+
+    let generation = cache.generation().await;
+    cache.insert(&digest, &key, generation).await;
+
+    let cached = cache.get(&digest).await.unwrap();
+    assert_eq!(cached.id, key.id);
+    assert_eq!(cache.stats().local_hits, 1);
+}
+
+#[actix_web::test]
+async fn invalidate_drops_entries() {
+    let cache = KeyCache::new(10, TTL, no_redis(), false);
+    let key = verified_key();
+    let digest = KeyCache::digest("sk_live_invalidated");
+    let generation = cache.generation().await;
+    cache.insert(&digest, &key, generation).await;
+
+    cache.invalidate(key.id).await;
+
+    assert!(cache.get(&digest).await.is_none());
+}
+
+#[actix_web::test]
+async fn skips_key_invalidated_during_verification() {
+    let cache = KeyCache::new(10, TTL, no_redis(), false);
+    let key = verified_key();
+    let digest = KeyCache::digest("sk_live_stale");
+
+    // the key is revoked while its verification is in flight
+    let generation = cache.generation().await;
+    cache.invalidate(key.id).await;
+    cache.insert(&digest, &key, generation).await;
+
+    assert!(cache.get(&digest).await.is_none());
+}
+
+#[actix_web::test]
+async fn caches_key_verified_after_invalidation() {
+    let cache = KeyCache::new(10, TTL, no_redis(), false);
+    let key = verified_key();
+    let digest = KeyCache::digest("sk_live_fresh");
+
+    cache.invalidate(key.id).await;
+    let generation = cache.generation().await;
+    cache.insert(&digest, &key, generation).await;
+
+    assert!(cache.get(&digest).await.is_some());
+}
+
+#[actix_web::test]
+async fn invalidation_of_other_key_does_not_skip_insert() {
+    let cache = KeyCache::new(10, TTL, no_redis(), false);
+    let key = verified_key();
+    let digest = KeyCache::digest("sk_live_other");
+
+    let generation = cache.generation().await;
+    cache.invalidate(Uuid::new_v4()).await;
+    cache.insert(&digest, &key, generation).await;
+
+    assert!(cache.get(&digest).await.is_some());
+}
+
+#[actix_web::test]
+#[ignore = "needs Redis"]
+async fn redis_tier_stores_no_secrets() {
+    let redis = redis().await;
+    let cache = KeyCache::new(0, TTL, redis.clone(), true);
+    let key = verified_key();
+    let digest = KeyCache::digest(&format!("sk_live_{}", Uuid::new_v4()));
+
+    let generation = cache.generation().await;
+    cache.insert(&digest, &key, generation).await;
+
+    let json = cached_in_redis(&redis, &digest).await.unwrap();
+    let entry: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let mut fields: Vec<&str> = entry
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    fields.sort();
+    assert_eq!(
+        fields,
+        [
+            "allowed_ips",
+            "expires_at",
+            "id",
+            "mode",
+            "owner_id",
+            "plan_id",
+            "scopes",
+            "status",
+            "stripe_customer_id"
+        ]
+    );
+
+    cache.invalidate(key.id).await;
+    assert!(cached_in_redis(&redis, &digest).await.is_none());
+}
+
+#[actix_web::test]
+#[ignore = "needs Redis"]
+async fn redis_tier_hit_restores_owner() {
+    let redis = redis().await;
+    // no in-process tier, so the hit comes from Redis
+    let cache = KeyCache::new(0, TTL, redis.clone(), true);
+    let key = verified_key();
+    let digest = KeyCache::digest(&format!("sk_live_{}", Uuid::new_v4()));
+
+    let generation = cache.generation().await;
+    cache.insert(&digest, &key, generation).await;
+
+    let cached = cache.get(&digest).await.unwrap();
+    assert_eq!(cached.owner.id, key.owner.id);
+    assert_eq!(
+        cached.owner.stripe_customer_id,
+        key.owner.stripe_customer_id
+    );
+    assert_eq!(cache.stats().redis_hits, 1);
+    cache.invalidate(key.id).await;
+}
+
+#[actix_web::test]
+#[ignore = "needs Redis"]
+async fn redis_tier_skips_key_invalidated_during_verification() {
+    let redis = redis().await;
+    // separate instances, as if the key was revoked through another instance
+    let cache = KeyCache::new(0, TTL, redis.clone(), true);
+    let other = KeyCache::new(0, TTL, redis.clone(), true);
+    let key = verified_key();
+    let digest = KeyCache::digest(&format!("sk_live_{}", Uuid::new_v4()));
+
+    let generation = cache.generation().await;
+    other.invalidate(key.id).await;
+    cache.insert(&digest, &key, generation).await;
+    assert!(cached_in_redis(&redis, &digest).await.is_none());
+
+    let generation = cache.generation().await;
+    cache.insert(&digest, &key, generation).await;
+    assert!(cached_in_redis(&redis, &digest).await.is_some());
+    cache.invalidate(key.id).await;
+}
+
+#[actix_web::test]
+#[ignore = "needs Redis"]
+async fn invalidation_reaches_other_instances() {
+    let redis = redis().await;
+    // separate instances without the Redis tier, as by default
+    let cache = KeyCache::new(10, TTL, redis.clone(), false);
+    let other = Arc::new(KeyCache::new(10, TTL, redis.clone(), false));
+    let listener = other.clone();
+    actix_web::rt::spawn(async move { listener.listen_for_invalidations().await });
+    let key = verified_key();
+    let digest = KeyCache::digest(&format!("sk_live_{}", Uuid::new_v4()));
+
+    let generation = other.generation().await;
+    other.insert(&digest, &key, generation).await;
+    assert!(other.get(&digest).await.is_some());
+
+    // the listener subscribes in the background, invalidate until it has
+    let mut dropped = false;
+    for _ in 0..50 {
+        cache.invalidate(key.id).await;
+        if other.get(&digest).await.is_none() {
+            dropped = true;
+            break;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(dropped, "the other instance kept the invalidated key");
+}
@@ -0,0 +1,175 @@
+//! Tests of the key usage tracker. They need a PostgreSQL server at
+//! `TEST_DATABASE_URL` (default `postgres://postgres@127.0.0.1/tokencheck_test`),
+//! whose database is created and migrated on first use. They are ignored by default,
+//! run them with `cargo test -- --ignored`.
+
+use std::sync::OnceLock;
+
+use api_keys::tracker::key::{self, KeyUsageTracker};
+use chrono::{Days, Utc};
+use common::misc::UserVerificationOrigin;
+use db::{
+    dtos::{
+        key::{KeyCreateRequest, KeyUsageUpdate},
+        user::UserCreateRequest,
+    },
+    models::key::ApiKey,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+fn database_url() -> String {
+    std::env::var("TEST_DATABASE_URL")
+        .unwrap_or_else(|_| "postgres://postgres@127.0.0.1/tokencheck_test".to_string())
+}
+
+/// Creates and migrates the test database, once for all tests.
+fn migrate() {
+    static MIGRATED: OnceLock<Result<(), String>> = OnceLock::new();
+    let result = MIGRATED.get_or_init(|| {
+        // on a runtime of its own, as each test has one that ends with the test
+        std::thread::spawn(|| {
+            actix_web::rt::System::new().block_on(async {
+                let pool = db::setup(&database_url(), false)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                pool.close().await;
+                Ok(())
+            })
+        })
+        .join()
+        .unwrap()
+    });
+    if let Err(e) = result {
+        panic!("Failed to set up the test database: {}", e);
+    }
+}
+
+/// Connects to the test database, panics if it is not reachable.
+async fn connect() -> PgPool {
+    migrate();
+    PgPool::connect(&database_url()).await.unwrap()
+}
+
+async fn create_key(pool: &PgPool) -> ApiKey {
+    let user = db::user::insert_user(
+        pool,
+        UserCreateRequest {
+            email: format!("{}@example.com", Uuid::new_v4()),
+            first_name: "Jane".to_string(),
+            last_name: "Doe".to_string(),
+            company_name: None,
+            verification_origin: UserVerificationOrigin::Email,
+            verified: true,
+            stripe_customer_id: "cus_test".to_string(),
+        },
+    )
+    .await
+    .unwrap();
+
+    db::key::insert_key(
+        pool,
+        KeyCreateRequest {
+            user_id: user.id,
+            key_encrypted: "unused".to_string(),
+            name: "test".to_string(),
+            permissions: serde_json::json!(["checker:read"]),
+            public_id: Uuid::new_v4().simple().to_string()[..12].to_string(),
+            plan_id: "price_test".to_string(),
+            expires_at: None,
+            rotated_from: None,
+            mode: "live".to_string(),
+            allowed_ips: None,
+        },
+    )
+    .await
+    .unwrap()
+}
+
+async fn request_count(pool: &PgPool, key: &ApiKey) -> i64 {
+    db::key::get_request_counts_by_user_id(pool, &key.user_id, key::window_start())
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|count| count.key_id == key.id)
+        .map(|count| count.request_count)
+        .unwrap_or(0)
+}
+
+/// Writes `count` requests made `days_ago` days ago.
+async fn record_days_ago(pool: &PgPool, key: &ApiKey, days_ago: u64, count: i64) {
+    let last_used_at = (Utc::now() - Days::new(days_ago)).naive_utc();
+    db::key::update_keys_usage(
+        pool,
+        &[KeyUsageUpdate {
+            key_id: key.id,
+            last_used_at,
+            last_used_ip: None,
+            request_count: count,
+        }],
+    )
+    .await
+    .unwrap();
+}
+
+#[actix_web::test]
+#[ignore = "needs PostgreSQL"]
+async fn flushes_usage() {
+    let pool = connect().await;
+    let key = create_key(&pool).await;
+    let tracker = KeyUsageTracker::new();
+    let ip = "203.0.113.7".parse().unwrap();
+
+    tracker.record(key.id, Some(ip));
+    tracker.record(key.id, None);
+    tracker.flush(&pool).await;
+
+    assert_eq!(request_count(&pool, &key).await, 2);
+    let stored = db::key::get_key_by_id(&pool, &key.id).await.unwrap();
+    assert!(stored.last_used_at.is_some());
+    assert_eq!(stored.last_used_ip.map(|net| net.ip()), Some(ip));
+
+    // a second flush adds to the bucket of the day
+    tracker.record(key.id, None);
+    tracker.flush(&pool).await;
+    assert_eq!(request_count(&pool, &key).await, 3);
+}
+
+#[actix_web::test]
+#[ignore = "needs PostgreSQL"]
+async fn counts_requests_within_window() {
+    let pool = connect().await;
+    let key = create_key(&pool).await;
+    let window = key::REQUEST_COUNT_WINDOW_DAYS;
+
+    record_days_ago(&pool, &key, 0, 1).await;
+    record_days_ago(&pool, &key, window - 1, 10).await;
+    record_days_ago(&pool, &key, window, 100).await;
+
+    assert_eq!(request_count(&pool, &key).await, 11);
+}
+
+#[actix_web::test]
+#[ignore = "needs PostgreSQL"]
+async fn deletes_usage_outside_window() {
+    let pool = connect().await;
+    let key = create_key(&pool).await;
+    record_days_ago(&pool, &key, 0, 1).await;
+    record_days_ago(&pool, &key, key::REQUEST_COUNT_WINDOW_DAYS + 5, 100).await;
+
+    let deleted = db::key::delete_key_usage_before(&pool, key::window_start())
+        .await
+        .unwrap();
+    assert!(deleted >= 1);
+
+    // only the bucket in the window is left
+    let all = db::key::get_request_counts_by_user_id(
+        &pool,
+        &key.user_id,
+        Utc::now().date_naive() - Days::new(365),
+    )
+    .await
+    .unwrap();
+    assert_eq!(all.len(), 1);
+    assert_eq!(all[0].request_count, 1);
+}
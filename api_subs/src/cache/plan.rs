@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use common::{connection::RedisConnection, error::Res};
+use futures::StreamExt;
+use redis::AsyncCommands;
+use stripe::Client;
+
+use crate::{models::sub::SubscriptionPlan, services};
+
+/// Redis channel used to tell other instances to reload the plans.
+const REFRESH_CHANNEL: &str = "plans:refresh";
+
+#[derive(Default)]
+struct PlanSnapshot {
+    /// Plans in the order Stripe lists them.
+    plans: Vec<Arc<SubscriptionPlan>>,
+    by_id: HashMap<String, Arc<SubscriptionPlan>>,
+    loaded: bool,
+}
+
+/// Catalogue of the subscription plans offered in Stripe, shared by the quota
+/// limiter and the plans route.
+///
+/// The plans are reloaded every refresh period and whenever Stripe reports a
+/// price or product change. If a reload fails, the last loaded plans are kept.
+pub struct PlanRegistry {
+    client: Client,
+    snapshot: RwLock<Arc<PlanSnapshot>>,
+    redis: Option<Arc<RedisConnection>>,
+}
+
+impl PlanRegistry {
+    pub fn new(client: Client, redis: Option<Arc<RedisConnection>>) -> Self {
+        PlanRegistry {
+            client,
+            snapshot: RwLock::new(Arc::new(PlanSnapshot::default())),
+            redis,
+        }
+    }
+
+    /// Gets a plan by its price ID.
+    pub fn get(&self, plan_id: &str) -> Option<Arc<SubscriptionPlan>> {
+        self.current().by_id.get(plan_id).cloned()
+    }
+
+    /// Gets all plans, or `None` if they were never loaded.
+    pub fn all(&self) -> Option<Vec<SubscriptionPlan>> {
+        let snapshot = self.current();
+        snapshot
+            .loaded
+            .then(|| snapshot.plans.iter().map(|plan| (**plan).clone()).collect())
+    }
+
+    /// Reloads the plans from Stripe. On failure, the current plans are kept.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` indicating success or an `AppError` if the plans could not be fetched.
+    pub async fn refresh(&self) -> Res<()> {
+        let plans = services::sub::get_subscription_plans(&self.client).await?;
+        let plans: Vec<Arc<SubscriptionPlan>> = plans.into_iter().map(Arc::new).collect();
+        let by_id = plans
+            .iter()
+            .map(|plan| (plan.id.clone(), plan.clone()))
+            .collect();
+
+        log::info!("Loaded {} subscription plans", plans.len());
+        *self.snapshot.write().unwrap() = Arc::new(PlanSnapshot {
+            plans,
+            by_id,
+            loaded: true,
+        });
+        Ok(())
+    }
+
+    /// Reloads the plans on this and on all other instances.
+    /// With Redis, every instance (this one included) reloads when it receives the request.
+    pub async fn refresh_everywhere(&self) {
+        if let Some(redis) = &self.redis {
+            let result: redis::RedisResult<i64> = async {
+                let mut conn = redis.get().await?;
+                conn.publish(REFRESH_CHANNEL, "").await
+            }
+            .await;
+
+            match result {
+                Ok(receivers) if receivers > 0 => return,
+                Ok(_) => log::warn!("No instance listens for subscription plan reloads"),
+                Err(e) => log::error!("Failed to publish subscription plans reload: {}", e),
+            }
+        }
+
+        if let Err(e) = self.refresh().await {
+            log::error!("Failed to reload subscription plans: {}", e);
+        }
+    }
+
+    /// Reloads the plans every `period`.
+    pub async fn refresh_periodically(&self, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        // the first tick completes immediately, the plans are loaded at startup
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh().await {
+                log::error!(
+                    "Failed to reload subscription plans, keeping the last ones: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    /// Listens for reloads requested by any instance, this one included.
+    /// Returns immediately if Redis is disabled.
+    pub async fn listen_for_refreshes(&self) {
+        let Some(redis) = &self.redis else {
+            return;
+        };
+
+        loop {
+            // pub/sub needs a dedicated connection
+            match redis.client().get_async_pubsub().await {
+                Ok(mut pubsub) => {
+                    if let Err(e) = pubsub.subscribe(REFRESH_CHANNEL).await {
+                        log::error!("Failed to subscribe to subscription plan reloads: {}", e);
+                    } else {
+                        let mut messages = pubsub.on_message();
+                        while messages.next().await.is_some() {
+                            if let Err(e) = self.refresh().await {
+                                log::error!("Failed to reload subscription plans: {}", e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => log::error!("Failed to connect to subscription plan reloads: {}", e),
+            }
+
+            // changes missed while disconnected are picked up by the periodic reload
+            log::warn!("Subscription plan reload listener disconnected, reconnecting");
+            actix_web::rt::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    fn current(&self) -> Arc<PlanSnapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use common::{connection::RedisConnection, error::Res};
+use dashmap::DashMap;
+use futures::StreamExt;
+use redis::AsyncCommands;
+use stripe::Client;
+
+use crate::{models::sub::UserSubscription, services};
+
+/// Redis channel used to tell other instances to drop their cached subscription of a customer.
+const INVALIDATION_CHANNEL: &str = "subcache:invalidate";
+
+struct CachedSubscription {
+    subscription: Option<UserSubscription>,
+    fetched_at: Instant,
+}
+
+impl CachedSubscription {
+    /// A subscription past its period end was renewed or lapsed, either way it is outdated.
+    fn period_ended(&self) -> bool {
+        self.subscription
+            .as_ref()
+            .is_some_and(|sub| sub.current_period_end <= Utc::now().timestamp())
+    }
+}
+
+/// Cache of the active subscription of Stripe customers, so the current plan
+/// of a user can be resolved on every request without calling Stripe.
+///
+/// Entries are refetched after `ttl`. `invalidate` drops the entry of a customer
+/// immediately and notifies other instances through Redis pub/sub, it is called
+/// whenever Stripe reports a subscription change.
+pub struct SubscriptionCache {
+    client: Client,
+    entries: DashMap<String, CachedSubscription>,
+    ttl: Duration,
+    redis: Option<Arc<RedisConnection>>,
+}
+
+impl SubscriptionCache {
+    pub fn new(client: Client, ttl: Duration, redis: Option<Arc<RedisConnection>>) -> Self {
+        SubscriptionCache {
+            client,
+            entries: DashMap::new(),
+            ttl,
+            redis,
+        }
+    }
+
+    /// Gets the active subscription of a customer.
+    ///
+    /// If Stripe can't be reached, an expired entry is returned rather than an error.
+    ///
+    /// # Arguments
+    ///
+    /// * `customer_id` - The ID of the Stripe customer.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing an `Option` of `UserSubscription` or an `AppError` if an error occurs.
+    /// Returns `None` if the customer is not subscribed to any plan.
+    pub async fn get(&self, customer_id: &str) -> Res<Option<UserSubscription>> {
+        if let Some(entry) = self.entries.get(customer_id)
+            && entry.fetched_at.elapsed() < self.ttl
+            && !entry.period_ended()
+        {
+            return Ok(entry.subscription.clone());
+        }
+
+        match services::sub::get_user_subscription(&self.client, customer_id).await {
+            Ok(subscription) => {
+                self.entries.insert(
+                    customer_id.to_string(),
+                    CachedSubscription {
+                        subscription: subscription.clone(),
+                        fetched_at: Instant::now(),
+                    },
+                );
+                Ok(subscription)
+            }
+            Err(e) => match self.entries.get(customer_id) {
+                Some(entry) => {
+                    log::warn!(
+                        "Failed to refresh subscription of customer {}, using cached one: {}",
+                        customer_id,
+                        e
+                    );
+                    Ok(entry.subscription.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Drops the cached subscription of a customer, on this and on all other instances.
+    pub async fn invalidate(&self, customer_id: &str) {
+        self.entries.remove(customer_id);
+
+        let Some(redis) = &self.redis else {
+            return;
+        };
+        let result: redis::RedisResult<()> = async {
+            let mut conn = redis.get().await?;
+            conn.publish(INVALIDATION_CHANNEL, customer_id).await
+        }
+        .await;
+
+        if let Err(e) = result {
+            log::error!(
+                "Failed to publish subscription invalidation of customer {}: {}",
+                customer_id,
+                e
+            );
+        }
+    }
+
+    /// Listens for invalidations published by other instances and drops the
+    /// matching entries. Returns immediately if Redis is disabled.
+    pub async fn listen_for_invalidations(&self) {
+        let Some(redis) = &self.redis else {
+            return;
+        };
+
+        loop {
+            // pub/sub needs a dedicated connection
+            match redis.client().get_async_pubsub().await {
+                Ok(mut pubsub) => {
+                    if let Err(e) = pubsub.subscribe(INVALIDATION_CHANNEL).await {
+                        log::error!("Failed to subscribe to subscription invalidations: {}", e);
+                    } else {
+                        let mut messages = pubsub.on_message();
+                        while let Some(msg) = messages.next().await {
+                            match msg.get_payload::<String>() {
+                                Ok(customer_id) => {
+                                    self.entries.remove(&customer_id);
+                                }
+                                Err(_) => {
+                                    log::warn!("Ignoring malformed subscription invalidation")
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => log::error!("Failed to connect to subscription invalidations: {}", e),
+            }
+
+            // entries may be stale while disconnected, the TTL bounds how long
+            log::warn!("Subscription invalidation listener disconnected, reconnecting");
+            self.entries.clear();
+            actix_web::rt::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
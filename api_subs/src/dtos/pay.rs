@@ -1,10 +1,5 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize)]
-pub struct OneTimeResponse {
-    pub url: String,
-}
-
 #[derive(Deserialize)]
 pub struct SubscriptionRequest {
     pub price_id: String,
@@ -1,5 +1,10 @@
 use actix_web::web::{self};
 
+pub mod cache {
+    pub mod plan;
+    pub mod sub;
+}
+
 pub mod routes {
     pub mod pay;
     pub mod sub;
@@ -17,6 +17,7 @@ pub struct UserSubscription {
     pub customer_id: String,
     pub sub_id: String,
     pub status: String,
+    pub current_period_start: i64,
     pub current_period_end: i64,
     pub cancel_at_period_end: bool,
 }
@@ -26,4 +27,7 @@ pub struct UserSubscription {
 pub struct Metadata {
     pub daily_api_limit: String,
     pub monthly_api_limit: String,
+    /// Requests a user may have in flight at the same time, unlimited if missing or 0.
+    #[serde(default)]
+    pub concurrent_api_limit: Option<String>,
 }
\ No newline at end of file
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use actix_web::{Responder, get, post, web};
 use common::{env_config::Config, error::{AppError, Res}, http::Success, jwt::JwtClaims};
 
-use crate::{dtos::pay::{PaymentIntentsRequest, PaymentIntentsResponse, RefundRequest, RefundResponse}, services};
+use crate::{cache::{plan::PlanRegistry, sub::SubscriptionCache}, dtos::pay::{PaymentIntentsRequest, PaymentIntentsResponse, RefundRequest, RefundResponse}, services};
 
 /// Handles Stripe webhook events for payment processing.
 ///
@@ -11,6 +11,8 @@ use crate::{dtos::pay::{PaymentIntentsRequest, PaymentIntentsResponse, RefundReq
 /// - `payload`: Raw string containing the webhook event data
 /// - `req`: HTTP request containing Stripe signature in headers
 /// - `config`: Application configuration with webhook secret
+/// - `subscriptions`: Subscription cache, refreshed on subscription events
+/// - `plans`: Plan registry, reloaded on price and product events
 ///
 /// # Output
 /// - Success: Returns 200 OK when webhook is processed successfully
@@ -33,11 +35,14 @@ use crate::{dtos::pay::{PaymentIntentsRequest, PaymentIntentsResponse, RefundReq
 /// - customer.subscription.created: Processed when a new subscription is created
 /// - customer.subscription.updated: Processed when a subscription is updated
 /// - customer.subscription.deleted: Processed when a subscription is canceled
+/// - price.* and product.*: Reload the subscription plans
 #[post("/webhook")]
 async fn post_webhook(
     payload: String,
     req: actix_web::HttpRequest,
     config: web::Data<Arc<Config>>,
+    subscriptions: web::Data<Arc<SubscriptionCache>>,
+    plans: web::Data<Arc<PlanRegistry>>,
 ) -> Res<impl Responder> {
     let signature = match req.headers().get("stripe-signature") {
         Some(signature) => signature.to_str().unwrap_or(""),
@@ -45,7 +50,7 @@ async fn post_webhook(
     };
 
     let event = services::pay::construct_event(&payload, signature, &config.stripe_webhook_secret)?;
-    services::pay::process_webhook_event(event)?;
+    services::pay::process_webhook_event(event, &subscriptions, &plans).await?;
 
     Success::ok("Webhook processed successfully")
 }
@@ -1,8 +1,15 @@
 use actix_web::{Responder, get, post, web};
-use common::{env_config::Config, error::AppError, http::Success, jwt::JwtClaims};
+use common::{
+    env_config::{Config, RestrictedAction},
+    error::AppError,
+    http::Success,
+    jwt::JwtClaims,
+};
+use sqlx::PgPool;
 use std::sync::Arc;
 
 use crate::{
+    cache::plan::PlanRegistry,
     dtos::{
         pay::SubscriptionRequest,
         sub::{
@@ -13,14 +20,14 @@ use crate::{
     services,
 };
 
-/// Retrieves all available subscription plans from Stripe.
+/// Retrieves all available subscription plans from the plan registry.
 ///
 /// # Input
-/// - `config`: Application configuration with Stripe API credentials
+/// - `plans`: Plan registry, kept in sync with Stripe
 ///
 /// # Output
 /// - Success: Returns a JSON object containing an array of subscription plans
-/// - Error: Returns 500 Internal Server Error if plans cannot be retrieved
+/// - Error: Returns 500 Internal Server Error if plans were never loaded
 ///
 /// # Frontend Example
 /// ```javascript
@@ -53,9 +60,10 @@ use crate::{
 /// }
 /// ```
 #[get("/plans")]
-pub async fn get_plans(config: web::Data<Arc<Config>>) -> impl Responder {
-    let client = common::stripe::create_client(&config.stripe_secret_key);
-    let plans = services::sub::get_subscription_plans(&client).await?;
+pub async fn get_plans(plans: web::Data<Arc<PlanRegistry>>) -> impl Responder {
+    let plans = plans
+        .all()
+        .ok_or_else(|| AppError::Internal("Subscription plans are not loaded".to_string()))?;
     Success::ok(SubscriptionPlansResponse { plans })
 }
 
@@ -68,9 +76,11 @@ pub async fn get_plans(config: web::Data<Arc<Config>>) -> impl Responder {
 ///   - `success_url`: URL to redirect after successful checkout
 ///   - `cancel_url`: URL to redirect if user cancels checkout
 /// - `config`: Application configuration with Stripe API credentials
+/// - `pool`: Database connection pool
 ///
 /// # Output
 /// - Success: Returns a JSON object with a URL to the Stripe Checkout session
+/// - Error: Returns 403 Forbidden if the user has not verified their email and `UNVERIFIED_USER_RESTRICTIONS` includes `subscribe`
 /// - Error: Returns appropriate error responses for various failure scenarios
 ///
 /// # Frontend Example
@@ -100,7 +110,15 @@ pub async fn post_subscribe(
     claims: web::ReqData<JwtClaims>,
     req: web::Json<SubscriptionCreateRequest>,
     config: web::Data<Arc<Config>>,
+    pool: web::Data<Arc<PgPool>>,
 ) -> impl Responder {
+    config
+        .unverified_user_restrictions
+        .require_verified_email(
+            RestrictedAction::Subscribe,
+            db::user::is_user_verified(&***pool, claims.user_id),
+        )
+        .await?;
     let client = common::stripe::create_client(&config.stripe_secret_key);
     let customer = services::pay::get_customer(&client, &claims.stripe_customer_id).await?;
 
@@ -129,9 +147,11 @@ pub async fn post_subscribe(
 ///   - `success_url`: URL to redirect after successful checkout
 ///   - `cancel_url`: URL to redirect if user cancels checkout
 /// - `config`: Application configuration with Stripe API credentials
+/// - `pool`: Database connection pool
 ///
 /// # Output
 /// - Success: Returns a JSON object with a URL to the Stripe Checkout session
+/// - Error: Returns 403 Forbidden if the user has not verified their email and `UNVERIFIED_USER_RESTRICTIONS` includes `subscribe`
 /// - Error: Returns appropriate error responses for various failure scenarios
 ///
 /// # Frontend Example
@@ -163,7 +183,15 @@ pub async fn post_enterprise(
     claims: web::ReqData<JwtClaims>,
     req: web::Json<EnterpriseSubscriptionRequest>,
     config: web::Data<Arc<Config>>,
+    pool: web::Data<Arc<PgPool>>,
 ) -> impl Responder {
+    config
+        .unverified_user_restrictions
+        .require_verified_email(
+            RestrictedAction::Subscribe,
+            db::user::is_user_verified(&***pool, claims.user_id),
+        )
+        .await?;
     let client = common::stripe::create_client(&config.stripe_secret_key);
     let customer = services::pay::get_customer(&client, &claims.stripe_customer_id).await?;
 
@@ -204,6 +232,7 @@ pub async fn post_enterprise(
 ///   //     customer_id: "cus_456def",
 ///   //     price_id: "price_789ghi",
 ///   //     status: "active",
+///   //     current_period_start: 1669852800, // Unix timestamp
 ///   //     current_period_end: 1672531200, // Unix timestamp
 ///   //     cancel_at_period_end: false
 ///   //   }
@@ -5,9 +5,12 @@ use stripe::{
     Customer, CustomerId, Event, EventObject, EventType, PaymentIntentId, Refund, Webhook,
 };
 
-use crate::dtos::pay::{
-    CustomSubscriptionRequest, PaymentIntent, PaymentIntentsRequest, RefundRequest,
-    SubscriptionRequest,
+use crate::{
+    cache::{plan::PlanRegistry, sub::SubscriptionCache},
+    dtos::pay::{
+        CustomSubscriptionRequest, PaymentIntent, PaymentIntentsRequest, RefundRequest,
+        SubscriptionRequest,
+    },
 };
 
 /// Retrieve customer object based on customer ID.
@@ -27,7 +30,7 @@ pub async fn get_customer(client: &Client, customer_id: &str) -> Res<Customer> {
             customer_id, e
         ))
     })?;
-    Customer::retrieve(&client, &id, &[])
+    Customer::retrieve(client, &id, &[])
         .await
         .map_err(AppError::from)
 }
@@ -63,7 +66,7 @@ pub async fn create_subscription_session(
         customer: Some(customer.id.clone()),
         ..Default::default()
     };
-    CheckoutSession::create(&client, params)
+    CheckoutSession::create(client, params)
         .await
         .map_err(AppError::from)
 }
@@ -114,7 +117,7 @@ pub async fn create_custom_subscription_session(
         customer: Some(customer.id.clone()),
         ..Default::default()
     };
-    CheckoutSession::create(&client, params)
+    CheckoutSession::create(client, params)
         .await
         .map_err(AppError::from)
 }
@@ -142,15 +145,24 @@ pub fn construct_event(payload: &str, signature: &str, webhook_secret: &str) ->
 }
 
 /// Processes the webhook event.
+/// Subscription events drop the cached subscription of the customer,
+/// so rate limits follow plan changes immediately.
+/// Price and product events reload the plan catalogue.
 ///
 /// # Arguments
 ///
 /// * `event` - The Stripe `Event` object to process.
+/// * `subscriptions` - The subscription cache.
+/// * `plans` - The plan registry.
 ///
 /// # Returns
 ///
 /// A `Result` indicating success or an `AppError` if an error occurs.
-pub fn process_webhook_event(event: Event) -> Res<()> {
+pub async fn process_webhook_event(
+    event: Event,
+    subscriptions: &SubscriptionCache,
+    plans: &PlanRegistry,
+) -> Res<()> {
     log::info!("Processing webhook event: {}", event.type_);
 
     match event.type_ {
@@ -167,18 +179,30 @@ pub fn process_webhook_event(event: Event) -> Res<()> {
         EventType::CustomerSubscriptionCreated => {
             if let EventObject::Subscription(subscription) = event.data.object {
                 log::info!("Subscription created: {}", subscription.id);
+                subscriptions.invalidate(subscription.customer.id().as_str()).await;
             }
         }
         EventType::CustomerSubscriptionUpdated => {
             if let EventObject::Subscription(subscription) = event.data.object {
                 log::info!("Subscription updated: {}", subscription.id);
+                subscriptions.invalidate(subscription.customer.id().as_str()).await;
             }
         }
         EventType::CustomerSubscriptionDeleted => {
             if let EventObject::Subscription(subscription) = event.data.object {
                 log::info!("Subscription deleted: {}", subscription.id);
+                subscriptions.invalidate(subscription.customer.id().as_str()).await;
             }
         }
+        EventType::PriceCreated
+        | EventType::PriceUpdated
+        | EventType::PriceDeleted
+        | EventType::ProductCreated
+        | EventType::ProductUpdated
+        | EventType::ProductDeleted => {
+            log::info!("Plan catalogue changed, reloading plans");
+            plans.refresh_everywhere().await;
+        }
         _ => {
             log::info!("Unhandled event type: {}", event.type_);
         }
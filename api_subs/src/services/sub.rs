@@ -99,6 +99,7 @@ pub async fn get_user_subscription(
                 .map(|item| item.price.clone().unwrap().id.to_string())
                 .unwrap_or_default(),
             status: sub.status.to_string(),
+            current_period_start: sub.current_period_start,
             current_period_end: sub.current_period_end,
             cancel_at_period_end: sub.cancel_at_period_end,
         };
@@ -125,7 +126,7 @@ pub async fn subscribe_user_to_free_plan(client: &Client, customer_id: CustomerI
     let plans = get_subscription_plans(client).await?;
     let free_price_id = plans
         .iter()
-        .find(|p| p.price == Some(0) || p.price == None)
+        .find(|p| p.price == Some(0) || p.price.is_none())
         .map(|p| p.id.clone())
         .ok_or_else(|| AppError::Internal("Failed to find free plan".into()))?;
 
@@ -235,6 +236,7 @@ pub async fn update_subscription_auto_renew(
             .map(|item| item.price.clone().unwrap().id.to_string())
             .unwrap_or_default(),
         status: subscription.status.to_string(),
+        current_period_start: subscription.current_period_start,
         current_period_end: subscription.current_period_end,
         cancel_at_period_end: subscription.cancel_at_period_end,
     };
@@ -1,18 +1,110 @@
 use std::time::Duration;
-use actix_web::{post, web, Responder};
-use common::{error::Res, http::Success};
+use actix_web::{http::Method, post, web, Responder};
+use common::{error::{AppError, Res}, http::Success, key::{KeyMode, Scope}};
+use db::models::key::VerifiedKey;
+use limiter::cost::{Cost, RouteCost};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio::time::sleep;
 
-/// Test function that simulates checking tokens
-#[post("/check-token")]
-async fn check_tokens() -> Res<impl Responder> {
+mod sandbox;
+
+/// Maximum number of addresses checked in one batch request.
+const MAX_BATCH_SIZE: usize = 50;
+
+/// Maximum size in bytes of the JSON body of a batch request.
+const MAX_BATCH_BODY_BYTES: usize = 16 * 1024;
+
+#[derive(Debug, Serialize)]
+pub struct CheckResponse {
+    /// `true` if the result is canned, for requests made with test keys.
+    pub sandbox: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchCheckRequest {
+    pub addresses: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchCheckResponse {
+    /// `true` if the results are canned, for requests made with test keys.
+    pub sandbox: bool,
+    pub results: Vec<CheckResponse>,
+}
+
+/// Test function that simulates checking tokens.
+/// Requests made with test keys get canned results from the sandbox.
+#[post("/check-token", wrap = "api_keys::require_scope(Scope::CheckerRead)")]
+async fn check_tokens(verified_key: web::ReqData<VerifiedKey>) -> Res<impl Responder> {
+    if verified_key.mode == KeyMode::Test {
+        return Success::ok(sandbox::check_tokens());
+    }
+
     log::info!("Start token checker");
     sleep(Duration::from_millis(1000)).await;
     log::info!("Stop token checker");
-    Success::ok(())
+    Success::ok(CheckResponse { sandbox: false })
+}
+
+/// Test function that simulates checking up to `MAX_BATCH_SIZE` addresses at once.
+/// Costs one quota unit per address.
+#[post("/check-token/batch", wrap = "api_keys::require_scope(Scope::CheckerBatch)")]
+async fn check_tokens_batch(
+    verified_key: web::ReqData<VerifiedKey>,
+    req: web::Json<BatchCheckRequest>,
+) -> Res<impl Responder> {
+    if req.addresses.is_empty() || req.addresses.len() > MAX_BATCH_SIZE {
+        return Err(AppError::BadRequest(format!(
+            "A batch must contain between 1 and {} addresses",
+            MAX_BATCH_SIZE
+        )));
+    }
+
+    if verified_key.mode == KeyMode::Test {
+        return Success::ok(sandbox::check_tokens_batch(req.addresses.len()));
+    }
+
+    log::info!("Start batch token checker for {} addresses", req.addresses.len());
+    sleep(Duration::from_millis(1000)).await;
+    log::info!("Stop batch token checker");
+    Success::ok(BatchCheckResponse {
+        sandbox: false,
+        results: req
+            .addresses
+            .iter()
+            .map(|_| CheckResponse { sandbox: false })
+            .collect(),
+    })
+}
+
+/// Units consumed by a batch request, one per address.
+/// Invalid bodies cost 1 unit, the route rejects them.
+fn batch_cost(body: &Value) -> u64 {
+    body.get("addresses")
+        .and_then(Value::as_array)
+        .map(|addresses| addresses.len().clamp(1, MAX_BATCH_SIZE) as u64)
+        .unwrap_or(1)
+}
+
+/// Quota costs of the checker routes.
+pub fn costs() -> Vec<RouteCost> {
+    vec![
+        RouteCost::new(Method::POST, "/checker/check-token", Cost::Fixed(1)),
+        RouteCost::new(
+            Method::POST,
+            "/checker/check-token/batch",
+            Cost::FromBody {
+                max_bytes: MAX_BATCH_BODY_BYTES,
+                units: batch_cost,
+            },
+        ),
+    ]
 }
 
 pub fn mount_checker() -> actix_web::Scope {
     web::scope("/checker")
+        .app_data(web::JsonConfig::default().limit(MAX_BATCH_BODY_BYTES))
         .service(check_tokens)
-}
\ No newline at end of file
+        .service(check_tokens_batch)
+}
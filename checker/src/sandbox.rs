@@ -0,0 +1,15 @@
+use crate::{BatchCheckResponse, CheckResponse};
+
+/// Canned result returned to test keys.
+/// Always the same, so integrations can assert on it in CI.
+pub fn check_tokens() -> CheckResponse {
+    CheckResponse { sandbox: true }
+}
+
+/// Canned results of a batch, one per address.
+pub fn check_tokens_batch(addresses: usize) -> BatchCheckResponse {
+    BatchCheckResponse {
+        sandbox: true,
+        results: (0..addresses).map(|_| check_tokens()).collect(),
+    }
+}
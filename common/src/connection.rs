@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use redis::{
+    RedisResult,
+    aio::{ConnectionManager, ConnectionManagerConfig},
+};
+use tokio::sync::OnceCell;
+
+/// Redis connection shared by the limiters and the caches of all workers.
+///
+/// A single `ConnectionManager`, created on first use, that reconnects on its own.
+/// Calls time out after 1 second so an unavailable Redis does not stall requests.
+pub struct RedisConnection {
+    client: redis::Client,
+    manager: OnceCell<ConnectionManager>,
+}
+
+impl RedisConnection {
+    pub fn new(client: redis::Client) -> Self {
+        RedisConnection {
+            client,
+            manager: OnceCell::new(),
+        }
+    }
+
+    /// Gets a handle to the connection, connecting on first use.
+    pub async fn get(&self) -> RedisResult<ConnectionManager> {
+        let manager = self
+            .manager
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(Duration::from_secs(1))
+                    .set_response_timeout(Duration::from_secs(1))
+                    .set_number_of_retries(1);
+                self.client.get_connection_manager_with_config(config)
+            })
+            .await?;
+        // clones share the same underlying connection
+        Ok(manager.clone())
+    }
+
+    /// The client the connection was created from, for connections that can't be
+    /// shared (e.g. pub/sub).
+    pub fn client(&self) -> &redis::Client {
+        &self.client
+    }
+}
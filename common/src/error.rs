@@ -31,9 +31,15 @@ pub enum AppError {
     #[error("Bad request: {0}")]
     BadRequest(String),
 
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
     #[error("Too Many Requests: {0}")]
     TooManyRequests(String),
 
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+
     #[error("{0}")]
     Internal(String),
 }
@@ -82,8 +88,12 @@ impl AppError {
             AppError::BadRequest(_) => {
                 HttpResponse::BadRequest().json(serde_json::json!({ "error": self.to_string() }))
             }
+            AppError::PayloadTooLarge(_) => HttpResponse::PayloadTooLarge()
+                .json(serde_json::json!({ "error": self.to_string() })),
             AppError::TooManyRequests(_) => HttpResponse::TooManyRequests()
                 .json(serde_json::json!({ "error": self.to_string() })),
+            AppError::ServiceUnavailable(_) => HttpResponse::ServiceUnavailable()
+                .json(serde_json::json!({ "error": self.to_string() })),
 
             AppError::Internal(error) => {
                 log::error!("Internal error: {}", error);
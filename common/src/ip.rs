@@ -0,0 +1,49 @@
+use std::net::IpAddr;
+
+use actix_web::HttpRequest;
+use sqlx::types::ipnetwork::IpNetwork;
+
+/// Resolves the IP address of the client that sent the request.
+///
+/// Forwarding headers are only honored when the request comes from a trusted proxy,
+/// otherwise anyone could claim any address by sending `X-Forwarded-For`.
+/// `X-Forwarded-For` is walked from right to left, skipping trusted proxies,
+/// and the first untrusted address is the client.
+///
+/// # Arguments
+///
+/// * `req` - The incoming request.
+/// * `trusted_proxies` - The networks of reverse proxies in front of the server.
+///
+/// # Returns
+///
+/// The client IP address, or `None` if the peer address is unknown.
+pub fn client_ip(req: &HttpRequest, trusted_proxies: &[IpNetwork]) -> Option<IpAddr> {
+    let peer_ip = req.peer_addr()?.ip().to_canonical();
+    if !is_in_networks(peer_ip, trusted_proxies) {
+        return Some(peer_ip);
+    }
+
+    let forwarded_ips: Vec<IpAddr> = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+        .collect();
+
+    // the left-most address is the best guess if every hop is trusted
+    forwarded_ips
+        .iter()
+        .rev()
+        .find(|ip| !is_in_networks(**ip, trusted_proxies))
+        .or(forwarded_ips.first())
+        .copied()
+        .or(Some(peer_ip))
+}
+
+/// Returns `true` if `ip` is part of any of the given networks.
+pub fn is_in_networks(ip: IpAddr, networks: &[IpNetwork]) -> bool {
+    networks.iter().any(|network| network.contains(ip))
+}
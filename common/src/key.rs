@@ -1,31 +1,218 @@
+use std::{fmt, str::FromStr};
+
 use actix_web::{HttpMessage, HttpResponse, dev::ServiceRequest};
-use base64::{engine::general_purpose, Engine};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use base64::{Engine, engine::general_purpose};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use uuid::Uuid;
 
 use crate::error::{AppError, Res};
 
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Length of the public identifier used to look up the key record.
+pub const PUBLIC_ID_LEN: usize = 12;
+/// Length of the random secret.
+pub const SECRET_LEN: usize = 32;
+/// Length of the base62 encoded CRC32 checksum.
+pub const CHECKSUM_LEN: usize = 6;
+
+/// Environment the key was issued for.
+/// Test keys get canned results from the sandbox checker and do not consume quota.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyMode {
+    #[default]
+    Live,
+    Test,
+}
+
+impl KeyMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyMode::Live => "live",
+            KeyMode::Test => "test",
+        }
+    }
+
+    /// Returns the prefix keys of this mode start with.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            KeyMode::Live => "sk_live_",
+            KeyMode::Test => "sk_test_",
+        }
+    }
+}
+
+impl FromStr for KeyMode {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "live" => Ok(KeyMode::Live),
+            "test" => Ok(KeyMode::Test),
+            _ => Err(AppError::Internal(format!("Unknown key mode '{}'", s))),
+        }
+    }
+}
+
+impl fmt::Display for KeyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Permission granted to an API key. Stored in the key's `permissions` column
+/// as a JSON array of scope names, e.g. `["checker:read"]`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    #[serde(rename = "checker:read")]
+    CheckerRead,
+    #[serde(rename = "checker:batch")]
+    CheckerBatch,
+}
+
+/// Scopes of keys created without explicit scopes, including all keys created
+/// before scopes were enforced. Matches what those keys could access before.
+pub const DEFAULT_SCOPES: &[Scope] = &[Scope::CheckerRead];
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::CheckerRead => "checker:read",
+            Scope::CheckerBatch => "checker:batch",
+        }
+    }
+
+    /// Parses the scopes stored in a key's `permissions` column.
+    ///
+    /// Unknown scope names are ignored. Anything that yields no scope, e.g. the `{}` or the
+    /// free-form permissions stored by keys created before scopes were enforced, yields
+    /// `DEFAULT_SCOPES`. New keys always have at least one scope.
+    pub fn from_permissions(permissions: &JsonValue) -> Vec<Scope> {
+        let scopes: Vec<Scope> = permissions
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|name| {
+                serde_json::from_value(name.clone())
+                    .inspect_err(|_| log::warn!("Ignoring unknown key scope {}", name))
+                    .ok()
+            })
+            .collect();
+        if scopes.is_empty() {
+            return DEFAULT_SCOPES.to_vec();
+        }
+        scopes
+    }
+
+    /// Serializes scopes for the key's `permissions` column.
+    pub fn to_permissions(scopes: &[Scope]) -> JsonValue {
+        serde_json::json!(scopes)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Opaque API key.
+///
+/// Serialized as `sk_<mode>_<public_id>_<secret><checksum>`, e.g.
+/// `sk_live_3kTMd9aQxR2b_Yq7...` where:
+/// - `public_id` is a random base62 identifier used to look up the key record,
+/// - `secret` is a random base62 secret stored only as an argon2 hash,
+/// - `checksum` is the base62 encoded CRC32 of everything before it.
+///
+/// The checksum allows rejecting mistyped keys without a database lookup
+/// and lets secret scanners recognise leaked keys.
+#[derive(Debug, Clone)]
+pub struct ApiKeyToken {
+    pub mode: KeyMode,
+    pub public_id: String,
+    pub secret: String,
+}
+
+impl ApiKeyToken {
+    /// Generates a new key with a random public identifier and secret.
+    pub fn generate(mode: KeyMode) -> Self {
+        Self {
+            mode,
+            public_id: random_base62(PUBLIC_ID_LEN),
+            secret: random_base62(SECRET_LEN),
+        }
+    }
+
+    pub fn to_key(&self) -> String {
+        let body = format!("{}{}_{}", self.mode.prefix(), self.public_id, self.secret);
+        let checksum = checksum(&body);
+        format!("{}{}", body, checksum)
+    }
+
+    pub fn from_key(key: &str) -> Res<Self> {
+        let (mode, rest) = if let Some(rest) = key.strip_prefix(KeyMode::Live.prefix()) {
+            (KeyMode::Live, rest)
+        } else if let Some(rest) = key.strip_prefix(KeyMode::Test.prefix()) {
+            (KeyMode::Test, rest)
+        } else {
+            return Err(AppError::BadRequest(
+                "Missing prefix 'sk_live_' or 'sk_test_'".to_string(),
+            ));
+        };
+
+        let invalid_format = || AppError::BadRequest("Invalid key format".to_string());
+
+        if !rest.is_ascii()
+            || rest.len() != PUBLIC_ID_LEN + 1 + SECRET_LEN + CHECKSUM_LEN
+            || &rest[PUBLIC_ID_LEN..PUBLIC_ID_LEN + 1] != "_"
+        {
+            return Err(invalid_format());
+        }
+
+        let public_id = &rest[..PUBLIC_ID_LEN];
+        let secret = &rest[PUBLIC_ID_LEN + 1..PUBLIC_ID_LEN + 1 + SECRET_LEN];
+        let key_checksum = &rest[PUBLIC_ID_LEN + 1 + SECRET_LEN..];
+
+        if !is_base62(public_id) || !is_base62(secret) {
+            return Err(invalid_format());
+        }
+
+        let body = &key[..key.len() - CHECKSUM_LEN];
+        if checksum(body) != key_checksum {
+            return Err(AppError::BadRequest("Invalid key checksum".to_string()));
+        }
+
+        Ok(Self {
+            mode,
+            public_id: public_id.to_string(),
+            secret: secret.to_string(),
+        })
+    }
+}
+
+/// Claims of the deprecated base64-encoded JSON key format (`sk_<base64 json>`).
+///
+/// These keys expose their internals to anyone holding them and freeze the plan
+/// the user had when the key was created. They are accepted only during the
+/// migration window configured by `LEGACY_API_KEYS_SUNSET` and are no longer issued.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct KeyClaims {
+pub struct LegacyKeyClaims {
     pub user_id: Uuid,
     pub plan_id: String,
     pub key_id: Uuid,
     pub secret: String,
 }
 
-impl KeyClaims {
-    pub fn to_key(&self) -> String {
-        let json = serde_json::to_string(self).unwrap();
-        let encoded = general_purpose::STANDARD.encode(json);
-        format!("sk_{}", encoded)
-    }
-
+impl LegacyKeyClaims {
     pub fn from_key(key: &str) -> Res<Self> {
         let encoded = key
             .strip_prefix("sk_")
             .ok_or_else(|| AppError::BadRequest("Missing prefix 'sk_'".to_string()))?;
 
-        let decoded_bytes = base64::engine::general_purpose::STANDARD
+        let decoded_bytes = general_purpose::STANDARD
             .decode(encoded)
             .map_err(|e| AppError::BadRequest(format!("Base64 decode error: {}", e)))?;
 
@@ -36,6 +223,30 @@ impl KeyClaims {
     }
 }
 
+/// API key presented by the client, parsed but not yet verified against the database.
+#[derive(Debug, Clone)]
+pub enum KeyClaims {
+    Opaque(ApiKeyToken),
+    Legacy(LegacyKeyClaims),
+}
+
+impl KeyClaims {
+    pub fn from_key(key: &str) -> Res<Self> {
+        if key.starts_with(KeyMode::Live.prefix()) || key.starts_with(KeyMode::Test.prefix()) {
+            ApiKeyToken::from_key(key).map(KeyClaims::Opaque)
+        } else {
+            LegacyKeyClaims::from_key(key).map(KeyClaims::Legacy)
+        }
+    }
+
+    pub fn secret(&self) -> &str {
+        match self {
+            KeyClaims::Opaque(token) => &token.secret,
+            KeyClaims::Legacy(claims) => &claims.secret,
+        }
+    }
+}
+
 pub fn get_key_claims_or_error(req: &ServiceRequest) -> Result<KeyClaims, HttpResponse> {
     if let Some(key_claims_res) = req.extensions().get::<Res<KeyClaims>>() {
         match key_claims_res {
@@ -46,3 +257,191 @@ pub fn get_key_claims_or_error(req: &ServiceRequest) -> Result<KeyClaims, HttpRe
         Err(AppError::Unauthorized("No API key provided".to_string()).to_http_response())
     }
 }
+
+/// Returns the base62 encoded CRC32 of the given key body, padded to `CHECKSUM_LEN`.
+fn checksum(body: &str) -> String {
+    let mut value = crc32fast::hash(body.as_bytes()) as u64;
+    let mut encoded = [b'0'; CHECKSUM_LEN];
+    for slot in encoded.iter_mut().rev() {
+        *slot = BASE62[(value % 62) as usize];
+        value /= 62;
+    }
+    String::from_utf8(encoded.to_vec()).unwrap()
+}
+
+fn random_base62(len: usize) -> String {
+    let mut out = String::with_capacity(len);
+    let mut buf = [0u8; 64];
+    while out.len() < len {
+        OsRng.fill_bytes(&mut buf);
+        // reject bytes >= 248 (62 * 4) to keep the distribution uniform
+        for byte in buf.iter().filter(|b| **b < 248) {
+            if out.len() == len {
+                break;
+            }
+            out.push(BASE62[(*byte % 62) as usize] as char);
+        }
+    }
+    out
+}
+
+fn is_base62(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token() -> ApiKeyToken {
+        ApiKeyToken {
+            mode: KeyMode::Live,
+            public_id: "3kTMd9aQxR2b".to_string(),
+            secret: "Yq7rT0pLmN4sVb8cXz1aKd6fGh2jWe9u".to_string(),
+        }
+    }
+
+    fn assert_bad_request(result: Res<ApiKeyToken>, message: &str) {
+        match result {
+            Err(AppError::BadRequest(m)) => assert_eq!(m, message),
+            other => panic!("expected BadRequest({:?}), got {:?}", message, other),
+        }
+    }
+
+    #[test]
+    fn formats_key() {
+        let key = token().to_key();
+
+        assert!(key.starts_with("sk_live_3kTMd9aQxR2b_Yq7rT0pLmN4sVb8cXz1aKd6fGh2jWe9u"));
+        assert_eq!(
+            key.len(),
+            "sk_live_".len() + PUBLIC_ID_LEN + 1 + SECRET_LEN + CHECKSUM_LEN
+        );
+    }
+
+    #[test]
+    fn parses_generated_keys() {
+        for mode in [KeyMode::Live, KeyMode::Test] {
+            let generated = ApiKeyToken::generate(mode);
+            let key = generated.to_key();
+            assert!(key.starts_with(mode.prefix()));
+
+            let parsed = ApiKeyToken::from_key(&key).unwrap();
+            assert_eq!(parsed.mode, mode);
+            assert_eq!(parsed.public_id, generated.public_id);
+            assert_eq!(parsed.secret, generated.secret);
+        }
+    }
+
+    #[test]
+    fn checksum_is_base62_crc32() {
+        let body = "sk_live_3kTMd9aQxR2b_Yq7rT0pLmN4sVb8cXz1aKd6fGh2jWe9u";
+        let encoded = checksum(body);
+
+        assert_eq!(encoded.len(), CHECKSUM_LEN);
+        assert!(is_base62(&encoded));
+        let decoded = encoded.bytes().fold(0u64, |value, b| {
+            value * 62 + BASE62.iter().position(|c| *c == b).unwrap() as u64
+        });
+        assert_eq!(decoded, crc32fast::hash(body.as_bytes()) as u64);
+        assert_eq!(token().to_key(), format!("{}{}", body, encoded));
+    }
+
+    #[test]
+    fn parses_scopes_from_permissions() {
+        let permissions = serde_json::json!(["checker:read", "checker:batch", "unknown"]);
+
+        assert_eq!(
+            Scope::from_permissions(&permissions),
+            [Scope::CheckerRead, Scope::CheckerBatch]
+        );
+    }
+
+    #[test]
+    fn defaults_permissions_without_scopes() {
+        for permissions in [
+            serde_json::json!({}),
+            serde_json::json!({ "read": true }),
+            serde_json::json!([]),
+            serde_json::json!(["read", "write"]),
+        ] {
+            assert_eq!(Scope::from_permissions(&permissions), DEFAULT_SCOPES);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_prefix() {
+        let key = token().to_key();
+
+        for bad in [
+            key.replacen("sk_live_", "sk_prod_", 1),
+            key.replacen("sk_live_", "pk_live_", 1),
+            key.replacen("sk_live_", "", 1),
+            key.replacen("sk_live_", "SK_LIVE_", 1),
+        ] {
+            assert_bad_request(
+                ApiKeyToken::from_key(&bad),
+                "Missing prefix 'sk_live_' or 'sk_test_'",
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let key = token().to_key();
+
+        assert_bad_request(
+            ApiKeyToken::from_key(&key[..key.len() - 1]),
+            "Invalid key format",
+        );
+        assert_bad_request(
+            ApiKeyToken::from_key(&format!("{}0", key)),
+            "Invalid key format",
+        );
+        assert_bad_request(ApiKeyToken::from_key("sk_live_"), "Invalid key format");
+    }
+
+    #[test]
+    fn rejects_malformed_parts() {
+        let key = token().to_key();
+
+        // separator replaced
+        let no_separator = format!("{}-{}", &key[..20], &key[21..]);
+        assert_bad_request(ApiKeyToken::from_key(&no_separator), "Invalid key format");
+
+        // non base62 character in the secret
+        let bad_secret = format!("{}!{}", &key[..21], &key[22..]);
+        assert_bad_request(ApiKeyToken::from_key(&bad_secret), "Invalid key format");
+
+        // multi-byte character keeping the byte length
+        let non_ascii = format!("{}é{}", &key[..21], &key[23..]);
+        assert_bad_request(ApiKeyToken::from_key(&non_ascii), "Invalid key format");
+    }
+
+    #[test]
+    fn rejects_tampered_checksum() {
+        let key = token().to_key();
+        let (body, key_checksum) = key.split_at(key.len() - CHECKSUM_LEN);
+
+        let flipped = if key_checksum.starts_with('0') {
+            '1'
+        } else {
+            '0'
+        };
+        let tampered = format!("{}{}{}", body, flipped, &key_checksum[1..]);
+        assert_bad_request(ApiKeyToken::from_key(&tampered), "Invalid key checksum");
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let key = token().to_key();
+
+        // a different secret keeps the format valid but not the checksum
+        let tampered = key.replacen("Yq7", "Yq8", 1);
+        assert_bad_request(ApiKeyToken::from_key(&tampered), "Invalid key checksum");
+
+        // a key of the other mode with the same checksum
+        let other_mode = key.replacen("sk_live_", "sk_test_", 1);
+        assert_bad_request(ApiKeyToken::from_key(&other_mode), "Invalid key checksum");
+    }
+}
@@ -4,4 +4,6 @@ pub mod env_config;
 pub mod http;
 pub mod stripe;
 pub mod jwt;
-pub mod key;
\ No newline at end of file
+pub mod key;
+pub mod ip;
+pub mod connection;
\ No newline at end of file
@@ -1,17 +1,18 @@
 use argon2::password_hash::SaltString;
 use argon2::password_hash::rand_core::OsRng;
 use argon2::{Argon2, password_hash::PasswordHasher};
+use std::fmt;
 
 #[derive(PartialEq)]
 pub enum UserVerificationOrigin {
     Email,
     OAuth,
 }
-impl ToString for UserVerificationOrigin {
-    fn to_string(&self) -> String {
+impl fmt::Display for UserVerificationOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            UserVerificationOrigin::Email => "email".to_string(),
-            UserVerificationOrigin::OAuth => "oauth".to_string(),
+            UserVerificationOrigin::Email => write!(f, "email"),
+            UserVerificationOrigin::OAuth => write!(f, "oauth"),
         }
     }
 }
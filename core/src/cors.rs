@@ -3,7 +3,7 @@ use actix_web::http::header::{self, HeaderName};
 
 pub fn middleware(origin: &str) -> Cors {
     Cors::default()
-        .allowed_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"])
+        .allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"])
         .allowed_headers(vec![
             header::AUTHORIZATION,
             header::CONTENT_TYPE,
@@ -13,7 +13,24 @@ pub fn middleware(origin: &str) -> Cors {
             HeaderName::from_static("x-api-key"),
         ])
         .allowed_origin(origin)
-        .expose_headers(&[header::SET_COOKIE])
+        .expose_headers(vec![
+            header::SET_COOKIE,
+            header::RETRY_AFTER,
+            HeaderName::from_static("x-ratelimit-limit"),
+            HeaderName::from_static("x-ratelimit-remaining"),
+            HeaderName::from_static("x-ratelimit-reset"),
+            HeaderName::from_static("x-ratelimit-limit-day"),
+            HeaderName::from_static("x-ratelimit-remaining-day"),
+            HeaderName::from_static("x-ratelimit-reset-day"),
+            HeaderName::from_static("x-ratelimit-limit-month"),
+            HeaderName::from_static("x-ratelimit-remaining-month"),
+            HeaderName::from_static("x-ratelimit-reset-month"),
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderName::from_static("ratelimit-reset"),
+            HeaderName::from_static("ratelimit-policy"),
+            HeaderName::from_static("x-ratelimit-cost"),
+        ])
         .supports_credentials()
         .max_age(3600)
 }
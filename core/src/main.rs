@@ -1,10 +1,25 @@
 mod cors;
 
+use std::{sync::Arc, time::Duration};
+
 use actix_web::{
     App, HttpServer,
     web::{self},
 };
-use common::env_config::Config;
+use api_auth::{
+    cache::{jwks::JwksCache, oidc::OidcRegistry},
+    guard::login::{LogLockoutNotifier, LoginGuard},
+    mail::{
+        mailer::{FileMailer, Mailer, SmtpMailer},
+        verification::EmailVerifier,
+    },
+};
+use api_keys::{cache::key::KeyCache, tracker::key::KeyUsageTracker};
+use api_subs::cache::{plan::PlanRegistry, sub::SubscriptionCache};
+use common::{connection::RedisConnection, env_config::Config};
+use limiter::{
+    concurrency::ConcurrencyLimiter, cost::CostTable, rate::RouteLimiter, store::QuotaStore,
+};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -27,24 +42,171 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Failed to set up database");
 
-    // get all subscription plans from Stripe
-    let client = common::stripe::create_client(&config.stripe_secret_key);
-    let plans = api_subs::services::sub::get_subscription_plans(&client)
-        .await
-        .expect("Failed to fetch subscription plans from Stripe API");
-
-    // init Redis
+    // init Redis connection shared by the limiters and the caches of all workers
     let redis_client =
         redis::Client::open(config.redis_url.clone()).expect("Failed to create Redis client");
+    let redis_connection = Arc::new(RedisConnection::new(redis_client));
+
+    // load subscription plans from Stripe, kept up to date in the background
+    let client = common::stripe::create_client(&config.stripe_secret_key);
+    let plan_registry = Arc::new(PlanRegistry::new(
+        client.clone(),
+        Some(redis_connection.clone()),
+    ));
+    if let Err(e) = plan_registry.refresh().await {
+        log::error!("Failed to load subscription plans from Stripe, retrying later: {}", e);
+    }
+    let registry = plan_registry.clone();
+    let plan_refresh_period = Duration::from_secs(config.plan_refresh_secs);
+    actix_web::rt::spawn(async move { registry.refresh_periodically(plan_refresh_period).await });
+    let registry = plan_registry.clone();
+    actix_web::rt::spawn(async move { registry.listen_for_refreshes().await });
+
+    // init verified API key cache
+    let key_cache = Arc::new(KeyCache::new(
+        config.key_cache_capacity,
+        Duration::from_secs(config.key_cache_ttl_secs),
+        redis_connection.clone(),
+        config.key_cache_redis_enabled,
+    ));
+    let cache = key_cache.clone();
+    actix_web::rt::spawn(async move { cache.listen_for_invalidations().await });
+    let cache = key_cache.clone();
+    actix_web::rt::spawn(async move { cache.log_stats(Duration::from_secs(300)).await });
+
+    // init subscription cache, used to resolve the current plan of a key owner
+    let sub_cache = Arc::new(SubscriptionCache::new(
+        client.clone(),
+        Duration::from_secs(config.subscription_cache_ttl_secs),
+        Some(redis_connection.clone()),
+    ));
+    let cache = sub_cache.clone();
+    actix_web::rt::spawn(async move { cache.listen_for_invalidations().await });
+
+    // init rate limiters of each route group
+    let rate_limit_redis = config
+        .rate_limit_redis_enabled
+        .then(|| redis_connection.clone());
+    let auth_limiter = Arc::new(RouteLimiter::new(
+        "auth",
+        config.rate_limit_auth,
+        rate_limit_redis.clone(),
+    ));
+    let dashboard_limiter = Arc::new(RouteLimiter::new(
+        "dashboard",
+        config.rate_limit_dashboard,
+        rate_limit_redis.clone(),
+    ));
+    let api_limiter = Arc::new(RouteLimiter::new(
+        "api",
+        config.rate_limit_api,
+        rate_limit_redis.clone(),
+    ));
+    let webhook_limiter = Arc::new(RouteLimiter::new(
+        "webhook",
+        config.rate_limit_webhook,
+        rate_limit_redis,
+    ));
+    for limiter in [
+        &auth_limiter,
+        &dashboard_limiter,
+        &api_limiter,
+        &webhook_limiter,
+    ] {
+        let limiter = limiter.clone();
+        actix_web::rt::spawn(
+            async move { limiter.clean_periodically(Duration::from_secs(300)).await },
+        );
+    }
+
+    // init quota counter storage
+    let quota_store = Arc::new(QuotaStore::new(
+        redis_connection.clone(),
+        config.quota_redis_failure_policy,
+    ));
+    let store = quota_store.clone();
+    actix_web::rt::spawn(async move { store.log_stats(Duration::from_secs(300)).await });
+
+    // init in-flight request limiter of /v1
+    let concurrency_limiter = Arc::new(ConcurrencyLimiter::new());
+    let limiter = concurrency_limiter.clone();
+    actix_web::rt::spawn(async move { limiter.clean_periodically(Duration::from_secs(300)).await });
+
+    // quota costs declared by the routes under /v1
+    let quota_costs = Arc::new(CostTable::new(checker::costs()));
+
+    // init failed login tracking
+    let login_guard = Arc::new(LoginGuard::new(&config, Arc::new(LogLockoutNotifier)));
+    let guard = login_guard.clone();
+    let guard_pool = pool.clone();
+    actix_web::rt::spawn(async move {
+        guard
+            .clean_periodically(&guard_pool, Duration::from_secs(3600))
+            .await
+    });
+
+    // init email verification of password registrations, emails are written to files without SMTP
+    let mailer: Arc<dyn Mailer> = match &config.smtp_url {
+        Some(url) => Arc::new(SmtpMailer::new(url).expect("Failed to set up SMTP mailer")),
+        None => Arc::new(FileMailer::new(config.mail_dir.clone())),
+    };
+    let email_verifier = Arc::new(EmailVerifier::new(
+        mailer,
+        &config.mail_from,
+        &config.email_verification_url,
+        Duration::from_secs(config.email_verification_ttl_hours * 3600),
+        Duration::from_secs(config.email_verification_resend_secs),
+    ));
+    let verifier = email_verifier.clone();
+    let verifier_pool = pool.clone();
+    actix_web::rt::spawn(async move {
+        verifier
+            .clean_periodically(&verifier_pool, Duration::from_secs(3600))
+            .await
+    });
+
+    // init cache of the keys OAuth providers sign ID tokens with
+    let jwks_cache = Arc::new(JwksCache::new(Duration::from_secs(3600)));
+
+    // init generic OpenID Connect providers, discovered on first use
+    let oidc_registry = Arc::new(OidcRegistry::new(
+        config.oidc_providers.clone(),
+        Duration::from_secs(3600),
+    ));
+
+    // init API key usage tracker
+    let key_tracker = Arc::new(KeyUsageTracker::new());
+    let tracker = key_tracker.clone();
+    let tracker_pool = pool.clone();
+    let flush_period = Duration::from_secs(config.key_usage_flush_secs);
+    actix_web::rt::spawn(async move {
+        tracker
+            .flush_periodically(&tracker_pool, flush_period)
+            .await
+    });
+    let tracker = key_tracker.clone();
+    let tracker_pool = pool.clone();
+    actix_web::rt::spawn(async move {
+        tracker
+            .clean_periodically(&tracker_pool, Duration::from_secs(3600))
+            .await
+    });
+    let shutdown_tracker = key_tracker.clone();
+    let shutdown_pool = pool.clone();
 
     HttpServer::new(move || {
         let secret = config_data.jwt_config.secret.as_bytes();
-        let redis_client = redis_client.clone();
-        let plans_data = plans.clone();
         App::new()
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::new(config_data.clone()))
-            .wrap(limiter::global_middleware(10)) // max 10 requests per second
+            .app_data(web::Data::new(key_cache.clone()))
+            .app_data(web::Data::new(key_tracker.clone()))
+            .app_data(web::Data::new(sub_cache.clone()))
+            .app_data(web::Data::new(plan_registry.clone()))
+            .app_data(web::Data::new(login_guard.clone()))
+            .app_data(web::Data::new(email_verifier.clone()))
+            .app_data(web::Data::new(jwks_cache.clone()))
+            .app_data(web::Data::new(oidc_registry.clone()))
             .wrap(logger::middleware()) // 4th
             .wrap(extractor::middleware()) // 3rd
             .wrap(cors::middleware(&origin)) // 2nd
@@ -55,11 +217,18 @@ async fn main() -> std::io::Result<()> {
             )) // 1st
             .service(
                 web::scope("/api")
-                    .service(api_auth::mount_auth())
-                    .service(api_subs::mount_webhook())
+                    .service(
+                        api_auth::mount_auth()
+                            .wrap(limiter::global_middleware(auth_limiter.clone())),
+                    )
+                    .service(
+                        api_subs::mount_webhook()
+                            .wrap(limiter::ip_middleware(webhook_limiter.clone())),
+                    )
                     .service(
                         web::scope("/dashboard")
-                            .wrap(api_auth::auth_middleware())
+                            .wrap(api_auth::auth_middleware()) // 2nd
+                            .wrap(limiter::global_middleware(dashboard_limiter.clone())) // 1st
                             .service(api_auth::mount_user())
                             .service(api_subs::mount_pay())
                             .service(api_subs::mount_subs())
@@ -67,8 +236,17 @@ async fn main() -> std::io::Result<()> {
                     )
                     .service(
                         web::scope("/v1")
-                            .wrap(api_keys::middleware())
-                            .wrap(limiter::quota_middleware(plans_data, redis_client))
+                            .wrap(limiter::quota_middleware(
+                                plan_registry.clone(),
+                                quota_store.clone(),
+                                sub_cache.clone(),
+                                config_data.quota_billing_cycle_anchored,
+                                quota_costs.clone(),
+                                concurrency_limiter.clone(),
+                            )) // 4th
+                            .wrap(limiter::key_owner_middleware(api_limiter.clone())) // 3rd
+                            .wrap(api_keys::middleware()) // 2nd
+                            .wrap(limiter::ip_middleware(api_limiter.clone())) // 1st
                             .service(checker::mount_checker()),
                     ),
             )
@@ -76,5 +254,9 @@ async fn main() -> std::io::Result<()> {
     .bind((config.server_host.as_str(), config.server_port))?
     .workers(config.num_workers)
     .run()
-    .await
+    .await?;
+
+    // write usage recorded since the last flush
+    shutdown_tracker.flush(&shutdown_pool).await;
+    Ok(())
 }
@@ -0,0 +1,3 @@
+-- Remove public id and plan id columns
+ALTER TABLE api_keys DROP COLUMN plan_id;
+ALTER TABLE api_keys DROP COLUMN public_id;
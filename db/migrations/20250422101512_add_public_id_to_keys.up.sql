@@ -0,0 +1,6 @@
+-- Public identifier of opaque keys (sk_live_/sk_test_), used to look up the key record.
+-- Legacy base64 keys have no public identifier and are looked up by id.
+ALTER TABLE api_keys ADD COLUMN public_id VARCHAR(12) UNIQUE;
+
+-- Plan the key was issued for. Legacy keys carry the plan in the key itself.
+ALTER TABLE api_keys ADD COLUMN plan_id VARCHAR(255);
@@ -0,0 +1,3 @@
+-- Remove expiry and rotation columns
+ALTER TABLE api_keys DROP COLUMN rotated_from;
+ALTER TABLE api_keys DROP COLUMN expires_at;
@@ -0,0 +1,5 @@
+-- Optional expiry. Rotated keys expire at the end of their grace period.
+ALTER TABLE api_keys ADD COLUMN expires_at TIMESTAMP;
+
+-- Key this key replaced on rotation. A key can only be rotated once.
+ALTER TABLE api_keys ADD COLUMN rotated_from UUID UNIQUE REFERENCES api_keys(id) ON DELETE SET NULL;
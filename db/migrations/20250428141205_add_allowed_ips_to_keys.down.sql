@@ -0,0 +1,2 @@
+-- Remove allowed IPs column
+ALTER TABLE api_keys DROP COLUMN allowed_ips;
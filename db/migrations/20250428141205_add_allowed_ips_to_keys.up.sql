@@ -0,0 +1,2 @@
+-- CIDR ranges the key may be used from. NULL allows any address.
+ALTER TABLE api_keys ADD COLUMN allowed_ips CIDR[];
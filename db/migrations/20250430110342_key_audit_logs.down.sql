@@ -0,0 +1,2 @@
+-- Remove API key audit logs
+DROP TABLE api_key_audit_logs;
@@ -0,0 +1,4 @@
+-- Remove usage tracking
+DROP TABLE api_key_daily_usage;
+ALTER TABLE api_keys DROP COLUMN last_used_ip;
+ALTER TABLE api_keys DROP COLUMN last_used_at;
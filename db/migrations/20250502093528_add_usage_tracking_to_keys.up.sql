@@ -0,0 +1,11 @@
+-- Usage of the key, written in batches by the key usage tracker
+ALTER TABLE api_keys ADD COLUMN last_used_at TIMESTAMP;
+ALTER TABLE api_keys ADD COLUMN last_used_ip INET;
+
+-- Requests made with a key per day, summed over a rolling window when keys are listed
+CREATE TABLE api_key_daily_usage (
+    key_id UUID NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    request_count BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (key_id, day)
+);
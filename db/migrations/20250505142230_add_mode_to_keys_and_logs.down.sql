@@ -0,0 +1,3 @@
+-- Remove mode columns
+ALTER TABLE logs DROP COLUMN mode;
+ALTER TABLE api_keys DROP COLUMN mode;
@@ -0,0 +1,6 @@
+-- Mode of the key, 'live' or 'test'. Existing keys are live keys.
+ALTER TABLE api_keys ADD COLUMN mode VARCHAR(10) NOT NULL DEFAULT 'live';
+
+-- Mode of the key the request was made with, NULL for requests without a key
+ALTER TABLE logs ADD COLUMN mode VARCHAR(10);
+UPDATE logs SET mode = 'live' WHERE key_id IS NOT NULL;
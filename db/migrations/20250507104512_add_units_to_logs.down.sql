@@ -0,0 +1,2 @@
+-- Remove units column
+ALTER TABLE logs DROP COLUMN units;
@@ -0,0 +1,2 @@
+-- Quota units consumed by the request, NULL if it was not counted
+ALTER TABLE logs ADD COLUMN units BIGINT;
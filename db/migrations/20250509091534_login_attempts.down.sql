@@ -0,0 +1,2 @@
+-- Remove failed login attempts
+DROP TABLE login_attempts;
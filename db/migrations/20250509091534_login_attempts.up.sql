@@ -0,0 +1,7 @@
+-- Failed login attempts, per account email and per client IP
+CREATE TABLE login_attempts (
+    subject VARCHAR(255) PRIMARY KEY, -- 'email:<email>' or 'ip:<address>'
+    failures INT NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    locked_until TIMESTAMP
+);
@@ -0,0 +1,2 @@
+-- Remove the link time of external providers
+ALTER TABLE auth_providers DROP COLUMN created_at;
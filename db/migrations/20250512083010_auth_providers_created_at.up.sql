@@ -0,0 +1,2 @@
+-- When an external provider was linked to the user
+ALTER TABLE auth_providers ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
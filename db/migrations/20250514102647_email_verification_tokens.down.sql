@@ -0,0 +1,2 @@
+-- Remove pending email verifications
+DROP TABLE email_verification_tokens;
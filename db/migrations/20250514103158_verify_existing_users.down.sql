@@ -0,0 +1,3 @@
+-- The users verified by the up migration can't be told apart from users who verified
+-- their email since, so they are left verified
+SELECT 1;
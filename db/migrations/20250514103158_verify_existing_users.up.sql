@@ -0,0 +1,6 @@
+-- Password users registered before email verification existed could never verify,
+-- treat their emails as verified so restricted actions stay available to them
+UPDATE users
+SET verified = TRUE, updated_at = CURRENT_TIMESTAMP
+WHERE verified = FALSE
+    AND id IN (SELECT user_id FROM auth_credentials);
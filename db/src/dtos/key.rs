@@ -1,4 +1,4 @@
-use sqlx::types::JsonValue;
+use sqlx::types::{JsonValue, chrono::NaiveDateTime, ipnetwork::IpNetwork};
 use uuid::Uuid;
 
 pub struct KeyCreateRequest {
@@ -6,9 +6,30 @@ pub struct KeyCreateRequest {
     pub key_encrypted: String,
     pub name: String,
     pub permissions: JsonValue,
+    pub public_id: String,
+    pub plan_id: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub rotated_from: Option<Uuid>,
+    pub mode: String,
+    pub allowed_ips: Option<Vec<IpNetwork>>,
 }
 
 pub struct KeyUpdateRequest {
     pub name: Option<String>,
     pub permissions: Option<JsonValue>,
-}
\ No newline at end of file
+}
+
+pub struct KeyAuditLogCreateRequest {
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub action: String,
+    pub changes: JsonValue,
+}
+
+/// Usage of a key accumulated since the last write.
+pub struct KeyUsageUpdate {
+    pub key_id: Uuid,
+    pub last_used_at: NaiveDateTime,
+    pub last_used_ip: Option<IpNetwork>,
+    pub request_count: i64,
+}
@@ -3,6 +3,7 @@ use uuid::Uuid;
 pub struct ReportFilter {
     pub user_id: Option<Uuid>,
     pub key_id: Option<Uuid>,
+    pub mode: Option<String>,
     pub method: Option<String>,
     pub code: Option<i32>,
     pub path: Option<String>,
@@ -7,6 +7,8 @@ pub struct UserCreateRequest {
     pub last_name: String,
     pub company_name: Option<String>,
     pub verification_origin: UserVerificationOrigin,
+    /// Whether the user owns the email, e.g. an OAuth provider verified it.
+    pub verified: bool,
     pub stripe_customer_id: String,
 }
 pub struct AuthProviderCreateRequest {
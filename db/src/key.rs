@@ -1,12 +1,20 @@
 use common::error::{AppError, Res};
-use sqlx::{Executor, Postgres};
+use sqlx::{
+    Executor, Postgres,
+    types::{
+        chrono::{NaiveDate, NaiveDateTime},
+        ipnetwork::IpNetwork,
+    },
+};
 use uuid::Uuid;
 
 use crate::{
-    dtos::key::KeyCreateRequest,
-    models::key::ApiKey,
+    dtos::key::{KeyAuditLogCreateRequest, KeyCreateRequest, KeyUpdateRequest, KeyUsageUpdate},
+    models::key::{ApiKey, KeyAuditLog, KeyRequestCount},
 };
 
+/// Not scoped to an owner, only used to authenticate a presented key.
+/// Use `get_user_key_by_id` when acting on behalf of a user.
 pub async fn get_key_by_id<'e, E: Executor<'e, Database = Postgres>>(
     executor: E,
     key_id: &Uuid,
@@ -17,6 +25,54 @@ pub async fn get_key_by_id<'e, E: Executor<'e, Database = Postgres>>(
         .map_err(AppError::from)
 }
 
+/// Not scoped to an owner, only used to authenticate a presented key.
+pub async fn get_key_by_public_id<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    public_id: &str,
+) -> Res<ApiKey> {
+    sqlx::query_as!(
+        ApiKey,
+        "SELECT * FROM api_keys WHERE public_id = $1",
+        public_id
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn get_user_key_by_id<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    key_id: &Uuid,
+    user_id: &Uuid,
+) -> Res<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "SELECT * FROM api_keys WHERE id = $1 AND user_id = $2",
+        key_id,
+        user_id
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Same as `get_user_key_by_id`, but locks the key until the transaction ends.
+pub async fn lock_user_key_by_id<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    key_id: &Uuid,
+    user_id: &Uuid,
+) -> Res<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "SELECT * FROM api_keys WHERE id = $1 AND user_id = $2 FOR UPDATE",
+        key_id,
+        user_id
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
 pub async fn get_keys_by_user_id<'e, E: Executor<'e, Database = Postgres>>(
     executor: E,
     user_id: &Uuid,
@@ -44,34 +100,220 @@ pub async fn insert_key<'e, E: Executor<'e, Database = Postgres>>(
     sqlx::query_as!(
         ApiKey,
         r#"
-        INSERT INTO api_keys (user_id, key_encrypted, name, status, permissions)
-        VALUES ($1, $2, $3, $4, $5)
+        INSERT INTO api_keys (user_id, key_encrypted, name, status, permissions, public_id, plan_id, expires_at, rotated_from, mode, allowed_ips)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
         RETURNING *
         "#,
         data.user_id,
         data.key_encrypted,
         data.name,
         "active",
-        data.permissions
+        data.permissions,
+        data.public_id,
+        data.plan_id,
+        data.expires_at,
+        data.rotated_from,
+        data.mode,
+        data.allowed_ips.as_deref() as Option<&[IpNetwork]>
     )
     .fetch_one(executor)
     .await
     .map_err(AppError::from)
 }
 
+/// Callers must also invalidate the key in the verified key cache (`api_keys::cache::key::KeyCache`).
 pub async fn update_key_status<'e, E: Executor<'e, Database = Postgres>>(
     executor: E,
-    key_id: Uuid,
-    status: &str
-) -> Res<ApiKey> {
-    let updated_key = sqlx::query_as!(ApiKey,
-        "UPDATE api_keys SET status = $1 WHERE id = $2 RETURNING *",
+    key_id: &Uuid,
+    user_id: &Uuid,
+    status: &str,
+) -> Res<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "UPDATE api_keys SET status = $1 WHERE id = $2 AND user_id = $3 RETURNING *",
         status,
         key_id,
+        user_id,
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Callers must also invalidate the key in the verified key cache (`api_keys::cache::key::KeyCache`).
+pub async fn update_key_expiry<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    key_id: &Uuid,
+    user_id: &Uuid,
+    expires_at: NaiveDateTime,
+) -> Res<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "UPDATE api_keys SET expires_at = $1 WHERE id = $2 AND user_id = $3 RETURNING *",
+        expires_at,
+        key_id,
+        user_id,
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn get_key_by_rotated_from<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    key_id: &Uuid,
+) -> Res<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "SELECT * FROM api_keys WHERE rotated_from = $1",
+        key_id
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Callers must also invalidate the key in the verified key cache (`api_keys::cache::key::KeyCache`).
+pub async fn update_key_allowed_ips<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    key_id: &Uuid,
+    user_id: &Uuid,
+    allowed_ips: Option<&[IpNetwork]>,
+) -> Res<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        "UPDATE api_keys SET allowed_ips = $1 WHERE id = $2 AND user_id = $3 RETURNING *",
+        allowed_ips as Option<&[IpNetwork]>,
+        key_id,
+        user_id,
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Only updates the provided fields.
+/// Callers must also invalidate the key in the verified key cache (`api_keys::cache::key::KeyCache`).
+pub async fn update_key<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    key_id: &Uuid,
+    user_id: &Uuid,
+    data: KeyUpdateRequest,
+) -> Res<Option<ApiKey>> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+        UPDATE api_keys
+        SET name = COALESCE($1, name),
+            permissions = COALESCE($2, permissions)
+        WHERE id = $3 AND user_id = $4
+        RETURNING *
+        "#,
+        data.name,
+        data.permissions,
+        key_id,
+        user_id,
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+pub async fn insert_key_audit_log<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    data: KeyAuditLogCreateRequest,
+) -> Res<KeyAuditLog> {
+    sqlx::query_as!(
+        KeyAuditLog,
+        r#"
+        INSERT INTO api_key_audit_logs (key_id, user_id, action, changes)
+        VALUES ($1, $2, $3, $4)
+        RETURNING *
+        "#,
+        data.key_id,
+        data.user_id,
+        data.action,
+        data.changes
     )
     .fetch_one(executor)
     .await
+    .map_err(AppError::from)
+}
+
+/// Applies accumulated usage of many keys in a single query. Requests are counted
+/// in the daily bucket of their `last_used_at`.
+pub async fn update_keys_usage<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    updates: &[KeyUsageUpdate],
+) -> Res<()> {
+    let key_ids: Vec<Uuid> = updates.iter().map(|u| u.key_id).collect();
+    let last_used_ats: Vec<NaiveDateTime> = updates.iter().map(|u| u.last_used_at).collect();
+    let last_used_ips: Vec<Option<IpNetwork>> = updates.iter().map(|u| u.last_used_ip).collect();
+    let request_counts: Vec<i64> = updates.iter().map(|u| u.request_count).collect();
+
+    sqlx::query!(
+        r#"
+        WITH u AS (
+            SELECT * FROM UNNEST($1::uuid[], $2::timestamp[], $3::inet[], $4::bigint[])
+                AS u(id, last_used_at, last_used_ip, request_count)
+        ), updated AS (
+            UPDATE api_keys AS k
+            SET last_used_at = GREATEST(k.last_used_at, u.last_used_at),
+                last_used_ip = COALESCE(u.last_used_ip, k.last_used_ip)
+            FROM u
+            WHERE k.id = u.id
+            RETURNING k.id
+        )
+        INSERT INTO api_key_daily_usage (key_id, day, request_count)
+        SELECT u.id, u.last_used_at::date, u.request_count
+        FROM u JOIN updated ON updated.id = u.id
+        ON CONFLICT (key_id, day)
+        DO UPDATE SET request_count = api_key_daily_usage.request_count + EXCLUDED.request_count
+        "#,
+        &key_ids,
+        &last_used_ats,
+        &last_used_ips as &[Option<IpNetwork>],
+        &request_counts
+    )
+    .execute(executor)
+    .await
     .map_err(AppError::from)?;
 
-    Ok(updated_key)
+    Ok(())
+}
+
+/// Gets the number of requests made with each key of a user since `since` (inclusive).
+/// Keys without requests in that window are left out.
+pub async fn get_request_counts_by_user_id<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: &Uuid,
+    since: NaiveDate,
+) -> Res<Vec<KeyRequestCount>> {
+    sqlx::query_as!(
+        KeyRequestCount,
+        r#"
+        SELECT u.key_id, SUM(u.request_count)::bigint AS "request_count!"
+        FROM api_key_daily_usage AS u
+        JOIN api_keys AS k ON k.id = u.key_id
+        WHERE k.user_id = $1 AND u.day >= $2
+        GROUP BY u.key_id
+        "#,
+        user_id,
+        since
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Deletes the daily usage of keys before `before`, returns the number of deleted days.
+pub async fn delete_key_usage_before<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    before: NaiveDate,
+) -> Res<u64> {
+    let result = sqlx::query!("DELETE FROM api_key_daily_usage WHERE day < $1", before)
+        .execute(executor)
+        .await
+        .map_err(AppError::from)?;
+    Ok(result.rows_affected())
 }
@@ -7,6 +7,8 @@ use std::{str::FromStr, sync::Arc};
 pub mod log;
 pub mod user;
 pub mod key;
+pub mod login;
+pub mod verification;
 
 pub mod models {
     pub mod key;
@@ -29,6 +29,11 @@ where
         qb.push("key_id = ").push_bind(key_id);
     }
 
+    if let Some(mode) = filter.mode {
+        add_condition_separator(&mut qb);
+        qb.push("mode = ").push_bind(mode);
+    }
+
     if let Some(method) = filter.method {
         add_condition_separator(&mut qb);
         qb.push("method = ").push_bind(method);
@@ -68,8 +73,8 @@ pub async fn insert_log<'e, E: Executor<'e, Database = Postgres>>(
     log: Log,
 ) -> Res<()> {
     sqlx::query(
-        "INSERT INTO logs (timestamp, method, path, status_code, user_id, params, key_id, request_body, response_body, ip_address, user_agent) 
-         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)"
+        "INSERT INTO logs (timestamp, method, path, status_code, user_id, params, key_id, request_body, response_body, ip_address, user_agent, mode, units) 
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)"
     )
     .bind(log.timestamp)
     .bind(&log.method)
@@ -82,6 +87,8 @@ pub async fn insert_log<'e, E: Executor<'e, Database = Postgres>>(
     .bind(log.response_body)
     .bind(log.ip_address)
     .bind(log.user_agent)
+    .bind(log.mode)
+    .bind(log.units)
     .execute(executor)
     .await
     .map_err(AppError::from)?;
@@ -0,0 +1,121 @@
+use chrono::NaiveDateTime;
+use common::error::{AppError, Res};
+use sqlx::{Executor, Postgres};
+
+/// Gets the latest lock of the given subjects that has not expired yet.
+///
+/// # Arguments
+///
+/// * `executor` - The database executor.
+/// * `subjects` - The subjects to check, e.g. `email:<email>` and `ip:<address>`.
+///
+/// # Returns
+///
+/// A `Result` containing the end of the lock, or `None` if no subject is locked.
+pub async fn get_active_lock<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    subjects: &[String],
+) -> Res<Option<NaiveDateTime>> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT MAX(locked_until)
+        FROM login_attempts
+        WHERE subject = ANY($1) AND locked_until > CURRENT_TIMESTAMP
+        "#,
+        subjects
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Records a failed login attempt of a subject.
+/// Failures older than `reset_secs` are forgotten.
+///
+/// # Returns
+///
+/// A `Result` containing the number of consecutive failures, including this one.
+pub async fn record_login_failure<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    subject: &str,
+    reset_secs: i64,
+) -> Res<i32> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO login_attempts (subject, failures, last_failure_at)
+        VALUES ($1, 1, CURRENT_TIMESTAMP)
+        ON CONFLICT (subject) DO UPDATE SET
+            failures = CASE
+                WHEN login_attempts.last_failure_at < CURRENT_TIMESTAMP - make_interval(secs => $2)
+                THEN 1
+                ELSE login_attempts.failures + 1
+            END,
+            last_failure_at = CURRENT_TIMESTAMP
+        RETURNING failures
+        "#,
+        subject,
+        reset_secs as f64
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Locks a subject for `lock_secs` seconds.
+///
+/// # Returns
+///
+/// A `Result` containing the end of the lock.
+pub async fn lock_login_subject<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    subject: &str,
+    lock_secs: i64,
+) -> Res<NaiveDateTime> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE login_attempts
+        SET locked_until = CURRENT_TIMESTAMP + make_interval(secs => $2)
+        WHERE subject = $1
+        RETURNING locked_until as "locked_until!"
+        "#,
+        subject,
+        lock_secs as f64
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Forgets the failed login attempts of a subject.
+pub async fn clear_login_attempts<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    subject: &str,
+) -> Res<()> {
+    sqlx::query!("DELETE FROM login_attempts WHERE subject = $1", subject)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Deletes the attempts of subjects that are not locked and whose failures are older than `reset_secs`.
+///
+/// # Returns
+///
+/// A `Result` containing the number of deleted subjects.
+pub async fn delete_stale_login_attempts<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    reset_secs: i64,
+) -> Res<u64> {
+    sqlx::query!(
+        r#"
+        DELETE FROM login_attempts
+        WHERE last_failure_at < CURRENT_TIMESTAMP - make_interval(secs => $1)
+            AND (locked_until IS NULL OR locked_until < CURRENT_TIMESTAMP)
+        "#,
+        reset_secs as f64
+    )
+    .execute(executor)
+    .await
+    .map(|result| result.rows_affected())
+    .map_err(AppError::from)
+}
@@ -1,9 +1,10 @@
 use chrono::NaiveDateTime;
-use serde::Serialize;
-use sqlx::types::JsonValue;
+use common::key::{KeyMode, Scope};
+use serde::{Deserialize, Serialize};
+use sqlx::types::{JsonValue, ipnetwork::IpNetwork};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
 pub struct ApiKey {
     pub id: Uuid,
     pub user_id: Uuid,
@@ -12,4 +13,62 @@ pub struct ApiKey {
     pub status: String,
     pub created_at: NaiveDateTime,
     pub permissions: JsonValue,
+    pub public_id: Option<String>,
+    pub plan_id: Option<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    /// Key this key replaced on rotation.
+    pub rotated_from: Option<Uuid>,
+    /// CIDR ranges the key may be used from. `None` allows any address.
+    pub allowed_ips: Option<Vec<IpNetwork>>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub last_used_ip: Option<IpNetwork>,
+    /// `live` or `test`, see `KeyMode`.
+    pub mode: String,
+}
+
+/// API key verified against the database and its owner.
+/// Inserted into request extensions by the key middleware.
+///
+/// Holds only the fields of the key record needed to authorize requests,
+/// never the hash of its secret.
+#[derive(Debug, Clone)]
+pub struct VerifiedKey {
+    pub id: Uuid,
+    pub owner: KeyOwner,
+    /// Plan the key was issued for.
+    pub plan_id: String,
+    /// Scopes granted to the key, parsed from its permissions.
+    pub scopes: Vec<Scope>,
+    pub mode: KeyMode,
+    pub status: String,
+    pub expires_at: Option<NaiveDateTime>,
+    /// CIDR ranges the key may be used from. `None` allows any address.
+    pub allowed_ips: Option<Vec<IpNetwork>>,
+}
+
+/// Owner of a verified key. Holds only what is needed to authorize and bill
+/// requests, so it can be cached without the owner's personal details.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyOwner {
+    pub id: Uuid,
+    pub stripe_customer_id: String,
+}
+
+/// Requests made with a key within a window, see `api_key_daily_usage`.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct KeyRequestCount {
+    pub key_id: Uuid,
+    pub request_count: i64,
+}
+
+/// Change made to an API key by its owner.
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct KeyAuditLog {
+    pub id: Uuid,
+    pub key_id: Uuid,
+    pub user_id: Uuid,
+    pub action: String,
+    /// Changed fields as `{ "<field>": { "from": ..., "to": ... } }`.
+    pub changes: JsonValue,
+    pub created_at: NaiveDateTime,
 }
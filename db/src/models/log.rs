@@ -17,4 +17,12 @@ pub struct Log {
     pub response_body: Option<JsonValue>,
     pub ip_address: IpNetwork,
     pub user_agent: String,
+    /// Mode of the key the request was made with, `None` for requests without a key.
+    pub mode: Option<String>,
+    /// Quota units consumed by the request, `None` if it was not counted.
+    pub units: Option<i64>,
 }
+
+/// Quota units consumed by a request, inserted into request extensions by the quota middleware.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsumedUnits(pub u64);
@@ -21,3 +21,12 @@ pub struct AuthCredentials {
     pub user_id: Uuid,
     pub password_hash: String,
 }
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+pub struct AuthProvider {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub provider: String,
+    pub provider_user_id: String,
+    pub created_at: NaiveDateTime,
+}
@@ -1,13 +1,10 @@
-use common::{
-    error::{AppError, Res},
-    misc::UserVerificationOrigin,
-};
+use common::error::{AppError, Res};
 use sqlx::{Executor, Postgres};
 use uuid::Uuid;
 
 use crate::{
     dtos::user::{AuthProviderCreateRequest, UserCreateRequest},
-    models::user::{AuthCredentials, User},
+    models::user::{AuthCredentials, AuthProvider, User},
 };
 
 pub async fn exists_user_by_email<'e, E: Executor<'e, Database = Postgres>>(
@@ -46,7 +43,6 @@ pub async fn insert_user<'e, E: Executor<'e, Database = Postgres>>(
     executor: E,
     data: UserCreateRequest,
 ) -> Res<User> {
-    let verified = data.verification_origin == UserVerificationOrigin::OAuth;
     sqlx::query_as!(
         User,
         r#"
@@ -59,7 +55,7 @@ pub async fn insert_user<'e, E: Executor<'e, Database = Postgres>>(
         data.last_name,
         data.company_name,
         data.verification_origin.to_string(),
-        verified,
+        data.verified,
         data.stripe_customer_id
     )
     .fetch_one(executor)
@@ -67,21 +63,147 @@ pub async fn insert_user<'e, E: Executor<'e, Database = Postgres>>(
     .map_err(AppError::from)
 }
 
+/// Marks the email of a user as verified.
+pub async fn set_user_verified<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+) -> Res<User> {
+    sqlx::query_as!(
+        User,
+        r#"
+        UPDATE users
+        SET verified = TRUE, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+        RETURNING *
+        "#,
+        user_id
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Checks if a user verified their email.
+pub async fn is_user_verified<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+) -> Res<bool> {
+    sqlx::query_scalar!("SELECT verified FROM users WHERE id = $1", user_id)
+        .fetch_one(executor)
+        .await
+        .map_err(AppError::from)
+}
+
 pub async fn insert_user_with_provider<'e, E: Executor<'e, Database = Postgres>>(
     executor: E,
     data: AuthProviderCreateRequest,
-) -> Res<()> {
-    sqlx::query!(
+) -> Res<AuthProvider> {
+    sqlx::query_as!(
+        AuthProvider,
         r#"
         INSERT INTO auth_providers (user_id, provider, provider_user_id)
         VALUES ($1, $2, $3)
+        RETURNING *
         "#,
         data.user_id,
         data.provider,
         data.provider_user_id
     )
+    .fetch_one(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Gets the user an external provider account is linked to.
+pub async fn get_user_by_provider<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    provider: &str,
+    provider_user_id: &str,
+) -> Res<Option<User>> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT u.*
+        FROM users u
+        JOIN auth_providers ap ON u.id = ap.user_id
+        WHERE ap.provider = $1 AND ap.provider_user_id = $2
+        "#,
+        provider,
+        provider_user_id
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Gets the external provider accounts linked to a user, oldest first.
+pub async fn get_auth_providers<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+) -> Res<Vec<AuthProvider>> {
+    sqlx::query_as!(
+        AuthProvider,
+        "SELECT * FROM auth_providers WHERE user_id = $1 ORDER BY created_at",
+        user_id
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Unlinks an external provider account of a user.
+/// Returns `false` if the user has no such provider account.
+pub async fn delete_auth_provider<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+    provider_id: Uuid,
+) -> Res<bool> {
+    sqlx::query!(
+        "DELETE FROM auth_providers WHERE id = $1 AND user_id = $2",
+        provider_id,
+        user_id
+    )
     .execute(executor)
-    .await?;
+    .await
+    .map(|result| result.rows_affected() > 0)
+    .map_err(AppError::from)
+}
+
+/// Checks if a user can log in with a password.
+pub async fn has_credentials<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+) -> Res<bool> {
+    sqlx::query!(
+        "SELECT EXISTS(SELECT 1 FROM auth_credentials WHERE user_id = $1) as exists",
+        user_id
+    )
+    .fetch_one(executor)
+    .await
+    .map(|row| row.exists.unwrap_or(false))
+    .map_err(AppError::from)
+}
+
+/// Locks the row of a user until the end of the transaction, so that changes
+/// to their login methods are serialized.
+pub async fn lock_user<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+) -> Res<()> {
+    sqlx::query!("SELECT id FROM users WHERE id = $1 FOR UPDATE", user_id)
+        .fetch_one(executor)
+        .await?;
+    Ok(())
+}
+
+/// Removes the password of a user, who can then only log in with a linked provider.
+pub async fn delete_credentials<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+) -> Res<()> {
+    sqlx::query!("DELETE FROM auth_credentials WHERE user_id = $1", user_id)
+        .execute(executor)
+        .await?;
     Ok(())
 }
 
@@ -102,10 +224,12 @@ pub async fn insert_user_with_credentials<'e, E: Executor<'e, Database = Postgre
     Ok(())
 }
 
+/// Gets a user and their password hash by email.
+/// Returns `None` if there is no such user or they have no password (OAuth only).
 pub async fn get_user_with_password_hash<'e, E: Executor<'e, Database = Postgres>>(
     executor: E,
     email: String,
-) -> Res<(User, AuthCredentials)> {
+) -> Res<Option<(User, AuthCredentials)>> {
     sqlx::query!(
         r#"
         SELECT u.*, ac.password_hash
@@ -115,27 +239,29 @@ pub async fn get_user_with_password_hash<'e, E: Executor<'e, Database = Postgres
         "#,
         email
     )
-    .fetch_one(executor)
+    .fetch_optional(executor)
     .await
     .map(|record| {
-        (
-            User {
-                id: record.id,
-                email: record.email,
-                first_name: record.first_name,
-                last_name: record.last_name,
-                company_name: record.company_name,
-                created_at: record.created_at,
-                updated_at: record.updated_at,
-                verification_origin: record.verification_origin,
-                verified: record.verified,
-                stripe_customer_id: record.stripe_customer_id,
-            },
-            AuthCredentials {
-                user_id: record.id,
-                password_hash: record.password_hash,
-            },
-        )
+        record.map(|record| {
+            (
+                User {
+                    id: record.id,
+                    email: record.email,
+                    first_name: record.first_name,
+                    last_name: record.last_name,
+                    company_name: record.company_name,
+                    created_at: record.created_at,
+                    updated_at: record.updated_at,
+                    verification_origin: record.verification_origin,
+                    verified: record.verified,
+                    stripe_customer_id: record.stripe_customer_id,
+                },
+                AuthCredentials {
+                    user_id: record.id,
+                    password_hash: record.password_hash,
+                },
+            )
+        })
     })
     .map_err(AppError::from)
 }
@@ -0,0 +1,83 @@
+use common::error::{AppError, Res};
+use sqlx::{Executor, Postgres};
+use uuid::Uuid;
+
+/// Stores a new email verification token of a user, replacing the previous one.
+/// The token is not replaced if the previous one was created less than `resend_secs` ago.
+///
+/// # Arguments
+///
+/// * `executor` - The database executor.
+/// * `user_id` - The ID of the user.
+/// * `token_hash` - The SHA-256 hash of the token, hex encoded.
+/// * `ttl_secs` - Seconds the token is valid.
+/// * `resend_secs` - Minimum seconds between two tokens of the user.
+///
+/// # Returns
+///
+/// A `Result` containing `false` if the token was not stored because the previous one is too recent.
+pub async fn insert_verification_token<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    user_id: Uuid,
+    token_hash: &str,
+    ttl_secs: i64,
+    resend_secs: i64,
+) -> Res<bool> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_verification_tokens (user_id, token_hash, expires_at)
+        VALUES ($1, $2, CURRENT_TIMESTAMP + make_interval(secs => $3))
+        ON CONFLICT (user_id) DO UPDATE SET
+            token_hash = EXCLUDED.token_hash,
+            expires_at = EXCLUDED.expires_at,
+            created_at = CURRENT_TIMESTAMP
+        WHERE email_verification_tokens.created_at <= CURRENT_TIMESTAMP - make_interval(secs => $4)
+        "#,
+        user_id,
+        token_hash,
+        ttl_secs as f64,
+        resend_secs as f64
+    )
+    .execute(executor)
+    .await
+    .map(|result| result.rows_affected() > 0)
+    .map_err(AppError::from)
+}
+
+/// Deletes the verification token with the given hash, so it can only be used once.
+///
+/// # Returns
+///
+/// A `Result` containing the ID of the user of the token, or `None` if there is no such token
+/// or it has expired.
+pub async fn consume_verification_token<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    token_hash: &str,
+) -> Res<Option<Uuid>> {
+    sqlx::query_scalar!(
+        r#"
+        DELETE FROM email_verification_tokens
+        WHERE token_hash = $1 AND expires_at > CURRENT_TIMESTAMP
+        RETURNING user_id
+        "#,
+        token_hash
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(AppError::from)
+}
+
+/// Deletes the verification tokens that have expired.
+///
+/// # Returns
+///
+/// A `Result` containing the number of deleted tokens.
+pub async fn delete_expired_verification_tokens<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+) -> Res<u64> {
+    sqlx::query!("DELETE FROM email_verification_tokens WHERE expires_at <= CURRENT_TIMESTAMP")
+        .execute(executor)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(AppError::from)
+}
@@ -14,6 +14,7 @@ use common::{
     key::{self, KeyClaims},
 };
 
+#[derive(Default)]
 pub struct ExtractionMiddleware {}
 
 impl ExtractionMiddleware {
@@ -61,13 +62,8 @@ where
             .headers()
             .get("Authorization")
             .and_then(|h| h.to_str().ok())
-            .and_then(|auth_value| {
-                if auth_value.starts_with("Bearer ") {
-                    Some(auth_value[7..].to_owned())
-                } else {
-                    None
-                }
-            });
+            .and_then(|auth_value| auth_value.strip_prefix("Bearer "))
+            .map(|token| token.to_owned());
         // retrieve API key from "X-API-KEY" header
         let api_key = req
             .headers()
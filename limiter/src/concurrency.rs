@@ -0,0 +1,95 @@
+use std::{sync::Arc, time::Duration};
+
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+/// Slot of an in-flight request. The slot is released when dropped, so it is
+/// returned when the request completes, fails, or is dropped on client disconnect.
+pub type ConcurrencySlot = OwnedSemaphorePermit;
+
+struct UserSlots {
+    limit: u32,
+    semaphore: Arc<Semaphore>,
+    /// Permits still to remove after the limit was lowered while they were taken.
+    owed: u32,
+}
+
+impl UserSlots {
+    /// Resizes the semaphore to `limit`, slots in flight keep counting against it.
+    fn resize(&mut self, limit: u32) {
+        if limit > self.limit {
+            let added = limit - self.limit;
+            let repaid = added.min(self.owed);
+            self.owed -= repaid;
+            self.semaphore.add_permits((added - repaid) as usize);
+        } else {
+            self.owed += self.limit - limit;
+        }
+        self.limit = limit;
+    }
+
+    /// Removes the owed permits that were returned since, returns whether none are left.
+    fn settle(&mut self) -> bool {
+        let forgotten = self.semaphore.forget_permits(self.owed as usize);
+        self.owed -= forgotten as u32;
+        self.owed == 0
+    }
+}
+
+/// Limits the requests of a user that are in flight at the same time.
+///
+/// Each user gets a semaphore with as many permits as their plan allows. When
+/// the limit of a user changes, the semaphore is resized: permits are added for
+/// a higher limit, and removed for a lower one as soon as requests in flight
+/// return them, so the user never has more requests in flight than the new limit
+/// allows. The state is per instance, shared by its workers.
+#[derive(Default)]
+pub struct ConcurrencyLimiter {
+    users: DashMap<Uuid, UserSlots>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new() -> Self {
+        ConcurrencyLimiter {
+            users: DashMap::new(),
+        }
+    }
+
+    /// Takes a slot of a user without waiting.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user making the request.
+    /// * `limit` - The number of requests the plan of the user may have in flight, at least 1.
+    ///
+    /// # Returns
+    ///
+    /// The `ConcurrencySlot`, or `None` if all slots of the user are taken.
+    pub fn try_acquire(&self, user_id: Uuid, limit: u32) -> Option<ConcurrencySlot> {
+        let mut slots = self.users.entry(user_id).or_insert_with(|| UserSlots {
+            limit,
+            semaphore: Arc::new(Semaphore::new(limit as usize)),
+            owed: 0,
+        });
+        if slots.limit != limit {
+            slots.resize(limit);
+        }
+        // more requests than the new limit are still in flight
+        if !slots.settle() {
+            return None;
+        }
+        slots.semaphore.clone().try_acquire_owned().ok()
+    }
+
+    /// Drops the semaphores of users without requests in flight, every `period`.
+    pub async fn clean_periodically(&self, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.users.retain(|_, slots| {
+                slots.semaphore.available_permits() < (slots.limit + slots.owed) as usize
+            });
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use actix_web::http::Method;
+use serde_json::Value;
+
+/// Quota units consumed by a request.
+#[derive(Debug, Clone, Copy)]
+pub enum Cost {
+    /// The same number of units for every request.
+    Fixed(u64),
+    /// Computed from the JSON body of the request, e.g. from the batch size.
+    /// `units` receives `Value::Null` if the body is empty or not JSON.
+    /// Bodies larger than `max_bytes` are rejected without being read to the end,
+    /// so it should match the JSON limit of the route.
+    FromBody {
+        max_bytes: usize,
+        units: fn(&Value) -> u64,
+    },
+}
+
+/// Cost of a route, declared by the crate that mounts it.
+#[derive(Debug, Clone)]
+pub struct RouteCost {
+    pub method: Method,
+    /// Path of the route within the `/v1` scope, e.g. `/checker/check-token`.
+    pub path: &'static str,
+    pub cost: Cost,
+}
+
+impl RouteCost {
+    pub fn new(method: Method, path: &'static str, cost: Cost) -> Self {
+        RouteCost { method, path, cost }
+    }
+}
+
+/// Costs of the routes behind the quota limiter.
+/// Routes without a declared cost consume 1 unit.
+#[derive(Debug, Clone, Default)]
+pub struct CostTable {
+    routes: Vec<RouteCost>,
+}
+
+impl CostTable {
+    pub fn new(routes: Vec<RouteCost>) -> Self {
+        CostTable { routes }
+    }
+
+    /// Gets the cost of a request by its method and path within the `/v1` scope.
+    /// Paths are compared as a whole, ignoring a trailing slash.
+    pub fn get(&self, method: &Method, path: &str) -> Cost {
+        let path = path.trim_end_matches('/');
+        self.routes
+            .iter()
+            .find(|route| route.method == method && path == route.path)
+            .map(|route| route.cost)
+            .unwrap_or(Cost::Fixed(1))
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use uuid::Uuid;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const MONTH: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// State of a user in the windows of their plan. Each window holds the time at
+/// which the user's quota is fully replenished, `None` if it already is.
+#[derive(Default)]
+struct UserState {
+    daily: Option<Instant>,
+    monthly: Option<Instant>,
+}
+
+/// Limiters of a plan, rebuilt when the limits of the plan change.
+struct PlanLimiters {
+    daily_limit: u64,
+    monthly_limit: u64,
+    users: DashMap<Uuid, UserState>,
+}
+
+/// Units left in the local limiter after a request was counted.
+/// `None` for unlimited windows, or if the request was free and nothing was checked.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalQuotaUsage {
+    pub daily_remaining: Option<u64>,
+    pub monthly_remaining: Option<u64>,
+}
+
+/// Window of the local limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalQuotaWindow {
+    Daily,
+    Monthly,
+}
+
+impl LocalQuotaWindow {
+    /// Time over which the limit of the window is replenished.
+    fn duration(self) -> Duration {
+        match self {
+            LocalQuotaWindow::Daily => DAY,
+            LocalQuotaWindow::Monthly => MONTH,
+        }
+    }
+}
+
+/// Window exhausted in the local limiter.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalQuotaExceeded {
+    pub window: LocalQuotaWindow,
+    pub retry_after_secs: u64,
+}
+
+/// In-memory quota limiter used while Redis is unavailable.
+///
+/// Each window is a GCRA limiter that allows a burst of the whole limit and
+/// replenishes it evenly over the window, so it approximates the Redis counters
+/// without knowing how much of the quota was already used. Like the Redis script,
+/// both windows are checked before either is counted, so a request rejected by
+/// one window uses nothing of the other.
+/// The state is per instance, with several instances a user may get up to
+/// the limit on each of them.
+#[derive(Default)]
+pub struct LocalQuotaLimiter {
+    plans: DashMap<String, Arc<PlanLimiters>>,
+}
+
+impl LocalQuotaLimiter {
+    pub fn new() -> Self {
+        LocalQuotaLimiter {
+            plans: DashMap::new(),
+        }
+    }
+
+    /// Counts the units of a request of a user against the limits of their plan.
+    /// A limit of 0 means unlimited.
+    pub fn check(
+        &self,
+        plan_id: &str,
+        user_id: Uuid,
+        daily_limit: u64,
+        monthly_limit: u64,
+        units: u64,
+    ) -> Result<LocalQuotaUsage, LocalQuotaExceeded> {
+        if units == 0 {
+            return Ok(LocalQuotaUsage::default());
+        }
+        let limiters = self.plan_limiters(plan_id, daily_limit, monthly_limit);
+        let now = Instant::now();
+
+        // the entry stays locked until both windows are counted
+        let mut state = limiters.users.entry(user_id).or_default();
+        let daily = advance(
+            state.daily,
+            now,
+            daily_limit,
+            LocalQuotaWindow::Daily,
+            units,
+        )?;
+        let monthly = advance(
+            state.monthly,
+            now,
+            monthly_limit,
+            LocalQuotaWindow::Monthly,
+            units,
+        )?;
+
+        let mut usage = LocalQuotaUsage::default();
+        if let Some((replenished_at, remaining)) = daily {
+            state.daily = Some(replenished_at);
+            usage.daily_remaining = Some(remaining);
+        }
+        if let Some((replenished_at, remaining)) = monthly {
+            state.monthly = Some(replenished_at);
+            usage.monthly_remaining = Some(remaining);
+        }
+        Ok(usage)
+    }
+
+    /// Drops the state of users whose quota is fully replenished.
+    pub fn retain_recent(&self) {
+        let now = Instant::now();
+        for limiters in self.plans.iter() {
+            limiters.users.retain(|_, state| {
+                [state.daily, state.monthly]
+                    .into_iter()
+                    .flatten()
+                    .any(|replenished_at| replenished_at > now)
+            });
+        }
+    }
+
+    fn plan_limiters(
+        &self,
+        plan_id: &str,
+        daily_limit: u64,
+        monthly_limit: u64,
+    ) -> Arc<PlanLimiters> {
+        if let Some(limiters) = self.plans.get(plan_id)
+            && limiters.daily_limit == daily_limit
+            && limiters.monthly_limit == monthly_limit
+        {
+            return limiters.clone();
+        }
+
+        let limiters = Arc::new(PlanLimiters {
+            daily_limit,
+            monthly_limit,
+            users: DashMap::new(),
+        });
+        self.plans.insert(plan_id.to_string(), limiters.clone());
+        limiters
+    }
+}
+
+/// Counts `units` in a window without storing it. Returns when the quota will be
+/// fully replenished and the units left, or `None` if the window is unlimited.
+fn advance(
+    replenished_at: Option<Instant>,
+    now: Instant,
+    limit: u64,
+    window: LocalQuotaWindow,
+    units: u64,
+) -> Result<Option<(Instant, u64)>, LocalQuotaExceeded> {
+    if limit == 0 {
+        return Ok(None);
+    }
+    let exceeded = |retry_after: Duration| LocalQuotaExceeded {
+        window,
+        retry_after_secs: retry_after.as_secs().max(1),
+    };
+
+    // each unit is replenished after `interval`, the whole limit after the window
+    let interval = window.duration() / u32::try_from(limit).unwrap_or(u32::MAX);
+    let cost = u32::try_from(units)
+        .ok()
+        .and_then(|units| interval.checked_mul(units))
+        .filter(|cost| *cost <= window.duration())
+        // the cost is larger than the whole limit
+        .ok_or_else(|| exceeded(window.duration()))?;
+
+    let replenished_at = replenished_at.map_or(now, |at| at.max(now)) + cost;
+    let horizon = now + window.duration();
+    if replenished_at > horizon {
+        return Err(exceeded(replenished_at - horizon));
+    }
+    let remaining = (horizon - replenished_at).as_nanos() / interval.as_nanos().max(1);
+    Ok(Some((replenished_at, remaining as u64)))
+}
@@ -0,0 +1,103 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
+
+/// State of a single quota window, as reported to the client.
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaWindow {
+    /// Suffix of the per-window `X-RateLimit-*` headers, e.g. `Day`.
+    pub name: &'static str,
+    pub limit: u64,
+    pub used: u64,
+    /// Length of the window in seconds, reported in `RateLimit-Policy`.
+    pub window_secs: u64,
+    /// Seconds until the window resets.
+    pub reset_secs: u64,
+}
+
+impl QuotaWindow {
+    pub fn remaining(&self) -> u64 {
+        self.limit.saturating_sub(self.used)
+    }
+}
+
+/// Adds the rate limit headers of the given windows to a response.
+///
+/// All values are in quota units, see `cost::Cost`.
+/// Every window gets `X-RateLimit-{Limit,Remaining,Reset}-{name}`. The unsuffixed
+/// `X-RateLimit-*` and IETF draft `RateLimit-*` headers describe the window closest to
+/// exhaustion, or the exhausted one if the request was rejected, in which case
+/// `Retry-After` is set as well. `X-RateLimit-Reset` is a Unix timestamp, while
+/// `RateLimit-Reset` and `Retry-After` are in seconds.
+///
+/// # Arguments
+///
+/// * `headers` - The response headers.
+/// * `windows` - The limited windows, unlimited ones must be left out.
+/// * `exceeded` - The window that rejected the request, if any.
+/// * `units` - The cost of the request, sent as `X-RateLimit-Cost`.
+/// * `now` - The Unix timestamp the resets are relative to.
+pub fn insert_rate_limit_headers(
+    headers: &mut HeaderMap,
+    windows: &[QuotaWindow],
+    exceeded: Option<&QuotaWindow>,
+    units: u64,
+    now: i64,
+) {
+    insert(headers, "x-ratelimit-cost", units);
+
+    for window in windows {
+        insert(
+            headers,
+            &format!("x-ratelimit-limit-{}", window.name),
+            window.limit,
+        );
+        insert(
+            headers,
+            &format!("x-ratelimit-remaining-{}", window.name),
+            window.remaining(),
+        );
+        insert(
+            headers,
+            &format!("x-ratelimit-reset-{}", window.name),
+            now + window.reset_secs as i64,
+        );
+    }
+
+    let binding = exceeded.or_else(|| {
+        windows
+            .iter()
+            .min_by_key(|window| (window.remaining(), std::cmp::Reverse(window.reset_secs)))
+    });
+    let Some(binding) = binding else {
+        return;
+    };
+
+    insert(headers, "x-ratelimit-limit", binding.limit);
+    insert(headers, "x-ratelimit-remaining", binding.remaining());
+    insert(
+        headers,
+        "x-ratelimit-reset",
+        now + binding.reset_secs as i64,
+    );
+
+    insert(headers, "ratelimit-limit", binding.limit);
+    insert(headers, "ratelimit-remaining", binding.remaining());
+    insert(headers, "ratelimit-reset", binding.reset_secs);
+    let policy = windows
+        .iter()
+        .map(|window| format!("{};w={}", window.limit, window.window_secs))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Ok(value) = HeaderValue::from_str(&policy) {
+        headers.insert(HeaderName::from_static("ratelimit-policy"), value);
+    }
+
+    if let Some(exceeded) = exceeded {
+        headers.insert(RETRY_AFTER, HeaderValue::from(exceeded.reset_secs.max(1)));
+    }
+}
+
+fn insert<V: Into<HeaderValue>>(headers: &mut HeaderMap, name: &str, value: V) {
+    if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+        headers.insert(name, value.into());
+    }
+}
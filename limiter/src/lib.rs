@@ -1,18 +1,56 @@
-use api_subs::models::sub::SubscriptionPlan;
-use middleware::{global::GlobalLimiter, quota::QuotaRateLimiter};
+use std::sync::Arc;
+
+use api_subs::cache::{plan::PlanRegistry, sub::SubscriptionCache};
+use concurrency::ConcurrencyLimiter;
+use middleware::{
+    global::{GlobalLimiter, LimitedBy},
+    quota::QuotaRateLimiter,
+};
+use cost::CostTable;
+use rate::RouteLimiter;
+use store::QuotaStore;
 
 pub mod middleware {
     pub mod global;
     pub mod quota;
 }
+pub mod concurrency;
+pub mod cost;
+pub mod fallback;
+pub mod headers;
+pub mod quota;
+pub mod rate;
+pub mod store;
+
+/// Limits requests per client IP and per user of a valid JWT.
+pub fn global_middleware(limiter: Arc<RouteLimiter>) -> GlobalLimiter {
+    GlobalLimiter::new(limiter, LimitedBy::IpAndSession)
+}
+
+/// Limits requests per client IP only, for routes authenticated by API keys.
+pub fn ip_middleware(limiter: Arc<RouteLimiter>) -> GlobalLimiter {
+    GlobalLimiter::new(limiter, LimitedBy::Ip)
+}
 
-pub fn global_middleware(permits_per_second: u32) -> GlobalLimiter {
-    GlobalLimiter::new(permits_per_second)
+/// Limits requests per owner of the verified API key, wrapped inside `api_keys::middleware()`.
+pub fn key_owner_middleware(limiter: Arc<RouteLimiter>) -> GlobalLimiter {
+    GlobalLimiter::new(limiter, LimitedBy::KeyOwner)
 }
 
 pub fn quota_middleware(
-    plans: Vec<SubscriptionPlan>,
-    redis_client: redis::Client,
+    plans: Arc<PlanRegistry>,
+    store: Arc<QuotaStore>,
+    subscriptions: Arc<SubscriptionCache>,
+    billing_cycle_anchored: bool,
+    costs: Arc<CostTable>,
+    concurrency: Arc<ConcurrencyLimiter>,
 ) -> QuotaRateLimiter {
-    QuotaRateLimiter::new(plans, redis_client)
+    QuotaRateLimiter::new(
+        plans,
+        store,
+        subscriptions,
+        billing_cycle_anchored,
+        costs,
+        concurrency,
+    )
 }
@@ -1,25 +1,40 @@
 use actix_web::{
-    Error,
+    Error, HttpMessage,
     dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    http::header::{HeaderValue, RETRY_AFTER},
+    web,
 };
-use common::error::AppError;
-use governor::{
-    Quota, RateLimiter,
-    clock::QuantaClock,
-    state::{InMemoryState, NotKeyed},
+use common::{
+    env_config::Config,
+    error::{AppError, Res},
+    ip,
+    jwt::JwtClaims,
 };
-use std::{future::Future, num::NonZeroU32, pin::Pin, rc::Rc, sync::Arc};
+use db::models::key::VerifiedKey;
+use std::{future::Future, pin::Pin, rc::Rc, sync::Arc};
 
-/// This limiter works for each request coming in (not per user)
+use crate::rate::RouteLimiter;
+
+/// Whom a `GlobalLimiter` counts the requests of.
+#[derive(Debug, Clone, Copy)]
+pub enum LimitedBy {
+    /// The client IP, and the user of a valid JWT.
+    IpAndSession,
+    /// The client IP only, for routes whose user is not known yet.
+    Ip,
+    /// The owner of the verified API key only, must run after the API key middleware.
+    KeyOwner,
+}
+
+/// This limiter works for each client IP and each authenticated user of a group of routes
 pub struct GlobalLimiter {
-    limiter: Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>,
+    limiter: Arc<RouteLimiter>,
+    by: LimitedBy,
 }
 
 impl GlobalLimiter {
-    pub fn new(permits_per_sec: u32) -> Self {
-        let quota = Quota::per_second(NonZeroU32::new(permits_per_sec).unwrap());
-        let limiter = Arc::new(RateLimiter::direct(quota));
-        Self { limiter }
+    pub fn new(limiter: Arc<RouteLimiter>, by: LimitedBy) -> Self {
+        Self { limiter, by }
     }
 }
 
@@ -38,13 +53,15 @@ where
         std::future::ready(Ok(GlobalLimiterService {
             service: Rc::new(service),
             limiter: self.limiter.clone(),
+            by: self.by,
         }))
     }
 }
 
 pub struct GlobalLimiterService<S> {
     service: Rc<S>,
-    limiter: Arc<RateLimiter<NotKeyed, InMemoryState, QuantaClock>>,
+    limiter: Arc<RouteLimiter>,
+    by: LimitedBy,
 }
 
 impl<S, B> Service<ServiceRequest> for GlobalLimiterService<S>
@@ -61,16 +78,45 @@ where
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let srv = Rc::clone(&self.service);
         let limiter = self.limiter.clone();
+
+        let client_ip = match self.by {
+            LimitedBy::IpAndSession | LimitedBy::Ip => {
+                let config = req.app_data::<web::Data<Arc<Config>>>().unwrap().clone();
+                ip::client_ip(req.request(), &config.trusted_proxies)
+            }
+            LimitedBy::KeyOwner => None,
+        };
+        let user_id = match self.by {
+            // only JWTs with a valid signature identify a user
+            LimitedBy::IpAndSession => match req.extensions().get::<Res<JwtClaims>>() {
+                Some(Ok(claims)) => Some(claims.user_id),
+                _ => None,
+            },
+            LimitedBy::Ip => None,
+            LimitedBy::KeyOwner => req
+                .extensions()
+                .get::<VerifiedKey>()
+                .map(|verified_key| verified_key.owner.id),
+        };
+
         Box::pin(async move {
-            // Check if the rate limiter allows the request
-            if limiter.check().is_ok() {
+            match limiter.check(client_ip, user_id).await {
                 // Move to the next services if ok
-                srv.call(req).await.map(|res| res.map_into_boxed_body())
-            } else {
+                Ok(()) => srv.call(req).await.map(|res| res.map_into_boxed_body()),
                 // Return 429 if limit reached
-                Ok(req.error_response(AppError::TooManyRequests(
-                    "Server overloaded. Please try again later.".to_string(),
-                )))
+                Err(limited) => {
+                    let mut res = req.error_response(AppError::TooManyRequests(format!(
+                        "Too many requests from this {}. Please try again later.",
+                        if limited.by == "ip" {
+                            "IP address"
+                        } else {
+                            "user"
+                        }
+                    )));
+                    res.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(limited.retry_after_secs));
+                    Ok(res)
+                }
             }
         })
     }
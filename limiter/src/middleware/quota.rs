@@ -1,37 +1,62 @@
-use std::{collections::HashMap, rc::Rc, sync::Arc};
+use std::{rc::Rc, sync::Arc};
 
 use actix_web::{
-    Error,
+    Error, HttpMessage,
+    dev::Payload,
     dev::{Service, ServiceRequest, ServiceResponse, Transform, forward_ready},
+    http::header::{CONTENT_LENGTH, HeaderValue, RETRY_AFTER},
+    web::{Bytes, BytesMut},
 };
-use api_subs::models::sub::SubscriptionPlan;
-
-use ::chrono::{/* Datelike, */ Duration};
-use chrono::{/* NaiveDate, */ Utc};
-use common::{
-    error::AppError,
-    key::{self},
-};
-use redis::AsyncCommands;
+use api_subs::cache::{plan::PlanRegistry, sub::SubscriptionCache};
+
+use ::chrono::{Datelike, Duration};
+use chrono::{NaiveDate, Utc};
+use common::{error::AppError, key::KeyMode};
+use db::models::{key::VerifiedKey, log::ConsumedUnits};
+use futures::StreamExt;
+use serde_json::Value;
 use sqlx::types::chrono;
 use std::{future::Future, pin::Pin};
 
+use crate::{
+    concurrency::ConcurrencyLimiter,
+    cost::{Cost, CostTable},
+    fallback::LocalQuotaWindow,
+    headers::{QuotaWindow, insert_rate_limit_headers},
+    quota::{QuotaOutcome, QuotaRequest},
+    store::{QuotaDecision, QuotaStore},
+};
+
+/// Seconds a client is asked to wait when the quota storage is unavailable.
+const UNAVAILABLE_RETRY_AFTER_SECS: u64 = 5;
+
 // --- Rate Limiting Middleware Definition ---
 
 pub struct QuotaRateLimiter {
-    plans: Arc<HashMap<String, Arc<SubscriptionPlan>>>,
-    redis_client: Arc<redis::Client>,
+    plans: Arc<PlanRegistry>,
+    store: Arc<QuotaStore>,
+    subscriptions: Arc<SubscriptionCache>,
+    billing_cycle_anchored: bool,
+    costs: Arc<CostTable>,
+    concurrency: Arc<ConcurrencyLimiter>,
 }
 
 impl QuotaRateLimiter {
-    pub fn new(plans_vec: Vec<SubscriptionPlan>, redis_client: redis::Client) -> Self {
-        let mut plans_map = HashMap::new();
-        for plan in plans_vec {
-            plans_map.insert(plan.id.clone(), Arc::new(plan));
-        }
+    pub fn new(
+        plans: Arc<PlanRegistry>,
+        store: Arc<QuotaStore>,
+        subscriptions: Arc<SubscriptionCache>,
+        billing_cycle_anchored: bool,
+        costs: Arc<CostTable>,
+        concurrency: Arc<ConcurrencyLimiter>,
+    ) -> Self {
         QuotaRateLimiter {
-            plans: Arc::new(plans_map),
-            redis_client: Arc::new(redis_client),
+            plans,
+            store,
+            subscriptions,
+            billing_cycle_anchored,
+            costs,
+            concurrency,
         }
     }
 }
@@ -53,7 +78,11 @@ where
         std::future::ready(Ok(QuotaRateLimitingMiddleware {
             service: Rc::new(service),
             plans: Arc::clone(&self.plans),
-            redis_client: Arc::clone(&self.redis_client),
+            store: Arc::clone(&self.store),
+            subscriptions: Arc::clone(&self.subscriptions),
+            billing_cycle_anchored: self.billing_cycle_anchored,
+            costs: Arc::clone(&self.costs),
+            concurrency: Arc::clone(&self.concurrency),
         }))
     }
 }
@@ -63,8 +92,13 @@ where
 pub struct QuotaRateLimitingMiddleware<S> {
     // Use Rc for the service within a single worker thread
     service: Rc<S>,
-    plans: Arc<HashMap<String, Arc<SubscriptionPlan>>>,
-    redis_client: Arc<redis::Client>,
+    plans: Arc<PlanRegistry>,
+    store: Arc<QuotaStore>,
+    subscriptions: Arc<SubscriptionCache>,
+    // Anchor monthly quotas to the billing period instead of the calendar month
+    billing_cycle_anchored: bool,
+    costs: Arc<CostTable>,
+    concurrency: Arc<ConcurrencyLimiter>,
 }
 
 // --- Service Trait Implementation for the Middleware ---
@@ -82,28 +116,120 @@ where
     forward_ready!(service);
 
     // Main logic: This method is called for each request
-    fn call(&self, req: ServiceRequest) -> Self::Future {
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
         // Clone Arcs to move into the async block
         let plans = Arc::clone(&self.plans);
-        let redis_client = Arc::clone(&self.redis_client);
+        let store = Arc::clone(&self.store);
+        let subscriptions = Arc::clone(&self.subscriptions);
+        let billing_cycle_anchored = self.billing_cycle_anchored;
+        // the part of the path within the scope the limiter wraps
+        let cost = self
+            .costs
+            .get(req.method(), req.match_info().unprocessed());
+        let concurrency = Arc::clone(&self.concurrency);
         // Clone Rc for the service
         let srv = Rc::clone(&self.service);
 
         Box::pin(async move {
-            // Check if API key claims are present in the request
-            if let Some(key_claims) = key::get_key_claims_or_error(&req).ok() {
-                // 1. Find the subscription plan based on claims
-                let plan = match plans.get(&key_claims.plan_id) {
-                    Some(p) => Arc::clone(p), // Clone the Arc<SubscriptionPlan>
+            // Check if verified API key is present in the request
+            let verified_key = req.extensions().get::<VerifiedKey>().cloned();
+
+            // Test keys never consume quota
+            if let Some(verified_key) = &verified_key
+                && verified_key.mode == KeyMode::Test
+            {
+                let mut res = srv.call(req).await?.map_into_boxed_body();
+                insert_rate_limit_headers(res.headers_mut(), &[], None, 0, Utc::now().timestamp());
+                return Ok(res);
+            }
+
+            if let Some(verified_key) = verified_key {
+                // Units the request consumes, possibly computed from its body
+                let units = match cost {
+                    Cost::Fixed(units) => units,
+                    Cost::FromBody {
+                        max_bytes,
+                        units: units_fn,
+                    } => {
+                        let body = match peek_body(&mut req, max_bytes).await {
+                            Ok(body) => body,
+                            Err(e) => return Ok(req.error_response(e)),
+                        };
+                        units_fn(&serde_json::from_slice(&body).unwrap_or(Value::Null))
+                    }
+                };
+
+                // 1. Find the current subscription plan of the key owner
+                let customer_id = &verified_key.owner.stripe_customer_id;
+                let subscription = match subscriptions.get(customer_id).await {
+                    Ok(Some(subscription)) => Some(subscription),
+                    Ok(None) => {
+                        return Ok(req.error_response(AppError::Forbidden(format!(
+                            "User {} has no active subscription",
+                            verified_key.owner.id
+                        ))));
+                    }
+                    Err(e) => {
+                        // Stripe is unreachable and nothing is cached,
+                        // the plan the key was issued for is the best guess
+                        log::warn!(
+                            "Failed to resolve subscription of customer {}, using plan of key {}: {}",
+                            customer_id,
+                            verified_key.id,
+                            e
+                        );
+                        None
+                    }
+                };
+                let plan_id = match &subscription {
+                    Some(subscription) => subscription.id.clone(),
+                    None => verified_key.plan_id.clone(),
+                };
+                let plan = match plans.get(&plan_id) {
+                    Some(p) => p,
                     None => {
                         return Ok(req.error_response(AppError::Internal(format!(
-                            "Plan ID '{}' from key '{}' not found in configured plans.",
-                            key_claims.plan_id, key_claims.key_id
+                            "Plan ID '{}' of user '{}' not found in configured plans.",
+                            plan_id, verified_key.owner.id
                         ))));
                     }
                 };
 
-                // 2. Parse limits from metadata
+                // 2. Take a concurrency slot, held until the request completes or is dropped
+                let concurrency_limit = match plan
+                    .metadata
+                    .as_ref()
+                    .and_then(|meta| meta.concurrent_api_limit.as_deref())
+                {
+                    Some(limit) => match limit.parse::<u32>() {
+                        Ok(limit) => limit,
+                        Err(_) => {
+                            return Ok(req.error_response(AppError::Internal(format!(
+                                "Failed to parse concurrency limit for plan ID '{}'",
+                                plan_id
+                            ))));
+                        }
+                    },
+                    None => 0,
+                };
+                let _slot = if concurrency_limit > 0 {
+                    match concurrency.try_acquire(verified_key.owner.id, concurrency_limit) {
+                        Some(slot) => Some(slot),
+                        None => {
+                            let mut res = req.error_response(AppError::TooManyRequests(format!(
+                                "Concurrency limit of {} requests in flight reached for user {}",
+                                concurrency_limit, verified_key.owner.id
+                            )));
+                            res.headers_mut()
+                                .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                            return Ok(res);
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                // 3. Parse limits from metadata
                 let (daily_limit, monthly_limit) = match &plan.metadata {
                     Some(meta) => {
                         match (
@@ -114,142 +240,204 @@ where
                             _ => {
                                 return Ok(req.error_response(AppError::Internal(format!(
                                     "Failed to parse limits for plan ID '{}'",
-                                    key_claims.plan_id
+                                    plan_id
                                 ))));
                             }
                         }
                     }
                     None => {
+                        // If no limits defined, allow the request without limits
                         log::warn!(
                             "Plan ID '{}' has no metadata defined. Allowing request without limits.",
-                            key_claims.plan_id
+                            plan_id
                         );
-                        // If no limits defined, allow the request to pass through
-                        return srv.call(req).await.map(|res| res.map_into_boxed_body());
+                        (0, 0)
                     }
                 };
+                let now = Utc::now();
 
-                // Skip checks if limits are zero (or handle as needed)
-                if daily_limit == 0 || monthly_limit == 0 {
-                    log::debug!(
-                        "Plan '{}' has zero limits, allowing request.",
-                        key_claims.plan_id
-                    );
-                    return srv.call(req).await.map(|res| res.map_into_boxed_body());
+                // A zero limit means unlimited, skip Redis if both windows are unlimited
+                // There is no window to report, only the cost
+                if daily_limit == 0 && monthly_limit == 0 {
+                    log::debug!("Plan '{}' has no limits, allowing request.", plan_id);
+                    let mut res = srv.call(req).await?.map_into_boxed_body();
+                    insert_rate_limit_headers(res.headers_mut(), &[], None, units, now.timestamp());
+                    return Ok(res);
                 }
 
-                // 3. Get Redis connection
-                let mut redis_conn = match redis_client.get_multiplexed_tokio_connection().await {
-                    Ok(conn) => conn,
-                    Err(e) => {
-                        return Ok(req.error_response(AppError::Internal(format!(
-                            "Failed to get Redis connection: {}",
-                            e
-                        ))));
-                    }
-                };
-
                 // 4. Prepare Redis keys and TTLs
-                let now = Utc::now();
                 let date_str = now.format("%Y-%m-%d").to_string();
                 let month_str = now.format("%Y-%m").to_string();
-                let user_id_str = key_claims.user_id.to_string();
-
-                // Create Redis keys for daily and monthly quotas
-                let daily_key = format!("quota:{}:daily:{}", user_id_str, date_str);
-                let monthly_key = format!("quota:{}:monthly:{}", user_id_str, month_str);
-
-                // Calculate TTLs for daily and monthly quotas
-                let seconds_until_midnight = calculate_seconds_until_midnight(now);
-                // let seconds_until_end_of_month = calculate_seconds_until_end_of_month(now);
-
-                // 5. Check and Increment Limits (Atomic Check-then-Increment is tricky without Lua)
-                // Approach: Increment first, check result, decrement if over limit.
-
-                // --- Daily Check ---
-                // Increment the daily count in Redis
-                let daily_count: Result<u64, redis::RedisError> =
-                    redis_conn.incr(&daily_key, 1).await;
-
-                match daily_count {
-                    Ok(count) => {
-                        // Set expiry only if the key was newly created (count is 1)
-                        if count == 1 {
-                            let _: Result<(), redis::RedisError> = redis_conn
-                                .expire(&daily_key, seconds_until_midnight as i64)
-                                .await;
-                        }
+                let user_id_str = verified_key.owner.id.to_string();
+
+                // The monthly window is the billing period of the subscription if anchored,
+                // the calendar month otherwise or if the period is unknown
+                let billing_period = subscription
+                    .as_ref()
+                    .filter(|_| billing_cycle_anchored)
+                    .filter(|sub| sub.current_period_end > now.timestamp())
+                    .map(|sub| (sub.current_period_start, sub.current_period_end));
+                let (monthly_key, monthly_ttl_secs, monthly_window_secs) = match billing_period {
+                    Some((start, end)) => (
+                        format!("quota:{}:period:{}", user_id_str, start),
+                        (end - now.timestamp()).max(1) as u64,
+                        (end - start).max(1) as u64,
+                    ),
+                    None => (
+                        format!("quota:{}:monthly:{}", user_id_str, month_str),
+                        calculate_seconds_until_end_of_month(now),
+                        calculate_seconds_in_month(now),
+                    ),
+                };
 
-                        // Check if daily limit is exceeded
-                        if count > daily_limit {
-                            // Decrement back as we exceeded the limit
-                            let _: Result<u64, redis::RedisError> =
-                                redis_conn.decr(&daily_key, 1).await;
+                let quota_req = QuotaRequest {
+                    daily_key: format!("quota:{}:daily:{}", user_id_str, date_str),
+                    monthly_key,
+                    daily_limit,
+                    monthly_limit,
+                    daily_ttl_secs: calculate_seconds_until_midnight(now),
+                    monthly_ttl_secs,
+                    units,
+                };
+                let daily_window = QuotaWindow {
+                    name: "Day",
+                    limit: daily_limit,
+                    used: 0,
+                    window_secs: 86400,
+                    reset_secs: quota_req.daily_ttl_secs,
+                };
+                let monthly_window = QuotaWindow {
+                    name: "Month",
+                    limit: monthly_limit,
+                    used: 0,
+                    window_secs: monthly_window_secs,
+                    reset_secs: quota_req.monthly_ttl_secs,
+                };
 
-                            return Ok(req.error_response(AppError::TooManyRequests(format!(
-                                "Daily limit exceeded for key {}. Count: {}, Limit: {}",
-                                user_id_str, count, daily_limit
-                            ))));
-                        }
+                // 5. Check and increment both limits atomically
+                // Yields the used units of both windows and, if the request is rejected,
+                // the error and the exhausted window
+                let decision = store
+                    .consume(&quota_req, &plan_id, verified_key.owner.id)
+                    .await;
+                let failed_closed = matches!(decision, QuotaDecision::FailedClosed);
+                // Only units counted in Redis are reported as consumed
+                let counted = matches!(
+                    decision,
+                    QuotaDecision::Counted(QuotaOutcome::Allowed { .. })
+                );
+                let (daily, monthly, rejection) = match decision {
+                    QuotaDecision::Counted(QuotaOutcome::Allowed { daily, monthly }) => {
+                        log::debug!(
+                            "Limits OK for user {}. Units: {}, Daily: {}/{}, Monthly: {}/{}",
+                            user_id_str,
+                            units,
+                            daily,
+                            daily_limit,
+                            monthly,
+                            monthly_limit
+                        );
+                        (daily, monthly, None)
                     }
-                    Err(e) => {
-                        return Ok(req.error_response(AppError::Internal(format!(
-                            "Redis error incrementing daily count for key {}: {}",
-                            user_id_str, e
-                        ))));
+                    QuotaDecision::Counted(QuotaOutcome::DailyExceeded { daily, monthly }) => {
+                        let error = AppError::TooManyRequests(format!(
+                            "Daily limit exceeded for user {}. Used: {}, Cost: {}, Limit: {}",
+                            user_id_str, daily, units, daily_limit
+                        ));
+                        let exceeded = QuotaWindow {
+                            used: daily,
+                            ..daily_window
+                        };
+                        (daily, monthly, Some((error, Some(exceeded))))
                     }
-                }
+                    QuotaDecision::Counted(QuotaOutcome::MonthlyExceeded { daily, monthly }) => {
+                        let error = AppError::TooManyRequests(format!(
+                            "Monthly limit exceeded for user {}. Used: {}, Cost: {}, Limit: {}",
+                            user_id_str, monthly, units, monthly_limit
+                        ));
+                        let exceeded = QuotaWindow {
+                            used: monthly,
+                            ..monthly_window
+                        };
+                        (daily, monthly, Some((error, Some(exceeded))))
+                    }
+                    // Nothing is known about the used units, the full limits are reported
+                    QuotaDecision::FailedOpen => (0, 0, None),
+                    QuotaDecision::FailedClosed => {
+                        let error = AppError::ServiceUnavailable(format!(
+                            "Quota storage unavailable, rejecting request of user {}",
+                            user_id_str
+                        ));
+                        (0, 0, Some((error, None)))
+                    }
+                    // The local limiter only knows the units left in its own windows
+                    QuotaDecision::Fallback(Ok(usage)) => (
+                        daily_limit.saturating_sub(usage.daily_remaining.unwrap_or(daily_limit)),
+                        monthly_limit
+                            .saturating_sub(usage.monthly_remaining.unwrap_or(monthly_limit)),
+                        None,
+                    ),
+                    QuotaDecision::Fallback(Err(exceeded)) => {
+                        let (window, daily, monthly, name) = match exceeded.window {
+                            LocalQuotaWindow::Daily => (daily_window, daily_limit, 0, "Daily"),
+                            LocalQuotaWindow::Monthly => {
+                                (monthly_window, 0, monthly_limit, "Monthly")
+                            }
+                        };
+                        let error = AppError::TooManyRequests(format!(
+                            "{} limit exceeded for user {}",
+                            name, user_id_str
+                        ));
+                        // the local limiter replenishes evenly, so it allows again before the reset
+                        let exceeded = QuotaWindow {
+                            used: window.limit,
+                            reset_secs: exceeded.retry_after_secs,
+                            ..window
+                        };
+                        (daily, monthly, Some((error, Some(exceeded))))
+                    }
+                };
+                // unlimited windows are not reported
+                let windows: Vec<QuotaWindow> = [
+                    QuotaWindow {
+                        used: daily,
+                        ..daily_window
+                    },
+                    QuotaWindow {
+                        used: monthly,
+                        ..monthly_window
+                    },
+                ]
+                .into_iter()
+                .filter(|window| window.limit > 0)
+                .collect();
+
+                let (mut res, exceeded) = match rejection {
+                    None => {
+                        // 6. Limits OK - Forward request to the next service
+                        if counted {
+                            req.extensions_mut().insert(ConsumedUnits(units));
+                        }
+                        let res = srv.call(req).await?.map_into_boxed_body();
+                        (res, None)
+                    }
+                    Some((error, exceeded)) => (req.error_response(error), exceeded),
+                };
 
-                // --- Monthly Check ---
-                // Increment the monthly count in Redis
-                let monthly_count: Result<u64, redis::RedisError> =
-                    redis_conn.incr(&monthly_key, 1).await;
-
-                // match monthly_count {
-                //     Ok(count) => {
-                //         // Set expiry only if the key was newly created (count is 1)
-                //         if count == 1 {
-                //             let _: Result<(), redis::RedisError> = redis_conn
-                //                 .expire(&monthly_key, seconds_until_end_of_month as i64)
-                //                 .await;
-                //         }
-
-                //         // Check if monthly limit is exceeded
-                //         if count > monthly_limit {
-                //             // Decrement back BOTH counters as this request is fully rejected
-                //             let _: Result<u64, redis::RedisError> =
-                //                 redis_conn.decr(&monthly_key, 1).await;
-                //             let _: Result<u64, redis::RedisError> =
-                //                 redis_conn.decr(&daily_key, 1).await; // Also undo daily incr
-                //             // Log decrement errors if needed
-
-                //             return Ok(req.error_response(AppError::TooManyRequests(format!(
-                //                 "Monthly limit exceeded for key {}. Count: {}, Limit: {}",
-                //                 user_id_str, count, monthly_limit
-                //             ))));
-                //         }
-                //     }
-                //     Err(e) => {
-                //         // We already incremented daily, attempt to decrement it back
-                //         let _: Result<u64, redis::RedisError> =
-                //             redis_conn.decr(&daily_key, 1).await;
-
-                //         return Ok(req.error_response(AppError::Internal(format!(
-                //             "Redis error incrementing monthly count for key {}: {}",
-                //             user_id_str, e
-                //         ))));
-                //     }
-                // }
-
-                // 6. Limits OK - Forward request to the next service
-                log::debug!(
-                    "Limits OK for key {}. Daily: {}/{}, Monthly: {}/{}",
-                    user_id_str,
-                    daily_count.unwrap_or(0), // Should be Ok here
-                    daily_limit,
-                    monthly_count.unwrap_or(0), // Should be Ok here
-                    monthly_limit
+                // 7. Report the state of both windows
+                insert_rate_limit_headers(
+                    res.headers_mut(),
+                    &windows,
+                    exceeded.as_ref(),
+                    units,
+                    now.timestamp(),
                 );
+                // Redis is expected back shortly, the request can be retried as is
+                if failed_closed {
+                    res.headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(UNAVAILABLE_RETRY_AFTER_SECS));
+                }
+                return Ok(res);
             } else {
                 log::warn!("No API key provided and QuotaRateLimiter was requested");
             }
@@ -261,6 +449,40 @@ where
 
 // --- Helper Functions ---
 
+/// Reads the body of a request and puts it back, so the route can still read it.
+/// Fails with `PayloadTooLarge` as soon as the body is known to exceed `max_bytes`,
+/// from its `Content-Length` or while reading it.
+async fn peek_body(req: &mut ServiceRequest, max_bytes: usize) -> Result<Bytes, AppError> {
+    let too_large =
+        || AppError::PayloadTooLarge(format!("Request body exceeds {} bytes", max_bytes));
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > max_bytes) {
+        return Err(too_large());
+    }
+
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk
+            .map_err(|e| AppError::BadRequest(format!("Failed to read request body: {}", e)))?;
+        if body.len() + chunk.len() > max_bytes {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+
+    let stream: Pin<
+        Box<dyn futures::Stream<Item = Result<Bytes, actix_web::error::PayloadError>>>,
+    > = futures::stream::once(std::future::ready(Ok(body.clone()))).boxed_local();
+    req.set_payload(Payload::from(stream));
+    Ok(body)
+}
+
 fn calculate_seconds_until_midnight(now: chrono::DateTime<Utc>) -> u64 {
     let midnight_tomorrow = (now.date_naive() + Duration::days(1))
         .and_hms_opt(0, 0, 0)
@@ -276,33 +498,48 @@ fn calculate_seconds_until_midnight(now: chrono::DateTime<Utc>) -> u64 {
         .max(0) as u64
 }
 
-// fn calculate_seconds_until_end_of_month(now: chrono::DateTime<Utc>) -> u64 {
-//     let current_month = now.month();
-//     let current_year = now.year();
-
-//     let next_month_year;
-//     let next_month;
-
-//     if current_month == 12 {
-//         next_month = 1;
-//         next_month_year = current_year + 1;
-//     } else {
-//         next_month = current_month + 1;
-//         next_month_year = current_year;
-//     }
-
-//     // First day of the next month
-//     let first_day_next_month = NaiveDate::from_ymd_opt(next_month_year, next_month, 1)
-//         .unwrap()
-//         .and_hms_opt(0, 0, 0)
-//         .unwrap();
-
-//     // Ensure we are using UTC for calculation consistency
-//     let first_day_next_month_utc =
-//         chrono::DateTime::<Utc>::from_naive_utc_and_offset(first_day_next_month, Utc);
-
-//     first_day_next_month_utc
-//         .signed_duration_since(now)
-//         .num_seconds()
-//         .max(0) as u64
-// }
+fn calculate_seconds_until_end_of_month(now: chrono::DateTime<Utc>) -> u64 {
+    let current_month = now.month();
+    let current_year = now.year();
+
+    let next_month_year;
+    let next_month;
+
+    if current_month == 12 {
+        next_month = 1;
+        next_month_year = current_year + 1;
+    } else {
+        next_month = current_month + 1;
+        next_month_year = current_year;
+    }
+
+    // First day of the next month
+    let first_day_next_month = NaiveDate::from_ymd_opt(next_month_year, next_month, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+
+    // Ensure we are using UTC for calculation consistency
+    let first_day_next_month_utc =
+        chrono::DateTime::<Utc>::from_naive_utc_and_offset(first_day_next_month, Utc);
+
+    first_day_next_month_utc
+        .signed_duration_since(now)
+        .num_seconds()
+        .max(0) as u64
+}
+
+fn calculate_seconds_in_month(now: chrono::DateTime<Utc>) -> u64 {
+    let first_day_this_month = NaiveDate::from_ymd_opt(now.year(), now.month(), 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let first_day_this_month_utc =
+        chrono::DateTime::<Utc>::from_naive_utc_and_offset(first_day_this_month, Utc);
+
+    calculate_seconds_until_end_of_month(now)
+        + now
+            .signed_duration_since(first_day_this_month_utc)
+            .num_seconds()
+            .max(0) as u64
+}
@@ -0,0 +1,98 @@
+use std::sync::LazyLock;
+
+use redis::{RedisResult, Script, aio::ConnectionLike};
+
+/// Checks both quota windows and increments them by the cost of the request in a single atomic step.
+///
+/// KEYS: daily counter, monthly counter.
+/// ARGV: daily limit, monthly limit, daily TTL, monthly TTL (seconds), units. A limit of 0 means unlimited.
+///
+/// Returns `{allowed, daily_count, monthly_count, exceeded}` where `exceeded` is
+/// 0 (none), 1 (daily) or 2 (monthly). Rejected requests do not change the counters.
+/// TTLs are set whenever a counter has none, so counters never outlive their window.
+static QUOTA_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+        local daily_limit = tonumber(ARGV[1])
+        local monthly_limit = tonumber(ARGV[2])
+        local units = tonumber(ARGV[5])
+        local daily = tonumber(redis.call('GET', KEYS[1]) or '0')
+        local monthly = tonumber(redis.call('GET', KEYS[2]) or '0')
+
+        if daily_limit > 0 and daily + units > daily_limit then
+            return {0, daily, monthly, 1}
+        end
+        if monthly_limit > 0 and monthly + units > monthly_limit then
+            return {0, daily, monthly, 2}
+        end
+
+        daily = redis.call('INCRBY', KEYS[1], units)
+        monthly = redis.call('INCRBY', KEYS[2], units)
+        if redis.call('TTL', KEYS[1]) < 0 then
+            redis.call('EXPIRE', KEYS[1], ARGV[3])
+        end
+        if redis.call('TTL', KEYS[2]) < 0 then
+            redis.call('EXPIRE', KEYS[2], ARGV[4])
+        end
+
+        return {1, daily, monthly, 0}
+        "#,
+    )
+});
+
+/// Counters and limits of a single quota check.
+#[derive(Debug, Clone)]
+pub struct QuotaRequest {
+    pub daily_key: String,
+    pub monthly_key: String,
+    /// 0 means unlimited.
+    pub daily_limit: u64,
+    /// 0 means unlimited.
+    pub monthly_limit: u64,
+    pub daily_ttl_secs: u64,
+    pub monthly_ttl_secs: u64,
+    /// Units consumed by the request, see `cost::Cost`.
+    pub units: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaOutcome {
+    /// The request was counted. Holds the used units including this request.
+    Allowed { daily: u64, monthly: u64 },
+    /// The daily limit would be exceeded. Nothing was counted.
+    DailyExceeded { daily: u64, monthly: u64 },
+    /// The monthly limit would be exceeded. Nothing was counted.
+    MonthlyExceeded { daily: u64, monthly: u64 },
+}
+
+/// Counts the units of a request against the daily and monthly quota, unless either would be exceeded.
+///
+/// # Arguments
+///
+/// * `conn` - A Redis connection.
+/// * `req` - The counters and limits to check.
+///
+/// # Returns
+///
+/// A `RedisResult` containing the `QuotaOutcome`.
+pub async fn consume<C: ConnectionLike>(
+    conn: &mut C,
+    req: &QuotaRequest,
+) -> RedisResult<QuotaOutcome> {
+    let (allowed, daily, monthly, exceeded): (u8, u64, u64, u8) = QUOTA_SCRIPT
+        .key(&req.daily_key)
+        .key(&req.monthly_key)
+        .arg(req.daily_limit)
+        .arg(req.monthly_limit)
+        .arg(req.daily_ttl_secs.max(1))
+        .arg(req.monthly_ttl_secs.max(1))
+        .arg(req.units)
+        .invoke_async(conn)
+        .await?;
+
+    Ok(match (allowed, exceeded) {
+        (1, _) => QuotaOutcome::Allowed { daily, monthly },
+        (_, 1) => QuotaOutcome::DailyExceeded { daily, monthly },
+        _ => QuotaOutcome::MonthlyExceeded { daily, monthly },
+    })
+}
@@ -0,0 +1,235 @@
+use std::{
+    net::IpAddr,
+    num::NonZeroU32,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+use common::{connection::RedisConnection, env_config::RouteRateLimit};
+use governor::{
+    Quota, RateLimiter,
+    clock::{Clock, QuantaClock},
+    state::keyed::DashMapStateStore,
+};
+use redis::{RedisResult, Script};
+use uuid::Uuid;
+
+type KeyedRateLimiter<K> = RateLimiter<K, DashMapStateStore<K>, QuantaClock>;
+
+/// Window the limits are counted over.
+const WINDOW_SECS: i64 = 60;
+
+/// Increments every given counter and sets its TTL whenever it has none.
+///
+/// KEYS: the counters of the current window.
+/// ARGV: seconds until the end of the window.
+///
+/// Returns the count of each counter including this request.
+static WINDOW_SCRIPT: LazyLock<Script> = LazyLock::new(|| {
+    Script::new(
+        r#"
+        local counts = {}
+        for i, key in ipairs(KEYS) do
+            counts[i] = redis.call('INCR', key)
+            if redis.call('TTL', key) < 0 then
+                redis.call('EXPIRE', key, ARGV[1])
+            end
+        end
+        return counts
+        "#,
+    )
+});
+
+/// Limit reached by a request.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimited {
+    /// `ip` or `user`.
+    pub by: &'static str,
+    pub retry_after_secs: u64,
+}
+
+/// Rate limiter of a group of routes, keyed by client IP and by authenticated user.
+///
+/// Without Redis, each key gets a GCRA limiter (`governor`) that allows a burst
+/// of the whole limit and replenishes it evenly over a minute. The state is shared
+/// by the workers of an instance.
+///
+/// With Redis, each key gets a counter per minute, shared by all instances.
+/// Rejected requests are counted too, so a client that keeps sending requests
+/// stays limited until the minute ends. While Redis is unavailable, the in-memory
+/// limiters are used.
+pub struct RouteLimiter {
+    group: &'static str,
+    limits: RouteRateLimit,
+    by_ip: Option<KeyedRateLimiter<IpAddr>>,
+    by_user: Option<KeyedRateLimiter<Uuid>>,
+    clock: QuantaClock,
+    redis: Option<Arc<RedisConnection>>,
+    redis_healthy: AtomicBool,
+}
+
+impl RouteLimiter {
+    /// Creates the limiter of a group of routes.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The name of the group, used in logs and Redis keys.
+    /// * `limits` - The requests per minute allowed per IP and per user.
+    /// * `redis` - The Redis connection to keep the counters in, or `None` to keep them in memory.
+    pub fn new(
+        group: &'static str,
+        limits: RouteRateLimit,
+        redis: Option<Arc<RedisConnection>>,
+    ) -> Self {
+        RouteLimiter {
+            group,
+            limits,
+            by_ip: keyed_rate_limiter(limits.per_ip),
+            by_user: keyed_rate_limiter(limits.per_user),
+            clock: QuantaClock::default(),
+            redis,
+            redis_healthy: AtomicBool::new(true),
+        }
+    }
+
+    /// Counts a request against the limits of its client IP and user.
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The client IP, if known.
+    /// * `user_id` - The authenticated user, if any.
+    ///
+    /// # Returns
+    ///
+    /// `Ok` if the request is allowed, or the `RateLimited` limit it reached.
+    pub async fn check(
+        &self,
+        ip: Option<IpAddr>,
+        user_id: Option<Uuid>,
+    ) -> Result<(), RateLimited> {
+        let ip = ip.filter(|_| self.by_ip.is_some());
+        let user_id = user_id.filter(|_| self.by_user.is_some());
+        if ip.is_none() && user_id.is_none() {
+            return Ok(());
+        }
+
+        if let Some(redis) = &self.redis {
+            match self.check_redis(redis, ip, user_id).await {
+                Ok(result) => {
+                    if !self.redis_healthy.swap(true, Ordering::Relaxed) {
+                        log::info!(
+                            "Redis is available again, {} rate limits are shared",
+                            self.group
+                        );
+                    }
+                    return result;
+                }
+                Err(e) => {
+                    if self.redis_healthy.swap(false, Ordering::Relaxed) {
+                        log::error!(
+                            "Redis is unavailable, {} rate limits are counted per instance: {}",
+                            self.group,
+                            e
+                        );
+                    }
+                }
+            }
+        }
+
+        self.check_local(ip, user_id)
+    }
+
+    /// Drops the in-memory state of clients whose limit is fully replenished, every `period`.
+    pub async fn clean_periodically(&self, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Some(limiter) = &self.by_ip {
+                limiter.retain_recent();
+            }
+            if let Some(limiter) = &self.by_user {
+                limiter.retain_recent();
+            }
+        }
+    }
+
+    /// Checks the user first, so a user over their limit does not use up the limit
+    /// of an IP that other users may share.
+    fn check_local(&self, ip: Option<IpAddr>, user_id: Option<Uuid>) -> Result<(), RateLimited> {
+        if let (Some(user_id), Some(limiter)) = (user_id, &self.by_user) {
+            limiter
+                .check_key(&user_id)
+                .map_err(|not_until| RateLimited {
+                    by: "user",
+                    retry_after_secs: not_until.wait_time_from(self.clock.now()).as_secs().max(1),
+                })?;
+        }
+        if let (Some(ip), Some(limiter)) = (ip, &self.by_ip) {
+            limiter.check_key(&ip).map_err(|not_until| RateLimited {
+                by: "ip",
+                retry_after_secs: not_until.wait_time_from(self.clock.now()).as_secs().max(1),
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn check_redis(
+        &self,
+        redis: &RedisConnection,
+        ip: Option<IpAddr>,
+        user_id: Option<Uuid>,
+    ) -> RedisResult<Result<(), RateLimited>> {
+        let now = Utc::now().timestamp();
+        let window = now / WINDOW_SECS;
+        let retry_after_secs = (WINDOW_SECS - now % WINDOW_SECS) as u64;
+
+        let mut counters = Vec::new();
+        if let Some(ip) = ip {
+            counters.push((
+                "ip",
+                self.limits.per_ip,
+                format!("ratelimit:{}:ip:{}:{}", self.group, ip, window),
+            ));
+        }
+        if let Some(user_id) = user_id {
+            counters.push((
+                "user",
+                self.limits.per_user,
+                format!("ratelimit:{}:user:{}:{}", self.group, user_id, window),
+            ));
+        }
+
+        let mut invocation = WINDOW_SCRIPT.prepare_invoke();
+        for (_, _, key) in &counters {
+            invocation.key(key);
+        }
+        let mut connection = redis.get().await?;
+        let counts: Vec<u64> = invocation
+            .arg(retry_after_secs)
+            .invoke_async(&mut connection)
+            .await?;
+
+        Ok(counters
+            .iter()
+            .zip(counts)
+            .find(|((_, limit, _), count)| *count > u64::from(*limit))
+            .map_or(Ok(()), |((by, _, _), _)| {
+                Err(RateLimited {
+                    by,
+                    retry_after_secs,
+                })
+            }))
+    }
+}
+
+fn keyed_rate_limiter<K>(per_minute: u32) -> Option<KeyedRateLimiter<K>>
+where
+    K: std::hash::Hash + Eq + Clone,
+{
+    let per_minute = NonZeroU32::new(per_minute)?;
+    Some(RateLimiter::keyed(Quota::per_minute(per_minute)))
+}
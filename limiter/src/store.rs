@@ -0,0 +1,163 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use common::{connection::RedisConnection, env_config::RedisFailurePolicy};
+use redis::RedisResult;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    fallback::{LocalQuotaExceeded, LocalQuotaLimiter, LocalQuotaUsage},
+    quota::{self, QuotaOutcome, QuotaRequest},
+};
+
+/// Result of counting a request against the quota.
+#[derive(Debug, Clone, Copy)]
+pub enum QuotaDecision {
+    /// Counted in Redis.
+    Counted(QuotaOutcome),
+    /// Redis is unavailable and the policy is `allow`, nothing was counted.
+    FailedOpen,
+    /// Redis is unavailable and the policy is `reject`.
+    FailedClosed,
+    /// Redis is unavailable and the request was counted in the local limiter.
+    Fallback(Result<LocalQuotaUsage, LocalQuotaExceeded>),
+}
+
+/// Snapshot of the store counters.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaStoreStats {
+    pub redis_healthy: bool,
+    pub redis_errors: u64,
+    pub failed_open: u64,
+    pub failed_closed: u64,
+    pub fallback_checks: u64,
+}
+
+/// Storage of the quota counters, shared by all workers.
+///
+/// Counters live in Redis, reached through the shared `RedisConnection`. While Redis is unavailable, requests are handled
+/// according to the `RedisFailurePolicy`. Transitions between healthy and
+/// degraded are logged once, the counters are available through `stats`.
+pub struct QuotaStore {
+    connection: Arc<RedisConnection>,
+    policy: RedisFailurePolicy,
+    local: LocalQuotaLimiter,
+    healthy: AtomicBool,
+    redis_errors: AtomicU64,
+    failed_open: AtomicU64,
+    failed_closed: AtomicU64,
+    fallback_checks: AtomicU64,
+}
+
+impl QuotaStore {
+    pub fn new(connection: Arc<RedisConnection>, policy: RedisFailurePolicy) -> Self {
+        QuotaStore {
+            connection,
+            policy,
+            local: LocalQuotaLimiter::new(),
+            healthy: AtomicBool::new(true),
+            redis_errors: AtomicU64::new(0),
+            failed_open: AtomicU64::new(0),
+            failed_closed: AtomicU64::new(0),
+            fallback_checks: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a request against the quota of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The counters and limits to check in Redis.
+    /// * `plan_id` - The plan of the user, used by the local limiter.
+    /// * `user_id` - The user making the request.
+    ///
+    /// # Returns
+    ///
+    /// The `QuotaDecision` for the request.
+    pub async fn consume(&self, req: &QuotaRequest, plan_id: &str, user_id: Uuid) -> QuotaDecision {
+        match self.consume_redis(req).await {
+            Ok(outcome) => {
+                if !self.healthy.swap(true, Ordering::Relaxed) {
+                    log::info!("Redis is available again, quotas are counted in Redis");
+                }
+                return QuotaDecision::Counted(outcome);
+            }
+            Err(e) => {
+                self.redis_errors.fetch_add(1, Ordering::Relaxed);
+                if self.healthy.swap(false, Ordering::Relaxed) {
+                    log::error!(
+                        "Redis is unavailable, quota failure policy is {:?}: {}",
+                        self.policy,
+                        e
+                    );
+                } else {
+                    log::debug!("Redis is still unavailable: {}", e);
+                }
+            }
+        }
+
+        match self.policy {
+            RedisFailurePolicy::Reject => {
+                self.failed_closed.fetch_add(1, Ordering::Relaxed);
+                QuotaDecision::FailedClosed
+            }
+            RedisFailurePolicy::Allow => {
+                self.failed_open.fetch_add(1, Ordering::Relaxed);
+                QuotaDecision::FailedOpen
+            }
+            RedisFailurePolicy::Fallback => {
+                self.fallback_checks.fetch_add(1, Ordering::Relaxed);
+                QuotaDecision::Fallback(self.local.check(
+                    plan_id,
+                    user_id,
+                    req.daily_limit,
+                    req.monthly_limit,
+                    req.units,
+                ))
+            }
+        }
+    }
+
+    pub fn stats(&self) -> QuotaStoreStats {
+        QuotaStoreStats {
+            redis_healthy: self.healthy.load(Ordering::Relaxed),
+            redis_errors: self.redis_errors.load(Ordering::Relaxed),
+            failed_open: self.failed_open.load(Ordering::Relaxed),
+            failed_closed: self.failed_closed.load(Ordering::Relaxed),
+            fallback_checks: self.fallback_checks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Logs the store counters every `period` and drops idle local limiter state.
+    pub async fn log_stats(&self, period: Duration) {
+        let mut interval = actix_web::rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.local.retain_recent();
+            let stats = self.stats();
+            log::info!(
+                "Quota store: redis {}, {} redis errors, {} failed open, {} failed closed, {} fallback checks",
+                if stats.redis_healthy {
+                    "healthy"
+                } else {
+                    "unavailable"
+                },
+                stats.redis_errors,
+                stats.failed_open,
+                stats.failed_closed,
+                stats.fallback_checks
+            );
+        }
+    }
+
+    async fn consume_redis(&self, req: &QuotaRequest) -> RedisResult<QuotaOutcome> {
+        let mut connection = self.connection.get().await?;
+        quota::consume(&mut connection, req).await
+    }
+}
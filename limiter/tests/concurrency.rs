@@ -0,0 +1,95 @@
+//! Tests of the per-user concurrency limiter.
+
+use std::time::Duration;
+
+use limiter::concurrency::ConcurrencyLimiter;
+use uuid::Uuid;
+
+#[test]
+fn allows_up_to_limit() {
+    let limiter = ConcurrencyLimiter::new();
+    let user_id = Uuid::new_v4();
+
+    let first = limiter.try_acquire(user_id, 2);
+    let second = limiter.try_acquire(user_id, 2);
+    assert!(first.is_some());
+    assert!(second.is_some());
+    assert!(limiter.try_acquire(user_id, 2).is_none());
+}
+
+#[test]
+fn users_are_limited_separately() {
+    let limiter = ConcurrencyLimiter::new();
+
+    let _slot = limiter.try_acquire(Uuid::new_v4(), 1).unwrap();
+    assert!(limiter.try_acquire(Uuid::new_v4(), 1).is_some());
+}
+
+#[test]
+fn dropped_slot_is_released() {
+    let limiter = ConcurrencyLimiter::new();
+    let user_id = Uuid::new_v4();
+
+    let slot = limiter.try_acquire(user_id, 1).unwrap();
+    assert!(limiter.try_acquire(user_id, 1).is_none());
+    drop(slot);
+    assert!(limiter.try_acquire(user_id, 1).is_some());
+}
+
+#[tokio::test]
+async fn slot_is_released_when_request_is_cancelled() {
+    let limiter = ConcurrencyLimiter::new();
+    let user_id = Uuid::new_v4();
+
+    let slot = limiter.try_acquire(user_id, 1).unwrap();
+    // a request dropped mid-flight, e.g. on client disconnect
+    let request = tokio::spawn(async move {
+        let _slot = slot;
+        tokio::time::sleep(Duration::from_secs(60)).await;
+    });
+    request.abort();
+    let _ = request.await;
+
+    assert!(limiter.try_acquire(user_id, 1).is_some());
+}
+
+#[test]
+fn changed_limit_applies() {
+    let limiter = ConcurrencyLimiter::new();
+    let user_id = Uuid::new_v4();
+
+    let _slot = limiter.try_acquire(user_id, 1).unwrap();
+    assert!(limiter.try_acquire(user_id, 1).is_none());
+
+    let upgraded = limiter.try_acquire(user_id, 3);
+    assert!(upgraded.is_some());
+}
+
+#[test]
+fn lowered_limit_applies_to_requests_in_flight() {
+    let limiter = ConcurrencyLimiter::new();
+    let user_id = Uuid::new_v4();
+
+    let mut slots: Vec<_> = (0..3)
+        .map(|_| limiter.try_acquire(user_id, 3).unwrap())
+        .collect();
+    assert!(limiter.try_acquire(user_id, 2).is_none());
+
+    // 2 requests in flight, as many as the new limit
+    slots.pop();
+    assert!(limiter.try_acquire(user_id, 2).is_none());
+
+    slots.pop();
+    let _slot = limiter.try_acquire(user_id, 2).unwrap();
+    assert!(limiter.try_acquire(user_id, 2).is_none());
+}
+
+#[test]
+fn raised_limit_keeps_requests_in_flight() {
+    let limiter = ConcurrencyLimiter::new();
+    let user_id = Uuid::new_v4();
+
+    let _first = limiter.try_acquire(user_id, 1).unwrap();
+    let _second = limiter.try_acquire(user_id, 2).unwrap();
+    assert!(limiter.try_acquire(user_id, 2).is_none());
+}
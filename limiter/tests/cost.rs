@@ -0,0 +1,43 @@
+//! Tests of the route cost lookup.
+
+use actix_web::http::Method;
+use limiter::cost::{Cost, CostTable, RouteCost};
+
+fn table() -> CostTable {
+    CostTable::new(vec![
+        RouteCost::new(Method::POST, "/checker/check-token", Cost::Fixed(2)),
+        RouteCost::new(Method::POST, "/checker/check-token/batch", Cost::Fixed(0)),
+    ])
+}
+
+#[test]
+fn matches_whole_path() {
+    let costs = table();
+
+    assert!(matches!(
+        costs.get(&Method::POST, "/checker/check-token"),
+        Cost::Fixed(2)
+    ));
+    assert!(matches!(
+        costs.get(&Method::POST, "/checker/check-token/"),
+        Cost::Fixed(2)
+    ));
+    assert!(matches!(
+        costs.get(&Method::POST, "/checker/check-token/batch"),
+        Cost::Fixed(0)
+    ));
+}
+
+#[test]
+fn other_routes_cost_one_unit() {
+    let costs = table();
+
+    for (method, path) in [
+        (Method::POST, "/foo/checker/check-token"),
+        (Method::POST, "/foo/checker/check-token/batch"),
+        (Method::POST, "/checker"),
+        (Method::GET, "/checker/check-token"),
+    ] {
+        assert!(matches!(costs.get(&method, path), Cost::Fixed(1)));
+    }
+}
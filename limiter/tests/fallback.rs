@@ -0,0 +1,61 @@
+//! Tests of the in-memory quota limiter used while Redis is unavailable.
+
+use limiter::fallback::{LocalQuotaLimiter, LocalQuotaWindow};
+use uuid::Uuid;
+
+#[test]
+fn reports_remaining_units() {
+    let limiter = LocalQuotaLimiter::new();
+    let user = Uuid::new_v4();
+
+    let usage = limiter.check("plan", user, 10, 100, 3).unwrap();
+    assert_eq!(usage.daily_remaining, Some(7));
+    assert_eq!(usage.monthly_remaining, Some(97));
+
+    let usage = limiter.check("plan", user, 10, 100, 7).unwrap();
+    assert_eq!(usage.daily_remaining, Some(0));
+}
+
+#[test]
+fn rejects_exhausted_window() {
+    let limiter = LocalQuotaLimiter::new();
+    let user = Uuid::new_v4();
+    limiter.check("plan", user, 5, 0, 5).unwrap();
+
+    let exceeded = limiter.check("plan", user, 5, 0, 1).unwrap_err();
+    assert_eq!(exceeded.window, LocalQuotaWindow::Daily);
+    assert!(exceeded.retry_after_secs >= 1);
+}
+
+#[test]
+fn unlimited_windows_have_no_remaining() {
+    let limiter = LocalQuotaLimiter::new();
+
+    let usage = limiter.check("plan", Uuid::new_v4(), 0, 100, 1).unwrap();
+    assert_eq!(usage.daily_remaining, None);
+    assert_eq!(usage.monthly_remaining, Some(99));
+}
+
+#[test]
+fn free_requests_are_not_checked() {
+    let limiter = LocalQuotaLimiter::new();
+    let user = Uuid::new_v4();
+    limiter.check("plan", user, 1, 0, 1).unwrap();
+
+    let usage = limiter.check("plan", user, 1, 0, 0).unwrap();
+    assert_eq!(usage.daily_remaining, None);
+}
+
+#[test]
+fn rejected_requests_use_no_units() {
+    let limiter = LocalQuotaLimiter::new();
+    let user = Uuid::new_v4();
+    limiter.check("plan", user, 10, 4, 3).unwrap();
+
+    let exceeded = limiter.check("plan", user, 10, 4, 2).unwrap_err();
+    assert_eq!(exceeded.window, LocalQuotaWindow::Monthly);
+
+    let usage = limiter.check("plan", user, 10, 4, 1).unwrap();
+    assert_eq!(usage.daily_remaining, Some(6));
+    assert_eq!(usage.monthly_remaining, Some(0));
+}
@@ -0,0 +1,203 @@
+//! Tests of the quota Lua script. They need a Redis server at `REDIS_URL`
+//! (default `redis://127.0.0.1/`) and are ignored by default, run them with
+//! `cargo test -- --ignored`.
+
+use limiter::quota::{self, QuotaOutcome, QuotaRequest};
+use redis::{AsyncCommands, aio::MultiplexedConnection};
+use uuid::Uuid;
+
+/// Connects to Redis, panics if it is not reachable.
+async fn connect() -> MultiplexedConnection {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let client = redis::Client::open(url).unwrap();
+    match client.get_multiplexed_tokio_connection().await {
+        Ok(conn) => conn,
+        Err(e) => panic!("Redis is not reachable: {}", e),
+    }
+}
+
+fn quota_request(daily_limit: u64, monthly_limit: u64) -> QuotaRequest {
+    let id = Uuid::new_v4();
+    QuotaRequest {
+        daily_key: format!("quota-test:{}:daily", id),
+        monthly_key: format!("quota-test:{}:monthly", id),
+        daily_limit,
+        monthly_limit,
+        daily_ttl_secs: 60,
+        monthly_ttl_secs: 3600,
+        units: 1,
+    }
+}
+
+async fn cleanup(conn: &mut MultiplexedConnection, req: &QuotaRequest) {
+    let _: () = conn.del(&[&req.daily_key, &req.monthly_key]).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn allows_up_to_daily_limit() {
+    let mut conn = connect().await;
+    let req = quota_request(3, 100);
+
+    for i in 1..=3 {
+        let outcome = quota::consume(&mut conn, &req).await.unwrap();
+        assert_eq!(
+            outcome,
+            QuotaOutcome::Allowed {
+                daily: i,
+                monthly: i
+            }
+        );
+    }
+    let outcome = quota::consume(&mut conn, &req).await.unwrap();
+    assert_eq!(
+        outcome,
+        QuotaOutcome::DailyExceeded {
+            daily: 3,
+            monthly: 3
+        }
+    );
+
+    cleanup(&mut conn, &req).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn rejected_requests_are_not_counted() {
+    let mut conn = connect().await;
+    let req = quota_request(1, 100);
+
+    quota::consume(&mut conn, &req).await.unwrap();
+    for _ in 0..5 {
+        quota::consume(&mut conn, &req).await.unwrap();
+    }
+    let daily: u64 = conn.get(&req.daily_key).await.unwrap();
+    let monthly: u64 = conn.get(&req.monthly_key).await.unwrap();
+    assert_eq!((daily, monthly), (1, 1));
+
+    cleanup(&mut conn, &req).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn enforces_monthly_limit() {
+    let mut conn = connect().await;
+    let req = quota_request(100, 2);
+
+    quota::consume(&mut conn, &req).await.unwrap();
+    quota::consume(&mut conn, &req).await.unwrap();
+    let outcome = quota::consume(&mut conn, &req).await.unwrap();
+    assert_eq!(
+        outcome,
+        QuotaOutcome::MonthlyExceeded {
+            daily: 2,
+            monthly: 2
+        }
+    );
+
+    cleanup(&mut conn, &req).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn zero_limit_is_unlimited() {
+    let mut conn = connect().await;
+    let req = quota_request(0, 5);
+
+    for _ in 0..5 {
+        let outcome = quota::consume(&mut conn, &req).await.unwrap();
+        assert!(matches!(outcome, QuotaOutcome::Allowed { .. }));
+    }
+    let outcome = quota::consume(&mut conn, &req).await.unwrap();
+    assert!(matches!(outcome, QuotaOutcome::MonthlyExceeded { .. }));
+
+    cleanup(&mut conn, &req).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn sets_and_repairs_ttls() {
+    let mut conn = connect().await;
+    let req = quota_request(10, 10);
+
+    quota::consume(&mut conn, &req).await.unwrap();
+    let daily_ttl: i64 = conn.ttl(&req.daily_key).await.unwrap();
+    let monthly_ttl: i64 = conn.ttl(&req.monthly_key).await.unwrap();
+    assert!(daily_ttl > 0 && daily_ttl <= 60);
+    assert!(monthly_ttl > 60 && monthly_ttl <= 3600);
+
+    // a counter that lost its TTL gets it back with the next request
+    let _: () = conn.persist(&req.daily_key).await.unwrap();
+    quota::consume(&mut conn, &req).await.unwrap();
+    let daily_ttl: i64 = conn.ttl(&req.daily_key).await.unwrap();
+    assert!(daily_ttl > 0 && daily_ttl <= 60);
+
+    cleanup(&mut conn, &req).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn concurrent_requests_never_overshoot() {
+    let conn = connect().await;
+    let req = quota_request(50, 1000);
+
+    let tasks: Vec<_> = (0..200)
+        .map(|_| {
+            let mut conn = conn.clone();
+            let req = req.clone();
+            tokio::spawn(async move { quota::consume(&mut conn, &req).await.unwrap() })
+        })
+        .collect();
+    let mut allowed = 0;
+    for task in tasks {
+        if matches!(task.await.unwrap(), QuotaOutcome::Allowed { .. }) {
+            allowed += 1;
+        }
+    }
+    assert_eq!(allowed, 50);
+
+    let mut conn = conn;
+    let daily: u64 = conn.get(&req.daily_key).await.unwrap();
+    assert_eq!(daily, 50);
+
+    cleanup(&mut conn, &req).await;
+}
+
+#[tokio::test]
+#[ignore = "needs Redis"]
+async fn consumes_units() {
+    let mut conn = connect().await;
+    let mut req = quota_request(10, 100);
+    req.units = 4;
+
+    let outcome = quota::consume(&mut conn, &req).await.unwrap();
+    assert_eq!(
+        outcome,
+        QuotaOutcome::Allowed {
+            daily: 4,
+            monthly: 4
+        }
+    );
+    quota::consume(&mut conn, &req).await.unwrap();
+    // 8 + 4 would exceed the daily limit, nothing is counted
+    let outcome = quota::consume(&mut conn, &req).await.unwrap();
+    assert_eq!(
+        outcome,
+        QuotaOutcome::DailyExceeded {
+            daily: 8,
+            monthly: 8
+        }
+    );
+    // a cheaper request still fits
+    req.units = 2;
+    let outcome = quota::consume(&mut conn, &req).await.unwrap();
+    assert_eq!(
+        outcome,
+        QuotaOutcome::Allowed {
+            daily: 10,
+            monthly: 10
+        }
+    );
+
+    cleanup(&mut conn, &req).await;
+}
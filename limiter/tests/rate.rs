@@ -0,0 +1,46 @@
+//! Tests of the in-memory route rate limiter.
+
+use common::env_config::RouteRateLimit;
+use limiter::rate::RouteLimiter;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn limits_users_and_ips() {
+    let limiter = RouteLimiter::new(
+        "test",
+        RouteRateLimit {
+            per_ip: 2,
+            per_user: 1,
+        },
+        None,
+    );
+    let ip = "203.0.113.7".parse().ok();
+    let user_id = Some(Uuid::new_v4());
+
+    assert!(limiter.check(ip, user_id).await.is_ok());
+    let limited = limiter.check(ip, user_id).await.unwrap_err();
+    assert_eq!(limited.by, "user");
+    assert!(limited.retry_after_secs >= 1);
+}
+
+#[tokio::test]
+async fn user_over_limit_does_not_use_up_ip() {
+    let limiter = RouteLimiter::new(
+        "test",
+        RouteRateLimit {
+            per_ip: 2,
+            per_user: 1,
+        },
+        None,
+    );
+    let ip = "203.0.113.7".parse().ok();
+    let user_id = Some(Uuid::new_v4());
+
+    assert!(limiter.check(ip, user_id).await.is_ok());
+    assert!(limiter.check(ip, user_id).await.is_err());
+
+    // another user behind the same IP still has the rest of its limit
+    assert!(limiter.check(ip, Some(Uuid::new_v4())).await.is_ok());
+    let limited = limiter.check(ip, Some(Uuid::new_v4())).await.unwrap_err();
+    assert_eq!(limited.by, "ip");
+}
@@ -9,9 +9,12 @@ use actix_web::{HttpMessage, HttpResponse, ResponseError};
 use chrono::Utc;
 use colored::Colorize;
 use common::env_config::Config;
+use common::ip;
 use common::jwt::get_jwt_claims_or_error;
-use common::key::get_key_claims_or_error;
-use db::models::log::Log;
+use db::models::{
+    key::VerifiedKey,
+    log::{ConsumedUnits, Log},
+};
 use futures::StreamExt;
 use futures::future::{LocalBoxFuture, Ready, ready};
 use log::{debug, info};
@@ -24,6 +27,7 @@ use std::str::FromStr;
 use std::sync::Arc;
 use uuid::Uuid;
 
+#[derive(Default)]
 pub struct LoggerMiddleware {}
 
 impl LoggerMiddleware {
@@ -75,14 +79,12 @@ where
         let path = req.path().to_string();
         let query_string = req.query_string().to_string();
 
+        let config = &***req.app_data::<web::Data<Arc<Config>>>().unwrap().clone();
+
         // IP
-        let ip_str = req
-            .connection_info()
-            .realip_remote_addr()
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| "0.0.0.0".to_string());
-        let ip_address = IpNetwork::from_str(&ip_str)
-            .unwrap_or_else(|_| IpNetwork::from_str("0.0.0.0").unwrap());
+        let ip_address = ip::client_ip(req.request(), &config.trusted_proxies)
+            .map(IpNetwork::from)
+            .unwrap_or_else(|| IpNetwork::from_str("0.0.0.0").unwrap());
 
         // Agent
         let user_agent = req
@@ -91,7 +93,6 @@ where
             .map(|ua| ua.to_str().unwrap_or_default().to_string())
             .unwrap_or_default();
 
-        let config = &***req.app_data::<web::Data<Arc<Config>>>().unwrap().clone();
         let console_logging_enabled = config.console_logging_enabled;
         let srv = Arc::clone(&self.service);
 
@@ -100,13 +101,6 @@ where
             let jwt_claims = get_jwt_claims_or_error(&req).ok();
             let mut user_id = jwt_claims.as_ref().map(|c| c.user_id);
 
-            // Key claims
-            let key_claims = get_key_claims_or_error(&req).ok();
-            let key_id = key_claims.as_ref().map(|c| c.key_id);
-            if user_id.is_none() {
-                user_id = key_claims.as_ref().map(|c| c.user_id);
-            }
-
             // Get postgres pool
             let pool = &***req.app_data::<web::Data<Arc<PgPool>>>().unwrap().clone();
 
@@ -129,8 +123,22 @@ where
             // Call next services
             let res = srv.call(req).await?;
 
+            // Verified key (inserted by key middleware, absent if the key was rejected)
+            let verified_key = res.request().extensions().get::<VerifiedKey>().cloned();
+            let key_id = verified_key.as_ref().map(|k| k.id);
+            let mode = verified_key.as_ref().map(|k| k.mode.to_string());
+            // Quota units (inserted by quota middleware, absent if the request was not counted)
+            let units = res
+                .request()
+                .extensions()
+                .get::<ConsumedUnits>()
+                .map(|units| units.0 as i64);
+            if user_id.is_none() {
+                user_id = verified_key.as_ref().map(|k| k.owner.id);
+            }
+
             // Get response status
-            let status = res.status().clone();
+            let status = res.status();
             let status_code = res.status().as_u16() as i32;
             let timestamp = Utc::now();
 
@@ -194,15 +202,15 @@ where
                     params_json.to_string().bright_cyan(),
                 );
 
-                if let Some(ref body) = request_body.as_object() {
-                    if !body.is_empty() {
-                        debug!(
-                            "  Request: {}",
-                            serde_json::to_string(&request_body)
-                                .unwrap_or_default()
-                                .bright_green()
-                        );
-                    }
+                if let Some(body) = request_body.as_object()
+                    && !body.is_empty()
+                {
+                    debug!(
+                        "  Request: {}",
+                        serde_json::to_string(&request_body)
+                            .unwrap_or_default()
+                            .bright_green()
+                    );
                 }
 
                 if status_code >= 400
@@ -233,6 +241,8 @@ where
                     response_body: Some(response_body),
                     ip_address,
                     user_agent,
+                    mode,
+                    units,
                 },
             )
             .await?;